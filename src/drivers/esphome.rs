@@ -0,0 +1,212 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::{
+    blocking, channels, check_channel_names, check_options, entry_names, DriverFuture, RelayDriver,
+};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::esphome_network_functions::{self, decode_encryption_key};
+
+/// Options of an `EspHome` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EspHomeOptions {
+    /// Switch entity used by a relay with a single `name`, 0 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) channel: Option<u8>,
+    /// API password, for devices with authentication turned on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password: Option<String>,
+    /// Base64 encryption key of the device's native API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encryption_key: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct EspHomeRelay {
+    pub(crate) ip: String,
+    pub(crate) key: u32,
+    pub(crate) entity: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+    pub(crate) settings: EspHomeOptions,
+}
+
+impl EspHomeRelay {
+    /// Connects to the ESPHome device at `ip`, one relay per switch entity.
+    pub fn new(
+        ip: String,
+        channels: Vec<(u8, String)>,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Vec<EspHomeRelay>, Error> {
+        let settings: EspHomeOptions = options.parse()?;
+        let device = esphome_network_functions::device(&ip, &settings)?;
+        let switches = device.switches();
+
+        let mut relays: Vec<EspHomeRelay> = Vec::new();
+        for (channel, name) in channels {
+            let switch = switches.get(channel as usize).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "ESPHome {} has {} switches, there is no channel {}",
+                        ip,
+                        switches.len(),
+                        channel
+                    ),
+                )
+            })?;
+            relays.push(EspHomeRelay {
+                ip: ip.clone(),
+                key: switch.key,
+                entity: switch.object_id.clone(),
+                name,
+                status: device.wait_for_state(switch.key, None)?,
+                room: room.clone(),
+                tags: tags.clone(),
+                options: options.clone(),
+                settings: settings.clone(),
+            });
+        }
+
+        Ok(relays)
+    }
+
+    /// Number of switch entities on the ESPHome device at `ip`.
+    pub fn switch_count(ip: &str, options: &RelayOptions) -> Result<usize, Error> {
+        Ok(esphome_network_functions::device(ip, &options.parse()?)?
+            .switches()
+            .len())
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        esphome_network_functions::device(&self.ip, &self.settings)?.set_state(self.key, on)?;
+        self.status = on;
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for EspHomeRelay {
+    relay_fields!("EspHome", ip, options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "ESPHome",
+            "ip": &self.ip,
+            "entity": &self.entity,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    /// Status from the last state the device reported.
+    fn get_status(&mut self) -> Result<bool, Error> {
+        self.status =
+            esphome_network_functions::device(&self.ip, &self.settings)?.state(self.key)?;
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        let status = self.status;
+        self.set(!status)
+    }
+}
+
+pub(crate) struct EspHomeDriver;
+
+impl RelayDriver for EspHomeDriver {
+    fn type_name(&self) -> &'static str {
+        "EspHome"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        EspHomeOptions::json_schema(gen)
+    }
+
+    fn relay_names(&self, relay: &ConfigRelay) -> Vec<String> {
+        entry_names(relay)
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        check_channel_names(label, relay, issues);
+        let options: EspHomeOptions = check_options(label, relay, issues);
+        if let Some(key) = &options.encryption_key {
+            if let Err(error) = decode_encryption_key(key) {
+                issues.push(ConfigIssue::error(format!("{} {}", label, error)));
+            }
+        }
+    }
+
+    /// Also reports more `names` than the device has switches.
+    fn probe<'a>(
+        &'a self,
+        label: &'a str,
+        relay: &'a ConfigRelay,
+        issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async move {
+            let (ip, options) = (relay.ip.clone(), relay.options.clone());
+            match blocking(move || EspHomeRelay::switch_count(&ip, &options)).await {
+                Ok(switches) if relay.names.len() > switches => {
+                    issues.push(ConfigIssue::error(format!(
+                        "{} lists {} names but the device at {} has {} switches",
+                        label,
+                        relay.names.len(),
+                        relay.ip,
+                        switches
+                    )))
+                }
+                Ok(_) => {}
+                Err(error) => issues.push(ConfigIssue::warning(format!(
+                    "{} at {} is unreachable: {}",
+                    label, relay.ip, error
+                ))),
+            }
+        })
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let channel = relay.options.parse::<EspHomeOptions>()?.channel;
+            let channels = channels(&relay, 0, channel);
+            let esphomes =
+                EspHomeRelay::new(relay.ip, channels, relay.room, relay.tags, relay.options)?;
+            Ok(esphomes
+                .into_iter()
+                .map(|esphome| Box::new(esphome) as RelayType)
+                .collect())
+        })
+    }
+}
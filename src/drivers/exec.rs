@@ -0,0 +1,325 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::state_parsing::{PendingStatus, StateParsing};
+use crate::drivers::{blocking, check_options, state_name, DriverFuture, RelayDriver};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::exec_functions::{self, user_ids};
+
+/// Command run by an `Exec` relay, without a shell. `{{ip}}`, `{{name}}` and
+/// `{{state}}` (`on` or `off`) are filled in in the arguments.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExecCommand {
+    /// Program to run, looked up in `PATH` unless it's a path
+    pub(crate) program: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) args: Vec<String>,
+    /// Seconds before the command is killed, 10 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout: Option<u64>,
+    /// User the command runs as, the server needs to run as root to switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+    /// Directory the command runs in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) working_dir: Option<String>,
+    /// Run with only `env` set instead of the server's environment
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) clear_env: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) env: BTreeMap<String, String>,
+}
+
+/// Options of an `Exec` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExecOptions {
+    /// Command run to turn on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) on_command: Option<ExecCommand>,
+    /// Command run to turn off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) off_command: Option<ExecCommand>,
+    /// Command whose exit code, or output when a state to parse is set, gives
+    /// the state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) status_command: Option<ExecCommand>,
+    #[serde(flatten)]
+    pub(crate) state: StateParsing,
+}
+
+#[derive(Debug)]
+pub struct ExecRelay {
+    pub(crate) ip: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+    pub(crate) settings: ExecOptions,
+    /// Status command still running from an earlier status read
+    pending: PendingStatus,
+}
+
+impl ExecRelay {
+    /// Runs the relay's `statusCommand`, if it has one, for its state.
+    pub fn new(
+        ip: String,
+        name: String,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Self, Error> {
+        let mut relay = ExecRelay {
+            ip,
+            name,
+            status: false,
+            room,
+            tags,
+            settings: options.parse()?,
+            options,
+            pending: PendingStatus::default(),
+        };
+        relay.get_status()?;
+        Ok(relay)
+    }
+
+    /// Runs the status command for the state, parsed from its output when a
+    /// state to parse is set, else given by its exit code.
+    fn status_command(&self, command: &ExecCommand) -> impl FnOnce() -> Result<bool, Error> {
+        let command = command.clone();
+        let parsing = self.settings.state.clone();
+        let (ip, name) = (self.ip.clone(), self.name.clone());
+        let state = state_name(self.status);
+        move || {
+            exec_functions::run(&command, &[("ip", &ip), ("name", &name), ("state", state)])
+                .and_then(|output| match parsing.is_set() {
+                    true => parsing.parse(&output.stdout),
+                    false => Ok(output.success),
+                })
+        }
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        let state = state_name(on);
+        let command = match on {
+            true => &self.settings.on_command,
+            false => &self.settings.off_command,
+        };
+        let command = command.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Exec relay {} has no {}Command", self.name, state),
+            )
+        })?;
+
+        let output = exec_functions::run(
+            command,
+            &[("ip", &self.ip), ("name", &self.name), ("state", state)],
+        )?;
+        if !output.success {
+            return Err(Error::other(format!(
+                "{} for {} failed with exit code {}",
+                command.program,
+                self.name,
+                output
+                    .code
+                    .map_or("none".to_string(), |code| code.to_string())
+            )));
+        }
+        // A status read started before the switch would report the old state
+        self.pending.clear();
+        self.status = on;
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for ExecRelay {
+    relay_fields!("Exec", ip, options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Exec",
+            "ip": &self.ip,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    /// Status from the `statusCommand`, on when it exits with 0 unless a state
+    /// is parsed from its output. Without a status command the status is
+    /// whatever was last commanded. A slow command keeps running in the
+    /// background, see `PendingStatus`.
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let Some(command) = &self.settings.status_command else {
+            return Ok(self.status);
+        };
+        let read = self.status_command(command);
+        self.status = self.pending.read(self.status, read)?;
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        let status = self.status;
+        self.set(!status)
+    }
+}
+
+fn check_command(label: &str, field: &str, command: &ExecCommand, issues: &mut Vec<ConfigIssue>) {
+    if command.program.trim().is_empty() {
+        issues.push(ConfigIssue::error(format!(
+            "{} has no `program` in its `{}`",
+            label, field
+        )));
+    }
+    if let Some(user) = &command.user {
+        if let Err(error) = user_ids(user) {
+            issues.push(ConfigIssue::error(format!(
+                "{} can't run its `{}`: {}",
+                label, field, error
+            )));
+        }
+    }
+}
+
+pub(crate) struct ExecDriver;
+
+impl RelayDriver for ExecDriver {
+    fn type_name(&self) -> &'static str {
+        "Exec"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        ExecOptions::json_schema(gen)
+    }
+
+    fn needs_ip(&self) -> bool {
+        false
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        if relay.name.is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no name", label)));
+        }
+        let options: ExecOptions = check_options(label, relay, issues);
+        for (command, field) in [
+            (&options.on_command, "onCommand"),
+            (&options.off_command, "offCommand"),
+            (&options.status_command, "statusCommand"),
+        ] {
+            match command {
+                None if field != "statusCommand" => {
+                    issues.push(ConfigIssue::error(format!("{} has no `{}`", label, field)))
+                }
+                None => {}
+                Some(command) => check_command(label, field, command, issues),
+            }
+        }
+        options.state.check(
+            label,
+            options.status_command.is_some(),
+            "statusCommand",
+            issues,
+        );
+    }
+
+    fn probe<'a>(
+        &'a self,
+        label: &'a str,
+        relay: &'a ConfigRelay,
+        issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async move {
+            if let Err(error) = self.connect(relay.clone()).await {
+                issues.push(ConfigIssue::warning(format!(
+                    "{} is unavailable: {}",
+                    label, error
+                )));
+            }
+        })
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let exec = ExecRelay::new(relay.ip, relay.name, relay.room, relay.tags, relay.options)?;
+            Ok(vec![Box::new(exec) as RelayType])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exec_relay_status_from_exit_code_and_output() {
+        let directory = std::env::temp_dir().join(format!("exec-relay-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let shell = |script: &str| ExecCommand {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            working_dir: Some(directory.to_string_lossy().to_string()),
+            ..ExecCommand::default()
+        };
+        let options = ExecOptions {
+            on_command: Some(shell("echo {{name}} > on")),
+            off_command: Some(shell("rm -f on")),
+            status_command: Some(shell("test -f on")),
+            ..ExecOptions::default()
+        };
+
+        let mut relay = ExecRelay::new(
+            String::new(),
+            "Pc".to_string(),
+            "office".to_string(),
+            vec![],
+            serde_json::from_value(json!(options)).unwrap(),
+        )
+        .unwrap();
+        assert!(!relay.status);
+        relay.turn_on().unwrap();
+        assert!(relay.get_status().unwrap());
+        relay.switch().unwrap();
+        assert!(!relay.get_status().unwrap());
+
+        relay.settings.status_command = Some(shell("echo state: running"));
+        relay.settings.state.state_regex = Some("state: (\\w+)".to_string());
+        relay.settings.state.state_on = Some("running".to_string());
+        assert!(relay.get_status().unwrap());
+
+        relay.settings.off_command = Some(shell("exit 2"));
+        assert!(relay.turn_off().is_err());
+        assert!(relay.status);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::Error;
+
+use crate::drivers::{blocking, check_options, entry_names, DriverFuture, RelayDriver};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::gpio_functions;
+
+/// Options of a `Gpio` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GpioOptions {
+    /// GPIO chip, `/dev/gpiochip0` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) chip: Option<String>,
+    /// Line offset switched by a relay with a single `name`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<u32>,
+    /// Line offsets of the relays in `names`, in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) lines: Vec<u32>,
+    /// Drive the line low to turn the relay on, as most relay boards need
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) active_low: Option<bool>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GpioRelay {
+    pub(crate) chip: String,
+    pub(crate) line: u32,
+    pub(crate) active_low: bool,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+}
+
+impl GpioRelay {
+    /// Requests the GPIO `lines` as outputs, one relay per line.
+    pub fn new(
+        lines: Vec<(u32, String)>,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Vec<GpioRelay>, Error> {
+        let gpio: GpioOptions = options.parse()?;
+        let chip = gpio_functions::chip_path(gpio.chip.as_deref());
+        let active_low = gpio.active_low.unwrap_or(false);
+
+        let mut relays: Vec<GpioRelay> = Vec::new();
+        for (line, name) in lines {
+            let mut relay = GpioRelay {
+                chip: chip.clone(),
+                line,
+                active_low,
+                name,
+                status: false,
+                room: room.clone(),
+                tags: tags.clone(),
+                options: options.clone(),
+            };
+            relay.get_status()?;
+            relays.push(relay);
+        }
+
+        Ok(relays)
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        gpio_functions::set_line(&self.chip, self.line, self.active_low, on)?;
+        self.status = on;
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for GpioRelay {
+    relay_fields!("Gpio", options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Gpio",
+            "chip": &self.chip,
+            "line": self.line,
+            "activeLow": self.active_low,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    /// Status read back from the line.
+    fn get_status(&mut self) -> Result<bool, Error> {
+        self.status = gpio_functions::get_line(&self.chip, self.line, self.active_low)?;
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        let status = self.status;
+        self.set(!status)
+    }
+
+    fn release(&mut self) {
+        gpio_functions::release_line(&self.chip, self.line);
+    }
+}
+
+/// GPIO line of each relay of a `Gpio` entry. `names` pair up with
+/// `lines`, a single `name` uses `line`. Names without a line are left out.
+fn gpio_lines(relay: &ConfigRelay, options: &GpioOptions) -> Vec<(u32, String)> {
+    match relay.names.is_empty() {
+        true => match options.line.or(options.lines.first().copied()) {
+            Some(line) => vec![(line, relay.name.clone())],
+            None => Vec::new(),
+        },
+        false => options
+            .lines
+            .iter()
+            .copied()
+            .zip(relay.names.iter().cloned())
+            .collect(),
+    }
+}
+
+pub(crate) struct GpioDriver;
+
+impl RelayDriver for GpioDriver {
+    fn type_name(&self) -> &'static str {
+        "Gpio"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        GpioOptions::json_schema(gen)
+    }
+
+    fn needs_ip(&self) -> bool {
+        false
+    }
+
+    fn relay_names(&self, relay: &ConfigRelay) -> Vec<String> {
+        entry_names(relay)
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        let options: GpioOptions = check_options(label, relay, issues);
+        if relay.name.is_empty() && relay.names.is_empty() {
+            issues.push(ConfigIssue::error(format!(
+                "{} has no `name` or `names`",
+                label
+            )));
+        } else if relay.names.is_empty() && gpio_lines(relay, &options).is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no `line`", label)));
+        } else if !relay.names.is_empty() && relay.names.len() != options.lines.len() {
+            issues.push(ConfigIssue::error(format!(
+                "{} lists {} names but {} `lines`",
+                label,
+                relay.names.len(),
+                options.lines.len()
+            )));
+        }
+    }
+
+    /// Only checks the lines exist, requesting them would switch the relays
+    /// and fail while the server holds them.
+    fn probe<'a>(
+        &'a self,
+        label: &'a str,
+        relay: &'a ConfigRelay,
+        issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async move {
+            let Ok(options) = relay.options.parse::<GpioOptions>() else {
+                return;
+            };
+            let chip = gpio_functions::chip_path(options.chip.as_deref());
+            for (line, _) in gpio_lines(relay, &options) {
+                if let Err(error) = gpio_functions::check_line(&chip, line) {
+                    issues.push(ConfigIssue::warning(format!(
+                        "{} is unavailable: {}",
+                        label, error
+                    )));
+                }
+            }
+        })
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let lines = gpio_lines(&relay, &relay.options.parse()?);
+            let gpios = GpioRelay::new(lines, relay.room, relay.tags, relay.options)?;
+            Ok(gpios
+                .into_iter()
+                .map(|gpio| Box::new(gpio) as RelayType)
+                .collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::gpio_functions::{insert_line, OutputLine};
+    use std::sync::{Arc, Mutex};
+
+    struct FakeLine(Arc<Mutex<bool>>);
+
+    impl OutputLine for FakeLine {
+        fn set(&mut self, on: bool) -> Result<(), std::io::Error> {
+            *self.0.lock().unwrap() = on;
+            Ok(())
+        }
+
+        fn get(&self) -> Result<bool, std::io::Error> {
+            Ok(*self.0.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_gpio_relays_drive_their_lines() {
+        let first = Arc::new(Mutex::new(false));
+        let second = Arc::new(Mutex::new(true));
+        insert_line(
+            "/dev/gpiochip-test",
+            5,
+            true,
+            Box::new(FakeLine(first.clone())),
+        );
+        insert_line(
+            "/dev/gpiochip-test",
+            6,
+            true,
+            Box::new(FakeLine(second.clone())),
+        );
+
+        let options = serde_json::from_value(json!({
+            "chip": "gpiochip-test",
+            "lines": [5, 6],
+            "activeLow": true,
+        }))
+        .unwrap();
+        let mut relays = GpioRelay::new(
+            vec![(5, "Pump".to_string()), (6, "Valve".to_string())],
+            "garden".to_string(),
+            vec![],
+            options,
+        )
+        .unwrap();
+        assert!(!relays[0].status);
+        assert!(relays[1].status);
+
+        relays[0].turn_on().unwrap();
+        assert!(*first.lock().unwrap());
+        relays[1].switch().unwrap();
+        assert!(!*second.lock().unwrap());
+
+        *first.lock().unwrap() = false;
+        assert!(!relays[0].get_status().unwrap());
+        assert_eq!(relays[0].to_json()["chip"], "/dev/gpiochip-test");
+
+        // A removed relay gives its line back, there's no chip to request it from
+        relays[0].release();
+        assert!(relays[0].get_status().is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::{check_options, DriverFuture, RelayDriver};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+
+/// When a `Group` relay reads as on.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupStatus {
+    #[default]
+    Any,
+    All,
+}
+
+/// Options of a `Group` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GroupOptions {
+    /// Relays switched together by the group
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) members: Vec<String>,
+    /// Whether the group is on when any or all of its members are, `any` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) group_status: Option<GroupStatus>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct GroupRelay {
+    pub(crate) name: String,
+    pub(crate) members: Vec<String>,
+    pub(crate) group_status: GroupStatus,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+}
+
+impl GroupRelay {
+    /// The status is taken from the members once they're loaded, see
+    /// `update_groups`.
+    pub fn new(
+        name: String,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Self, Error> {
+        let group: GroupOptions = options.parse()?;
+        Ok(GroupRelay {
+            name,
+            members: group.members,
+            group_status: group.group_status.unwrap_or_default(),
+            status: false,
+            room,
+            tags,
+            options,
+        })
+    }
+
+    fn unsupported(&self) -> Error {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("Group {} is switched through its members", self.name),
+        )
+    }
+}
+
+impl RelayActions for GroupRelay {
+    relay_fields!("Group", options);
+
+    fn members(&self) -> Option<&Vec<String>> {
+        Some(&self.members)
+    }
+
+    fn follow_members(&mut self, statuses: &[bool]) {
+        self.status = !statuses.is_empty()
+            && match self.group_status {
+                GroupStatus::Any => statuses.contains(&true),
+                GroupStatus::All => !statuses.contains(&false),
+            };
+    }
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Group",
+            "name": &self.name,
+            "members": &self.members,
+            "groupStatus": self.group_status,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    /// Status as last taken from the members.
+    fn get_status(&mut self) -> Result<bool, Error> {
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        Err(self.unsupported())
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        Err(self.unsupported())
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        Err(self.unsupported())
+    }
+}
+
+pub(crate) struct GroupDriver;
+
+impl RelayDriver for GroupDriver {
+    fn type_name(&self) -> &'static str {
+        "Group"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        GroupOptions::json_schema(gen)
+    }
+
+    fn needs_ip(&self) -> bool {
+        false
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        let groups: HashSet<&String> = relays
+            .iter()
+            .filter(|relay| relay.relay_type.name() == self.type_name())
+            .map(|relay| &relay.name)
+            .collect();
+        let relay_names: HashSet<String> =
+            relays.iter().flat_map(ConfigRelay::relay_names).collect();
+
+        if relay.name.is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no name", label)));
+        }
+        let options: GroupOptions = check_options(label, relay, issues);
+        if options.members.is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no `members`", label)));
+        }
+        for member in &options.members {
+            if groups.contains(member) {
+                issues.push(ConfigIssue::error(format!(
+                    "{} has group \"{}\" as a member, groups can't contain groups",
+                    label, member
+                )));
+            } else if !relay_names.contains(member) {
+                issues.push(ConfigIssue::warning(format!(
+                    "{} has unknown member \"{}\"",
+                    label, member
+                )));
+            }
+        }
+    }
+
+    /// Nothing to reach, groups are only as available as their members.
+    fn probe<'a>(
+        &'a self,
+        _label: &'a str,
+        _relay: &'a ConfigRelay,
+        _issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        Box::pin(async move {
+            let group = GroupRelay::new(relay.name, relay.room, relay.tags, relay.options)?;
+            Ok(vec![Box::new(group) as RelayType])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::virtual_relay::VirtualRelay;
+    use crate::models::presets::{set_preset, Preset};
+    use crate::models::relays::{expand_groups, set_relay, update_groups};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_groups_switch_their_members() {
+        let virtual_relay = |name: &str| -> RelayType {
+            Box::new(VirtualRelay::new(
+                name.to_string(),
+                "porch".to_string(),
+                vec![],
+            ))
+        };
+        let options = serde_json::from_value(json!({"members": ["Left", "Right"]})).unwrap();
+        let mut relays = HashMap::from([
+            ("Left".to_string(), virtual_relay("Left")),
+            ("Right".to_string(), virtual_relay("Right")),
+            ("Door".to_string(), virtual_relay("Door")),
+            (
+                "AllPorchLights".to_string(),
+                Box::new(
+                    GroupRelay::new(
+                        "AllPorchLights".to_string(),
+                        "porch".to_string(),
+                        vec![],
+                        options,
+                    )
+                    .unwrap(),
+                ),
+            ),
+        ]);
+
+        set_relay(&mut relays, "Left", Some(true)).unwrap();
+        assert!(relays["AllPorchLights"].status());
+        // Any member is on, so switching turns them all off
+        set_relay(&mut relays, "AllPorchLights", None).unwrap();
+        assert!(!relays["Left"].status() && !relays["Right"].status());
+        set_relay(&mut relays, "AllPorchLights", Some(true)).unwrap();
+        assert!(relays["Right"].status());
+
+        let options = json!({"members": ["Left", "Right"], "groupStatus": "all"});
+        let all = GroupRelay::new(
+            "AllPorchLights".to_string(),
+            "porch".to_string(),
+            vec![],
+            serde_json::from_value(options).unwrap(),
+        )
+        .unwrap();
+        relays.insert("AllPorchLights".to_string(), Box::new(all));
+        set_relay(&mut relays, "Right", Some(false)).unwrap();
+        assert!(!relays["AllPorchLights"].status());
+        assert_eq!(
+            expand_groups(&relays, &["AllPorchLights".to_string(), "Left".to_string()]),
+            vec!["Left".to_string(), "Right".to_string()]
+        );
+        assert!(relays.get_mut("AllPorchLights").unwrap().turn_on().is_err());
+
+        let preset = Preset {
+            name: "Night".to_string(),
+            enabled: true,
+            relays: HashMap::from([
+                ("AllPorchLights".to_string(), true),
+                ("Right".to_string(), false),
+            ]),
+            levels: HashMap::new(),
+        };
+        set_preset(&preset, &mut relays).unwrap();
+        update_groups(&mut relays);
+        assert!(relays["Left"].status());
+        assert!(!relays["Right"].status() && !relays["Door"].status());
+        assert!(!relays["AllPorchLights"].status());
+    }
+}
@@ -0,0 +1,281 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::state_parsing::{PendingStatus, StateParsing};
+use crate::drivers::{blocking, check_options, state_name, DriverFuture, RelayDriver};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::http_relay_functions;
+
+/// Request sent by an `Http` relay. `{{ip}}`, `{{name}}` and `{{state}}`
+/// (`on` or `off`) are filled in in the url, headers and body.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub(crate) struct HttpRequest {
+    /// HTTP method, `GET` by default, or `POST` when there is a body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) method: Option<String>,
+    pub(crate) url: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) body: Option<String>,
+    /// Seconds to wait for the response, 10 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timeout: Option<u64>,
+    /// Whether the request is safe to send again after a failure, like a
+    /// `PUT` of the new state. `GET` requests always are
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) idempotent: bool,
+}
+
+/// Options of an `Http` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HttpOptions {
+    /// Request sent to turn on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) on_request: Option<HttpRequest>,
+    /// Request sent to turn off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) off_request: Option<HttpRequest>,
+    /// Request whose response gives the state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) status_request: Option<HttpRequest>,
+    #[serde(flatten)]
+    pub(crate) state: StateParsing,
+}
+
+#[derive(Debug)]
+pub struct HttpRelay {
+    pub(crate) ip: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+    pub(crate) settings: HttpOptions,
+    /// Status request still running from an earlier status read
+    pending: PendingStatus,
+}
+
+impl HttpRelay {
+    /// Reads the relay's state when it has a `statusRequest`.
+    pub fn new(
+        ip: String,
+        name: String,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Self, Error> {
+        let mut relay = HttpRelay {
+            ip,
+            name,
+            status: false,
+            room,
+            tags,
+            settings: options.parse()?,
+            options,
+            pending: PendingStatus::default(),
+        };
+        relay.get_status()?;
+        Ok(relay)
+    }
+
+    /// Sends the status request and parses the state out of its response.
+    fn status_request(&self, request: &HttpRequest) -> impl FnOnce() -> Result<bool, Error> {
+        let request = request.clone();
+        let parsing = self.settings.state.clone();
+        let (ip, name) = (self.ip.clone(), self.name.clone());
+        let state = state_name(self.status);
+        move || {
+            http_relay_functions::send(&request, &[("ip", &ip), ("name", &name), ("state", state)])
+                .and_then(|body| parsing.parse(&body))
+        }
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        let request = match on {
+            true => &self.settings.on_request,
+            false => &self.settings.off_request,
+        };
+        let request = request.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("HTTP relay {} has no {}Request", self.name, state_name(on)),
+            )
+        })?;
+
+        http_relay_functions::send(
+            request,
+            &[
+                ("ip", &self.ip),
+                ("name", &self.name),
+                ("state", state_name(on)),
+            ],
+        )?;
+        // A status read started before the switch would report the old state
+        self.pending.clear();
+        self.status = on;
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for HttpRelay {
+    relay_fields!("Http", ip, options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Http",
+            "ip": &self.ip,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    /// Status parsed from the `statusRequest` response. Without a status
+    /// request the status is whatever was last commanded. A slow request
+    /// keeps running in the background, see `PendingStatus`.
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let Some(request) = &self.settings.status_request else {
+            return Ok(self.status);
+        };
+        let read = self.status_request(request);
+        self.status = self.pending.read(self.status, read)?;
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        let status = self.status;
+        self.set(!status)
+    }
+}
+
+pub(crate) struct HttpDriver;
+
+impl RelayDriver for HttpDriver {
+    fn type_name(&self) -> &'static str {
+        "Http"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        HttpOptions::json_schema(gen)
+    }
+
+    fn needs_ip(&self) -> bool {
+        false
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        if relay.name.is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no name", label)));
+        }
+        let options: HttpOptions = check_options(label, relay, issues);
+        for (request, field) in [
+            (&options.on_request, "onRequest"),
+            (&options.off_request, "offRequest"),
+        ] {
+            if request.is_none() {
+                issues.push(ConfigIssue::error(format!("{} has no `{}`", label, field)));
+            }
+        }
+        options.state.check(
+            label,
+            options.status_request.is_some(),
+            "statusRequest",
+            issues,
+        );
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let http = HttpRelay::new(relay.ip, relay.name, relay.room, relay.tags, relay.options)?;
+            Ok(vec![Box::new(http) as RelayType])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::test_support::{http_stub, ok};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_http_relay_templates_and_status() {
+        let state = Arc::new(Mutex::new(false));
+        let device = state.clone();
+        let (ip, requests) = http_stub(Box::new(move |path, authorization| {
+            if authorization != Some("Bearer token") {
+                return (401, String::new(), String::new());
+            }
+            let mut on = device.lock().unwrap();
+            match path {
+                "/relay?name=Heater&turn=on" => *on = true,
+                "/relay?name=Heater&turn=off" => *on = false,
+                _ => {}
+            }
+            ok(&format!(r#"{{"relay": {{"ison": {}}}}}"#, *on))
+        }));
+        let request = |query: &str| HttpRequest {
+            url: format!("http://{{{{ip}}}}/relay{}", query),
+            headers: [("Authorization".to_string(), "Bearer token".to_string())].into(),
+            ..HttpRequest::default()
+        };
+        let options = HttpOptions {
+            on_request: Some(request("?name={{name}}&turn={{state}}")),
+            off_request: Some(request("?name={{name}}&turn={{state}}")),
+            status_request: Some(request("")),
+            state: StateParsing {
+                state_pointer: Some("/relay/ison".to_string()),
+                ..StateParsing::default()
+            },
+        };
+
+        let mut relay = HttpRelay::new(
+            ip,
+            "Heater".to_string(),
+            "garage".to_string(),
+            vec![],
+            serde_json::from_value(json!(options)).unwrap(),
+        )
+        .unwrap();
+        assert!(!relay.status);
+
+        relay.switch().unwrap();
+        assert!(*state.lock().unwrap());
+        assert!(relay.get_status().unwrap());
+        relay.turn_off().unwrap();
+        assert!(!relay.get_status().unwrap());
+        assert_eq!(
+            requests.lock().unwrap()[1],
+            "/relay?name=Heater&turn=on".to_string()
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::{blocking, DriverFuture, RelayDriver};
+use crate::models::config_models::ConfigRelay;
+use crate::models::kasa_network_models::{
+    EmeterReading, MultiPlugStatus, PlugMutateResponse, PlugStatusWithEmeter,
+};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::kasa_plug_network_functions;
+
+#[derive(Debug, PartialEq)]
+pub struct KasaPlug {
+    pub(crate) ip: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    /// Last energy meter reading, for plugs that have one
+    pub(crate) emeter: Option<EmeterReading>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KasaMultiPlug {
+    pub(crate) ip: String,
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+}
+
+impl KasaPlug {
+    pub fn new(ip: String, name: String, room: String, tags: Vec<String>) -> Self {
+        KasaPlug {
+            ip,
+            name,
+            status: false,
+            tags,
+            room,
+            emeter: None,
+        }
+    }
+}
+
+impl RelayActions for KasaPlug {
+    relay_fields!("KasaPlug", ip);
+
+    fn emeter(&self) -> Option<&EmeterReading> {
+        self.emeter.as_ref()
+    }
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()
+    }
+
+    fn to_json(&self) -> Value {
+        let mut result = json!({
+            "type": "Kasa Plug",
+            "ip": &self.ip,
+            "name": &self.name,
+            "status": &self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        });
+        if let Some(emeter) = &self.emeter {
+            result["emeter"] = json!(emeter);
+        }
+        result
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let cmd = json!({"system": {"get_sysinfo": {}}, "emeter": {"get_realtime": {}}});
+        let response =
+            kasa_plug_network_functions::send::<PlugStatusWithEmeter>(&self.ip, &cmd.to_string())?;
+        let relay_state = response.system.get_sysinfo.relay_state == 1;
+        self.status = relay_state;
+        self.emeter = response
+            .emeter
+            .and_then(|emeter| emeter.get_realtime)
+            .and_then(|realtime| realtime.reading());
+        Ok(relay_state)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        let cmd = json!({"system": {"set_relay_state": {"state": 0}}});
+
+        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
+            Ok(..) => {
+                self.status = false;
+                Ok(self.to_json())
+            }
+            Err(..) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Can't Connect To Plug".to_string(),
+            )),
+        }
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        let cmd = json!({"system": {"set_relay_state": {"state": 1}}});
+        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
+            Ok(..) => {
+                self.status = true;
+                Ok(self.to_json())
+            }
+            Err(..) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Can't Connect To Plug".to_string(),
+            )),
+        }
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        match self.status {
+            true => self.turn_off(),
+            false => self.turn_on(),
+        }
+    }
+}
+
+impl KasaMultiPlug {
+    pub fn new(
+        ip: String,
+        names: Vec<String>,
+        room: String,
+        tags: Vec<String>,
+    ) -> Result<Vec<KasaMultiPlug>, Error> {
+        let command = json!({"system": {"get_sysinfo": {}}});
+        let response =
+            match kasa_plug_network_functions::send::<MultiPlugStatus>(&ip, &command.to_string()) {
+                Ok(response) => response,
+                Err(..) => {
+                    return Err(Error::new(
+                        ErrorKind::NotConnected,
+                        format!("Unable to connect to KasaMultiPlug {}", ip),
+                    ))
+                }
+            };
+
+        let mut multi_plug_children: Vec<KasaMultiPlug> = Vec::new();
+
+        for (child, name) in response
+            .system
+            .get_sysinfo
+            .children
+            .iter()
+            .zip(names.iter())
+        {
+            multi_plug_children.push(KasaMultiPlug {
+                ip: ip.clone(),
+                id: child.id.to_string(),
+                name: name.clone(),
+                status: child.state == 1,
+                room: room.clone(),
+                tags: tags.clone(),
+            })
+        }
+
+        Ok(multi_plug_children)
+    }
+
+    /// Number of outlets reported by the multi-plug at `ip`.
+    pub fn outlet_count(ip: &str) -> Result<usize, Error> {
+        let command = json!({"system": {"get_sysinfo": {}}});
+        let response =
+            kasa_plug_network_functions::send::<MultiPlugStatus>(ip, &command.to_string())?;
+        Ok(response.system.get_sysinfo.children.len())
+    }
+}
+
+impl RelayActions for KasaMultiPlug {
+    relay_fields!("KasaMultiPlug", ip);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        let command = json!({"system": {"get_sysinfo": {}}});
+        let _ =
+            kasa_plug_network_functions::send::<MultiPlugStatus>(&self.ip, &command.to_string())?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Kasa Plug",
+            "ip": &self.ip,
+            "id": &self.id,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let cmd = json!({"system": {"get_sysinfo": {}}});
+        let result =
+            kasa_plug_network_functions::send::<MultiPlugStatus>(&self.ip, &cmd.to_string())?;
+
+        for child in result.system.get_sysinfo.children {
+            if child.id == self.id {
+                let relay_state = child.state == 1;
+                self.status = relay_state;
+                return Ok(relay_state);
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::NotFound,
+            "Can't Connect to To Plug".to_string(),
+        ))
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        let cmd = json!({"context": {"child_ids": [self.id.clone()]}, "system": {"set_relay_state": {"state": 0}}});
+        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
+            Ok(..) => {
+                self.status = false;
+                Ok(self.to_json())
+            }
+            Err(..) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Can't Connect To Plug".to_string(),
+            )),
+        }
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        let cmd = json!({"context": {"child_ids": [self.id.clone()]}, "system": {"set_relay_state": {"state": 1}}});
+        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
+            Ok(..) => {
+                self.status = true;
+                Ok(self.to_json())
+            }
+            Err(..) => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                "Can't Connect To Plug".to_string(),
+            )),
+        }
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        match self.status {
+            true => self.turn_off(),
+            false => self.turn_on(),
+        }
+    }
+}
+
+/// Checks the entry of a Kasa device that is a single relay.
+pub(crate) fn check_single_relay(label: &str, relay: &ConfigRelay, issues: &mut Vec<ConfigIssue>) {
+    if relay.name.is_empty() {
+        issues.push(ConfigIssue::error(format!("{} has no name", label)));
+    }
+    if !relay.names.is_empty() {
+        issues.push(ConfigIssue::warning(format!(
+            "{} is a {}, its `names` are ignored",
+            label, relay.relay_type
+        )));
+    }
+}
+
+pub(crate) struct KasaPlugDriver;
+
+impl RelayDriver for KasaPlugDriver {
+    fn type_name(&self) -> &'static str {
+        "KasaPlug"
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        check_single_relay(label, relay, issues);
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let mut plug = KasaPlug::new(relay.ip, relay.name, relay.room, relay.tags);
+            plug.connected()?;
+            Ok(vec![Box::new(plug) as RelayType])
+        })
+    }
+}
+
+pub(crate) struct KasaMultiPlugDriver;
+
+impl RelayDriver for KasaMultiPlugDriver {
+    fn type_name(&self) -> &'static str {
+        "KasaMultiPlug"
+    }
+
+    fn lists_names(&self) -> bool {
+        true
+    }
+
+    fn relay_names(&self, relay: &ConfigRelay) -> Vec<String> {
+        relay.names.clone()
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        if relay.names.is_empty() {
+            issues.push(ConfigIssue::error(format!(
+                "{} is a KasaMultiPlug but lists no `names`",
+                label
+            )));
+        }
+        if !relay.name.is_empty() {
+            issues.push(ConfigIssue::warning(format!(
+                "{} is a KasaMultiPlug, its `name` is ignored in favour of `names`",
+                label
+            )));
+        }
+    }
+
+    /// Also reports `names` that don't match the number of outlets.
+    fn probe<'a>(
+        &'a self,
+        label: &'a str,
+        relay: &'a ConfigRelay,
+        issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async move {
+            let ip = relay.ip.clone();
+            match blocking(move || KasaMultiPlug::outlet_count(&ip)).await {
+                Ok(outlets) if outlets != relay.names.len() => {
+                    issues.push(ConfigIssue::error(format!(
+                        "{} lists {} names but the device at {} has {} outlets",
+                        label,
+                        relay.names.len(),
+                        relay.ip,
+                        outlets
+                    )))
+                }
+                Ok(_) => {}
+                Err(error) => issues.push(ConfigIssue::warning(format!(
+                    "{} at {} is unreachable: {}",
+                    label, relay.ip, error
+                ))),
+            }
+        })
+    }
+
+    /// Outlets that don't answer are left out.
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let plugs = KasaMultiPlug::new(relay.ip, relay.names, relay.room, relay.tags)?;
+            Ok(plugs
+                .into_iter()
+                .filter_map(|mut plug| match plug.connected() {
+                    Ok(_) => Some(Box::new(plug) as RelayType),
+                    Err(_) => None,
+                })
+                .collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::kasa_network_models::EmeterGetRealtime;
+
+    // #[test]
+    // fn test_singleplug_timeouts() {
+    //     let mut plug = KasaPlug::new("192.168.0.107".to_string(), "ScentLight".to_string(), "bedroom".to_string());
+    //     println!("{:?}", &plug.connected());
+    // }
+
+    #[test]
+    fn test_emeter_readings() {
+        let older: EmeterGetRealtime = serde_json::from_str(
+            r#"{"get_realtime": {"current": 0.05, "voltage": 230.1, "power": 11.5, "total": 1.2, "err_code": 0}}"#,
+        )
+        .unwrap();
+        let newer: EmeterGetRealtime = serde_json::from_str(
+            r#"{"get_realtime": {"current_ma": 50, "voltage_mv": 230100, "power_mw": 11500, "total_wh": 1200, "err_code": 0}}"#,
+        )
+        .unwrap();
+        let unsupported: EmeterGetRealtime =
+            serde_json::from_str(r#"{"err_code": -1, "err_msg": "module not support"}"#).unwrap();
+
+        let reading = older.get_realtime.unwrap().reading().unwrap();
+        assert_eq!(reading, newer.get_realtime.unwrap().reading().unwrap());
+        assert_eq!(reading.power, 11.5);
+        assert_eq!(reading.total, 1.2);
+        assert!(unsupported.get_realtime.is_none());
+    }
+
+    #[test]
+    fn test_multiplug_stuff() {
+        let ip = "192.168.0.218".to_string();
+
+        let mut plugs: Vec<KasaMultiPlug> = KasaMultiPlug::new(
+            ip,
+            vec![
+                "BedframeLight".parse().unwrap(),
+                "BedroomLight".parse().unwrap(),
+            ],
+            "Bedroom".parse().unwrap(),
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(plugs.len(), 2);
+
+        for plug in &plugs {
+            println!("{} \t {}", &plug.name, &plug.id);
+        }
+
+        plugs
+            .get_mut(0)
+            .unwrap()
+            .turn_on()
+            .expect("TODO: panic message");
+    }
+}
@@ -0,0 +1,442 @@
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::kasa::check_single_relay;
+use crate::drivers::{blocking, DriverFuture, RelayDriver};
+use crate::models::config_models::ConfigRelay;
+use crate::models::kasa_network_models::{BulbStatus, DimmerStatus, LightState};
+use crate::models::relays::{relay_fields, LightLevels, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::kasa_plug_network_functions;
+
+/// Wall dimmer like the HS220, a relay with a brightness.
+#[derive(Debug, PartialEq)]
+pub struct KasaDimmer {
+    pub(crate) ip: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) brightness: u8,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+}
+
+/// Bulb or light strip of the KL series. What it can change besides the
+/// brightness is read from the bulb when it connects.
+#[derive(Debug, PartialEq)]
+pub struct KasaBulb {
+    pub(crate) ip: String,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) levels: LightLevels,
+    pub(crate) light_strip: bool,
+    pub(crate) color: bool,
+    pub(crate) color_temp: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+}
+
+/// Sends `command` to the Kasa device at `ip`, failing when the answer at
+/// each of `modules` has a non-zero `err_code`.
+fn send(ip: &str, command: &Value, modules: &[&str]) -> Result<Value, Error> {
+    let response = kasa_plug_network_functions::send::<Value>(ip, &command.to_string())?;
+    for module in modules {
+        let code = response
+            .pointer(&format!("{}/err_code", module))
+            .and_then(Value::as_i64)
+            .unwrap_or(0);
+        if code != 0 {
+            return Err(Error::other(format!(
+                "{} answered {} with error {}",
+                ip, module, code
+            )));
+        }
+    }
+    Ok(response)
+}
+
+impl KasaDimmer {
+    pub fn new(ip: String, name: String, room: String, tags: Vec<String>) -> Self {
+        KasaDimmer {
+            ip,
+            name,
+            status: false,
+            brightness: 100,
+            room,
+            tags,
+        }
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        let command = json!({"system": {"set_relay_state": {"state": i32::from(on)}}});
+        send(&self.ip, &command, &["/system/set_relay_state"])?;
+        self.status = on;
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for KasaDimmer {
+    relay_fields!("KasaDimmer", ip);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Kasa Dimmer",
+            "ip": &self.ip,
+            "name": &self.name,
+            "status": self.status,
+            "levels": self.levels(),
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let command = json!({"system": {"get_sysinfo": {}}});
+        let response =
+            kasa_plug_network_functions::send::<DimmerStatus>(&self.ip, &command.to_string())?;
+        self.status = response.system.get_sysinfo.relay_state == 1;
+        self.brightness = response.system.get_sysinfo.brightness;
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        let status = self.status;
+        self.set(!status)
+    }
+
+    fn levels(&self) -> Option<LightLevels> {
+        Some(LightLevels {
+            brightness: Some(self.brightness),
+            ..LightLevels::default()
+        })
+    }
+
+    fn set_levels(&mut self, levels: LightLevels) -> Result<Value, Error> {
+        if levels.has_color() || levels.color_temp.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} is a dimmer, it only has a brightness", self.name),
+            ));
+        }
+
+        let brightness = levels.brightness.unwrap_or(self.brightness);
+        let command = json!({
+            "smartlife.iot.dimmer": {"set_brightness": {"brightness": brightness}},
+            "system": {"set_relay_state": {"state": 1}},
+        });
+        send(
+            &self.ip,
+            &command,
+            &[
+                "/smartlife.iot.dimmer/set_brightness",
+                "/system/set_relay_state",
+            ],
+        )?;
+        self.brightness = brightness;
+        self.status = true;
+        Ok(self.to_json())
+    }
+}
+
+impl KasaBulb {
+    /// Reads what the bulb at `ip` can change along with its state.
+    pub fn new(ip: String, name: String, room: String, tags: Vec<String>) -> Result<Self, Error> {
+        let mut bulb = KasaBulb {
+            ip,
+            name,
+            status: false,
+            levels: LightLevels::default(),
+            light_strip: false,
+            color: false,
+            color_temp: false,
+            room,
+            tags,
+        };
+        bulb.get_status()?;
+        Ok(bulb)
+    }
+
+    /// Module and method setting the light state. Light strips have their own.
+    fn service(&self) -> (&'static str, &'static str) {
+        match self.light_strip {
+            true => ("smartlife.iot.lightStrip", "set_light_state"),
+            false => (
+                "smartlife.iot.smartbulb.lightingservice",
+                "transition_light_state",
+            ),
+        }
+    }
+
+    /// Takes the status and levels from a reported light state. The colour
+    /// temperature is 0 while the bulb shows a hue and saturation.
+    fn update(&mut self, state: &LightState) {
+        self.status = state.on_off == 1;
+        let levels = state.dft_on_state.as_deref().unwrap_or(state);
+        let color_temp = levels.color_temp.filter(|kelvin| *kelvin != 0);
+        self.levels = LightLevels {
+            brightness: levels.brightness,
+            color_temp,
+            hue: levels.hue.filter(|_| color_temp.is_none()),
+            saturation: levels.saturation.filter(|_| color_temp.is_none()),
+        };
+    }
+
+    /// Light state arguments turning the bulb on at `levels`.
+    fn light_state(&self, levels: &LightLevels) -> Result<Value, Error> {
+        if levels.has_color() && levels.color_temp.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Set either a colour temperature or a hue and saturation".to_string(),
+            ));
+        }
+        if levels.has_color() && !self.color {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} can't change its colour", self.name),
+            ));
+        }
+        if levels.color_temp.is_some() && !self.color_temp {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} has no colour temperature", self.name),
+            ));
+        }
+
+        let mut state = json!({"on_off": 1, "ignore_default": 1});
+        if let Some(brightness) = levels.brightness {
+            state["brightness"] = json!(brightness);
+        }
+        if let Some(kelvin) = levels.color_temp {
+            state["color_temp"] = json!(kelvin);
+        }
+        if levels.has_color() {
+            // A colour temperature of 0 switches the bulb to hue and saturation
+            state["color_temp"] = json!(0);
+            state["hue"] = json!(levels.hue.or(self.levels.hue).unwrap_or(0));
+            state["saturation"] =
+                json!(levels.saturation.or(self.levels.saturation).unwrap_or(100));
+        }
+        Ok(state)
+    }
+
+    fn transition(&mut self, state: Value) -> Result<Value, Error> {
+        let (module, method) = self.service();
+        let response = send(
+            &self.ip,
+            &json!({module: {method: state}}),
+            &[&format!("/{}/{}", module, method)],
+        )?;
+        let state: LightState = serde_json::from_value(response[module][method].clone())
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+        self.update(&state);
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for KasaBulb {
+    relay_fields!("KasaBulb", ip);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Kasa Bulb",
+            "ip": &self.ip,
+            "name": &self.name,
+            "status": self.status,
+            "levels": self.levels,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let command = json!({"system": {"get_sysinfo": {}}});
+        let response =
+            kasa_plug_network_functions::send::<BulbStatus>(&self.ip, &command.to_string())?;
+        let info = response.system.get_sysinfo;
+        self.light_strip = info.length.is_some();
+        self.color = info.is_color == 1;
+        self.color_temp = info.is_variable_color_temp == 1;
+        self.update(&info.light_state);
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.transition(json!({"on_off": 0}))
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.transition(json!({"on_off": 1}))
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        match self.status {
+            true => self.turn_off(),
+            false => self.turn_on(),
+        }
+    }
+
+    fn levels(&self) -> Option<LightLevels> {
+        Some(self.levels)
+    }
+
+    fn set_levels(&mut self, levels: LightLevels) -> Result<Value, Error> {
+        let state = self.light_state(&levels)?;
+        self.transition(state)
+    }
+}
+
+pub(crate) struct KasaDimmerDriver;
+
+impl RelayDriver for KasaDimmerDriver {
+    fn type_name(&self) -> &'static str {
+        "KasaDimmer"
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        check_single_relay(label, relay, issues);
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let mut dimmer = KasaDimmer::new(relay.ip, relay.name, relay.room, relay.tags);
+            dimmer.connected()?;
+            Ok(vec![Box::new(dimmer) as RelayType])
+        })
+    }
+}
+
+pub(crate) struct KasaBulbDriver;
+
+impl RelayDriver for KasaBulbDriver {
+    fn type_name(&self) -> &'static str {
+        "KasaBulb"
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        check_single_relay(label, relay, issues);
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let bulb = KasaBulb::new(relay.ip, relay.name, relay.room, relay.tags)?;
+            Ok(vec![Box::new(bulb) as RelayType])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulb(color: bool) -> KasaBulb {
+        KasaBulb {
+            ip: "192.168.0.30".to_string(),
+            name: "Reading".to_string(),
+            status: false,
+            levels: LightLevels::default(),
+            light_strip: false,
+            color,
+            color_temp: true,
+            room: "living".to_string(),
+            tags: vec![],
+        }
+    }
+
+    #[test]
+    fn test_bulb_light_states() {
+        let mut lamp = bulb(true);
+        let off: LightState = serde_json::from_str(
+            r#"{"on_off": 0, "dft_on_state": {"mode": "normal", "hue": 120, "saturation": 80, "color_temp": 0, "brightness": 40}, "err_code": 0}"#,
+        )
+        .unwrap();
+        lamp.update(&off);
+        assert!(!lamp.status);
+        assert_eq!(
+            lamp.levels,
+            LightLevels {
+                brightness: Some(40),
+                color_temp: None,
+                hue: Some(120),
+                saturation: Some(80),
+            }
+        );
+
+        let warm: LightState = serde_json::from_str(
+            r#"{"on_off": 1, "mode": "normal", "hue": 120, "saturation": 80, "color_temp": 2700, "brightness": 75}"#,
+        )
+        .unwrap();
+        lamp.update(&warm);
+        assert!(lamp.status);
+        assert_eq!(lamp.levels.color_temp, Some(2700));
+        assert_eq!(lamp.levels.hue, None);
+
+        let red = LightLevels {
+            hue: Some(0),
+            ..LightLevels::default()
+        };
+        assert_eq!(
+            lamp.light_state(&red).unwrap(),
+            json!({"on_off": 1, "ignore_default": 1, "color_temp": 0, "hue": 0, "saturation": 100})
+        );
+        assert_eq!(
+            bulb(false).light_state(&red).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            lamp.light_state(&LightLevels {
+                color_temp: Some(4000),
+                ..red
+            })
+            .unwrap_err()
+            .kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(lamp.service().1, "transition_light_state");
+    }
+
+    #[test]
+    fn test_dimmer_only_has_a_brightness() {
+        let mut dimmer = KasaDimmer::new(
+            "192.168.0.31".to_string(),
+            "Hallway".to_string(),
+            "hall".to_string(),
+            vec![],
+        );
+        let warm = LightLevels {
+            color_temp: Some(2700),
+            ..LightLevels::default()
+        };
+        assert_eq!(
+            dimmer.set_levels(warm).unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(dimmer.to_json()["levels"], json!({"brightness": 100}));
+    }
+}
@@ -0,0 +1,511 @@
+//! Relay drivers, one per config `type`. A driver checks the config entries of
+//! its type and makes their relays, so a new kind of device only needs a
+//! module here, behind its own cargo feature, and an entry in `DRIVERS`.
+//!
+//! remoterelay only builds as a binary, so drivers are compiled in rather than
+//! registered by other crates. Connecting and probing are async and return
+//! boxed futures, which keeps the trait object-safe, so every entry of a
+//! config is connected at once instead of one device timeout after another.
+//! Most device protocols are plain blocking sockets, those drivers run their
+//! work on its own thread with `blocking`.
+
+use std::future::Future;
+use std::io::Error;
+use std::pin::Pin;
+use std::thread;
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+#[cfg(any(feature = "http", feature = "exec", feature = "mqtt"))]
+use serde_json::Value;
+
+use crate::models::config_models::ConfigRelay;
+use crate::models::relays::RelayType;
+use crate::utils::config_validation::ConfigIssue;
+
+#[cfg(feature = "esphome")]
+pub(crate) mod esphome;
+#[cfg(feature = "exec")]
+pub(crate) mod exec;
+#[cfg(feature = "gpio")]
+pub(crate) mod gpio;
+pub(crate) mod group;
+#[cfg(feature = "http")]
+pub(crate) mod http;
+pub(crate) mod kasa;
+pub(crate) mod kasa_light;
+#[cfg(feature = "mqtt")]
+pub(crate) mod mqtt;
+#[cfg(feature = "shelly")]
+pub(crate) mod shelly;
+#[cfg(any(feature = "http", feature = "exec"))]
+pub(crate) mod state_parsing;
+#[cfg(feature = "tasmota")]
+pub(crate) mod tasmota;
+pub(crate) mod virtual_relay;
+
+/// Fields every config entry can set, whatever its type.
+const COMMON_FIELDS: [&str; 7] = ["type", "name", "names", "ip", "room", "tags", "restore"];
+
+/// Future returned by the async methods of a driver.
+pub type DriverFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Runs blocking device code on its own thread, finishing when it returns.
+pub(crate) fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, Error> + Send + 'static,
+) -> DriverFuture<'static, Result<T, Error>> {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    thread::spawn(move || {
+        let _ = sender.send(work());
+    });
+    Box::pin(async move {
+        receiver
+            .await
+            .unwrap_or_else(|_| Err(Error::other("Driver thread stopped without a result")))
+    })
+}
+
+/// Makes the relays of one config `type`.
+pub trait RelayDriver: Sync {
+    /// Value of `type` that selects the driver.
+    fn type_name(&self) -> &'static str;
+
+    /// Schema of the options the driver reads out of an entry, usually the
+    /// schema of its options struct. Entries of the driver's type setting any
+    /// other option get a warning, and the config schema only allows these.
+    fn options_schema(&self, _gen: &mut SchemaGenerator) -> Schema {
+        Schema::Object(SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            ..SchemaObject::default()
+        })
+    }
+
+    /// Whether entries need an `ip`.
+    fn needs_ip(&self) -> bool {
+        true
+    }
+
+    /// Whether entries always list their relays in `names`, like the outlets
+    /// of a multi-plug, rather than using `name` for a single relay.
+    fn lists_names(&self) -> bool {
+        false
+    }
+
+    /// Names of the relays an entry makes.
+    fn relay_names(&self, relay: &ConfigRelay) -> Vec<String> {
+        vec![relay.name.clone()]
+    }
+
+    /// Adds the problems with an entry that show without connecting to its
+    /// device. `relays` are all the entries of the config.
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        if relay.name.is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no name", label)));
+        }
+    }
+
+    /// Connects to an entry's device, adding a warning when it doesn't answer
+    /// and anything else about the device that doesn't match the entry.
+    fn probe<'a>(
+        &'a self,
+        label: &'a str,
+        relay: &'a ConfigRelay,
+        issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async move {
+            if let Err(error) = self.connect(relay.clone()).await {
+                issues.push(ConfigIssue::warning(match self.needs_ip() {
+                    true => format!("{} at {} is unreachable: {}", label, relay.ip, error),
+                    false => format!("{} is unreachable: {}", label, error),
+                }));
+            }
+        })
+    }
+
+    /// Makes the relays of an entry, connecting to their device.
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>>;
+}
+
+/// Every driver built in, in the order the config schema lists their types.
+static DRIVERS: &[&dyn RelayDriver] = &[
+    &kasa::KasaPlugDriver,
+    &kasa::KasaMultiPlugDriver,
+    &kasa_light::KasaDimmerDriver,
+    &kasa_light::KasaBulbDriver,
+    #[cfg(feature = "shelly")]
+    &shelly::ShellyDriver,
+    #[cfg(feature = "tasmota")]
+    &tasmota::TasmotaDriver,
+    #[cfg(feature = "mqtt")]
+    &mqtt::MqttDriver,
+    #[cfg(feature = "esphome")]
+    &esphome::EspHomeDriver,
+    #[cfg(feature = "gpio")]
+    &gpio::GpioDriver,
+    #[cfg(feature = "http")]
+    &http::HttpDriver,
+    #[cfg(feature = "exec")]
+    &exec::ExecDriver,
+    &group::GroupDriver,
+    &virtual_relay::VirtualDriver,
+];
+
+/// Driver of the config `type` called `type_name`.
+pub(crate) fn driver(type_name: &str) -> Option<&'static dyn RelayDriver> {
+    DRIVERS
+        .iter()
+        .find(|driver| driver.type_name() == type_name)
+        .copied()
+}
+
+pub(crate) fn driver_names() -> Vec<&'static str> {
+    DRIVERS.iter().map(|driver| driver.type_name()).collect()
+}
+
+/// Options a driver reads, as they're named in the config.
+fn option_names(driver: &dyn RelayDriver, gen: &mut SchemaGenerator) -> Vec<String> {
+    match driver.options_schema(gen) {
+        Schema::Object(schema) => schema
+            .object
+            .map(|object| object.properties.into_keys().collect())
+            .unwrap_or_default(),
+        Schema::Bool(_) => Vec::new(),
+    }
+}
+
+/// Options set on `relay` that its driver doesn't read.
+pub(crate) fn ignored_options(relay: &ConfigRelay) -> Vec<String> {
+    let Some(driver) = relay.relay_type.driver() else {
+        return Vec::new();
+    };
+    let options = option_names(driver, &mut SchemaGenerator::default());
+    relay
+        .options
+        .names()
+        .filter(|option| !options.contains(option))
+        .cloned()
+        .collect()
+}
+
+/// Schema rules allowing each relay type only the common fields and the
+/// options of its driver, with the types its driver reads them as. Types the
+/// options use are added to the definitions of `gen`.
+pub(crate) fn option_schemas(gen: &mut SchemaGenerator) -> Vec<Schema> {
+    DRIVERS
+        .iter()
+        .filter_map(|driver| {
+            let options = driver.options_schema(gen);
+            let fields: Vec<String> = COMMON_FIELDS
+                .iter()
+                .map(|field| field.to_string())
+                .chain(option_names(*driver, gen))
+                .collect();
+            serde_json::from_value(json!({
+                "if": {"properties": {"type": {"const": driver.type_name()}}},
+                "then": {"allOf": [options, {"propertyNames": {"enum": fields}}]},
+            }))
+            .ok()
+        })
+        .collect()
+}
+
+/// Reads the options of an entry as `T`, adding an error when they don't
+/// parse.
+pub(crate) fn check_options<T: DeserializeOwned + Default>(
+    label: &str,
+    relay: &ConfigRelay,
+    issues: &mut Vec<ConfigIssue>,
+) -> T {
+    relay.options.parse().unwrap_or_else(|error| {
+        issues.push(ConfigIssue::error(format!("{} has {}", label, error)));
+        T::default()
+    })
+}
+
+/// Names of an entry's relays, its `names` or else its single `name`.
+#[cfg(any(
+    feature = "shelly",
+    feature = "tasmota",
+    feature = "esphome",
+    feature = "gpio"
+))]
+pub(crate) fn entry_names(relay: &ConfigRelay) -> Vec<String> {
+    match relay.names.is_empty() {
+        true => vec![relay.name.clone()],
+        false => relay.names.clone(),
+    }
+}
+
+/// Channels of a multi-channel device and the relay name of each, counted
+/// from `first`. `names` are given in channel order, a single `name` uses
+/// `channel`. ESPHome channels count the switch entities in the order the
+/// device lists them.
+#[cfg(any(feature = "shelly", feature = "tasmota", feature = "esphome"))]
+pub(crate) fn channels(relay: &ConfigRelay, first: u8, channel: Option<u8>) -> Vec<(u8, String)> {
+    match relay.names.is_empty() {
+        true => vec![(channel.unwrap_or(first), relay.name.clone())],
+        false => (first..).zip(relay.names.iter().cloned()).collect(),
+    }
+}
+
+/// Checks the `name` or `names` of a multi-channel device's entry.
+#[cfg(any(feature = "shelly", feature = "tasmota", feature = "esphome"))]
+pub(crate) fn check_channel_names(label: &str, relay: &ConfigRelay, issues: &mut Vec<ConfigIssue>) {
+    if relay.name.is_empty() && relay.names.is_empty() {
+        issues.push(ConfigIssue::error(format!(
+            "{} has no `name` or `names`",
+            label
+        )));
+    }
+    if !relay.name.is_empty() && !relay.names.is_empty() {
+        issues.push(ConfigIssue::warning(format!(
+            "{} is a {} with `names`, its `name` is ignored",
+            label, relay.relay_type
+        )));
+    }
+}
+
+/// `on` or `off`, as filled in for `{{state}}` in requests and commands.
+#[cfg(any(feature = "http", feature = "exec"))]
+pub(crate) fn state_name(on: bool) -> &'static str {
+    match on {
+        true => "on",
+        false => "off",
+    }
+}
+
+/// Text a state value is compared against the configured states as.
+#[cfg(any(feature = "http", feature = "exec", feature = "mqtt"))]
+pub(crate) fn state_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+// Each stub is only used by the tests of the drivers built in
+#[cfg_attr(
+    not(all(
+        any(feature = "shelly", feature = "tasmota", feature = "http"),
+        feature = "mqtt"
+    )),
+    allow(dead_code)
+)]
+pub(crate) mod test_support {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    pub(crate) type Handler = dyn Fn(&str, Option<&str>) -> (u16, String, String) + Send;
+
+    /// Serves HTTP on a free local port, answering each request with
+    /// `handler(path, authorization)`. Returns the address and the paths
+    /// requested so far.
+    pub(crate) fn http_stub(handler: Box<Handler>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("/").to_string();
+
+                let mut authorization = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("authorization") {
+                            authorization = Some(value.trim().to_string());
+                        }
+                    }
+                }
+
+                seen.lock().unwrap().push(path.clone());
+                let (status, headers, body) = handler(&path, authorization.as_deref());
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Stub\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        (address, requests)
+    }
+
+    pub(crate) fn ok(body: &str) -> (u16, String, String) {
+        (200, String::new(), body.to_string())
+    }
+
+    /// Retained or last published payload of each topic on an `mqtt_stub`.
+    pub(crate) type MqttTopics = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    type Subscribers = Arc<Mutex<Vec<(String, TcpStream)>>>;
+
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0u8; 1];
+        stream.read_exact(&mut header).ok()?;
+        let mut length = 0usize;
+        for shift in 0..4 {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).ok()?;
+            length |= usize::from(byte[0] & 0x7f) << (7 * shift);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).ok()?;
+        Some((header[0], body))
+    }
+
+    fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut packet = vec![header];
+        let mut length = body.len();
+        loop {
+            let byte = (length % 128) as u8;
+            length /= 128;
+            packet.push(if length > 0 { byte | 0x80 } else { byte });
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn read_string(body: &[u8], at: usize) -> (String, usize) {
+        let length = usize::from(u16::from_be_bytes([body[at], body[at + 1]]));
+        let text = String::from_utf8_lossy(&body[at + 2..at + 2 + length]).to_string();
+        (text, at + 2 + length)
+    }
+
+    fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend_from_slice(topic.as_bytes());
+        body.extend_from_slice(payload);
+        packet(0x30, &body)
+    }
+
+    fn matches(filter: &str, topic: &str) -> bool {
+        let mut levels = topic.split('/');
+        for part in filter.split('/') {
+            match (part, levels.next()) {
+                ("#", _) => return true,
+                ("+", Some(_)) => {}
+                (part, Some(level)) if part == level => {}
+                _ => return false,
+            }
+        }
+        levels.next().is_none()
+    }
+
+    fn serve_mqtt_client(mut stream: TcpStream, topics: MqttTopics, subscribers: Subscribers) {
+        while let Some((header, body)) = read_packet(&mut stream) {
+            let reply = match header >> 4 {
+                // CONNECT
+                1 => packet(0x20, &[0, 0]),
+                // PUBLISH, delivered at QoS 0
+                3 => {
+                    let (topic, mut at) = read_string(&body, 0);
+                    let qos = (header >> 1) & 0x03;
+                    let packet_id = body.get(at..at + 2).map(<[u8]>::to_vec);
+                    if qos > 0 {
+                        at += 2;
+                    }
+                    let payload = body[at..].to_vec();
+                    topics
+                        .lock()
+                        .unwrap()
+                        .insert(topic.clone(), payload.clone());
+                    for (filter, subscriber) in subscribers.lock().unwrap().iter_mut() {
+                        if matches(filter, &topic) {
+                            let _ = subscriber.write_all(&publish_packet(&topic, &payload));
+                        }
+                    }
+                    match (qos, packet_id) {
+                        (0, _) | (_, None) => continue,
+                        (_, Some(packet_id)) => packet(0x40, &packet_id),
+                    }
+                }
+                // SUBSCRIBE, granted at QoS 0, then the retained messages
+                8 => {
+                    let mut at = 2;
+                    let mut granted = body[..2].to_vec();
+                    let mut retained = Vec::new();
+                    while at < body.len() {
+                        let (filter, next) = read_string(&body, at);
+                        at = next + 1;
+                        granted.push(0);
+                        for (topic, payload) in topics.lock().unwrap().iter() {
+                            if matches(&filter, topic) {
+                                retained.push(publish_packet(topic, payload));
+                            }
+                        }
+                        if let Ok(subscriber) = stream.try_clone() {
+                            subscribers.lock().unwrap().push((filter, subscriber));
+                        }
+                    }
+                    let _ = stream.write_all(&packet(0x90, &granted));
+                    for message in retained {
+                        let _ = stream.write_all(&message);
+                    }
+                    continue;
+                }
+                // PINGREQ
+                12 => packet(0xd0, &[]),
+                // DISCONNECT
+                14 => return,
+                _ => continue,
+            };
+            if stream.write_all(&reply).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// MQTT 3.1.1 broker on a free local port, enough for a client to
+    /// connect, subscribe and publish. Every published message is kept like
+    /// a retained one. Returns the broker url and the messages by topic, which
+    /// can be filled in beforehand.
+    pub(crate) fn mqtt_stub() -> (String, MqttTopics) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let topics: MqttTopics = Arc::new(Mutex::new(HashMap::new()));
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+        let served = topics.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let topics = served.clone();
+                let subscribers = subscribers.clone();
+                thread::spawn(move || serve_mqtt_client(stream, topics, subscribers));
+            }
+        });
+
+        (url, topics)
+    }
+}
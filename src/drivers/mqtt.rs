@@ -0,0 +1,337 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+
+use crate::drivers::{blocking, check_options, state_text, DriverFuture, RelayDriver};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::mqtt_network_functions::{self, extract_json_path};
+
+/// Options of an `Mqtt` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MqttOptions {
+    /// Broker url, `--mqtt-broker` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) broker: Option<String>,
+    /// Topic commands are published to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) command_topic: Option<String>,
+    /// Topic the device reports its state on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_topic: Option<String>,
+    /// Payload that turns the relay on, `ON` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) payload_on: Option<String>,
+    /// Payload that turns the relay off, `OFF` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) payload_off: Option<String>,
+    /// State reported when on, `payloadOn` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_on: Option<String>,
+    /// State reported when off, `payloadOff` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_off: Option<String>,
+    /// Path of the state in JSON state messages, e.g. `state` or `$.POWER`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_path: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MqttRelay {
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+    pub(crate) settings: MqttOptions,
+    /// When the last command was published, older state messages are stale
+    pub(crate) commanded_at: Option<Instant>,
+}
+
+impl MqttRelay {
+    /// Subscribes to the relay's state topic on its broker. The status starts
+    /// from the last state message, usually a retained one, if there is one.
+    pub fn new(
+        name: String,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Self, Error> {
+        let settings: MqttOptions = options.parse()?;
+        let broker = mqtt_network_functions::broker(settings.broker.as_deref())?;
+        if let Some(topic) = &settings.state_topic {
+            broker.subscribe(topic)?;
+        }
+
+        let mut relay = MqttRelay {
+            name,
+            status: false,
+            room,
+            tags,
+            options,
+            settings,
+            commanded_at: None,
+        };
+        relay.connected()?;
+        Ok(relay)
+    }
+
+    fn payload_on(&self) -> &str {
+        self.settings.payload_on.as_deref().unwrap_or("ON")
+    }
+
+    fn payload_off(&self) -> &str {
+        self.settings.payload_off.as_deref().unwrap_or("OFF")
+    }
+
+    /// Reads a state message, either the whole payload or the value at
+    /// `statePath` of a JSON payload.
+    pub(crate) fn state_from_message(&self, payload: &[u8]) -> Option<bool> {
+        let text = String::from_utf8_lossy(payload);
+        let state = match &self.settings.state_path {
+            Some(path) => {
+                let message: Value = serde_json::from_str(&text).ok()?;
+                state_text(extract_json_path(&message, path)?)
+            }
+            None => text.trim().to_string(),
+        };
+
+        let state_on = self
+            .settings
+            .state_on
+            .as_deref()
+            .unwrap_or(self.payload_on());
+        let state_off = self
+            .settings
+            .state_off
+            .as_deref()
+            .unwrap_or(self.payload_off());
+        match state.as_str() {
+            state if state == state_on => Some(true),
+            state if state == state_off => Some(false),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        let topic = self.settings.command_topic.clone().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("MQTT relay {} has no commandTopic", self.name),
+            )
+        })?;
+        let payload = match on {
+            true => self.payload_on(),
+            false => self.payload_off(),
+        };
+
+        mqtt_network_functions::broker(self.settings.broker.as_deref())?
+            .publish(&topic, payload)?;
+        self.status = on;
+        self.commanded_at = Some(Instant::now());
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for MqttRelay {
+    relay_fields!("Mqtt", options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Mqtt",
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+            "commandTopic": &self.settings.command_topic,
+            "stateTopic": &self.settings.state_topic,
+        })
+    }
+
+    /// Status from the last state message received since the last command,
+    /// so a state from before it doesn't undo it. Without a state topic the
+    /// status is whatever was last commanded.
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let broker = mqtt_network_functions::broker(self.settings.broker.as_deref())?;
+        broker.wait_connected()?;
+
+        if let Some(topic) = &self.settings.state_topic {
+            if let Some(state) = broker
+                .last_message(topic, self.commanded_at)
+                .and_then(|payload| self.state_from_message(&payload))
+            {
+                self.status = state;
+            }
+        }
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        let status = self.status;
+        self.set(!status)
+    }
+}
+
+pub(crate) struct MqttDriver;
+
+impl RelayDriver for MqttDriver {
+    fn type_name(&self) -> &'static str {
+        "Mqtt"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        MqttOptions::json_schema(gen)
+    }
+
+    fn needs_ip(&self) -> bool {
+        false
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        if relay.name.is_empty() {
+            issues.push(ConfigIssue::error(format!("{} has no name", label)));
+        }
+        let options: MqttOptions = check_options(label, relay, issues);
+        if options.command_topic.is_none() {
+            issues.push(ConfigIssue::error(format!(
+                "{} has no `commandTopic`",
+                label
+            )));
+        }
+        if options.state_path.is_some() && options.state_topic.is_none() {
+            issues.push(ConfigIssue::warning(format!(
+                "{} has a `statePath` but no `stateTopic`",
+                label
+            )));
+        }
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let mqtt = MqttRelay::new(relay.name, relay.room, relay.tags, relay.options)?;
+            Ok(vec![Box::new(mqtt) as RelayType])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::test_support::mqtt_stub;
+    use std::thread;
+    use std::time::Duration;
+
+    fn mqtt_relay(settings: MqttOptions) -> MqttRelay {
+        MqttRelay {
+            name: "Dehumidifier".to_string(),
+            status: false,
+            room: "basement".to_string(),
+            tags: vec![],
+            options: RelayOptions::default(),
+            settings,
+            commanded_at: None,
+        }
+    }
+
+    #[test]
+    fn test_mqtt_state_messages() {
+        let plain = mqtt_relay(MqttOptions::default());
+        assert_eq!(plain.state_from_message(b"ON\n"), Some(true));
+        assert_eq!(plain.state_from_message(b"OFF"), Some(false));
+        assert_eq!(plain.state_from_message(b"unavailable"), None);
+
+        let zigbee = mqtt_relay(MqttOptions {
+            payload_on: Some(r#"{"state": "ON"}"#.to_string()),
+            payload_off: Some(r#"{"state": "OFF"}"#.to_string()),
+            state_on: Some("ON".to_string()),
+            state_off: Some("OFF".to_string()),
+            state_path: Some("state".to_string()),
+            ..MqttOptions::default()
+        });
+        assert_eq!(
+            zigbee.state_from_message(br#"{"state": "OFF", "linkquality": 120}"#),
+            Some(false)
+        );
+        assert_eq!(zigbee.state_from_message(b"not json"), None);
+
+        let custom = mqtt_relay(MqttOptions {
+            payload_on: Some("1".to_string()),
+            payload_off: Some("0".to_string()),
+            state_path: Some("$.relay.on".to_string()),
+            state_on: Some("true".to_string()),
+            state_off: Some("false".to_string()),
+            ..MqttOptions::default()
+        });
+        assert_eq!(
+            custom.state_from_message(br#"{"relay": {"on": true}}"#),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_mqtt_relay_against_broker() {
+        let (url, topics) = mqtt_stub();
+        topics
+            .lock()
+            .unwrap()
+            .insert("remoterelay/test/state".to_string(), b"ON".to_vec());
+
+        let options = serde_json::from_value(json!({
+            "broker": url,
+            "commandTopic": "remoterelay/test/set",
+            "stateTopic": "remoterelay/test/state",
+        }))
+        .unwrap();
+        let mut relay =
+            MqttRelay::new("TestRelay".to_string(), "test".to_string(), vec![], options).unwrap();
+
+        // The retained state arrives right after the subscription
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !relay.get_status().unwrap() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(relay.status);
+
+        relay.turn_off().unwrap();
+        assert!(!relay.status);
+        assert!(
+            !relay.get_status().unwrap(),
+            "the retained ON is older than the command"
+        );
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while topics.lock().unwrap().get("remoterelay/test/set").is_none()
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            topics.lock().unwrap().get("remoterelay/test/set"),
+            Some(&b"OFF".to_vec())
+        );
+    }
+}
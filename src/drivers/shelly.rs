@@ -0,0 +1,330 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::Error;
+
+use crate::drivers::{
+    blocking, channels, check_channel_names, check_options, entry_names, DriverFuture, RelayDriver,
+};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::models::shelly_network_models::{
+    ShellyInfo, ShellyRelayStatus, ShellySwitchSet, ShellySwitchStatus,
+};
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::shelly_network_functions::{self, ShellyCredentials};
+
+/// Options of a `Shelly` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ShellyOptions {
+    /// Shelly generation, 1 or 2 and later, detected from the device when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) generation: Option<u8>,
+    /// Channel switched by a relay with a single `name`, 0 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) channel: Option<u8>,
+    /// Username, `admin` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) username: Option<String>,
+    /// Password, for devices with authentication turned on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ShellyRelay {
+    pub(crate) ip: String,
+    pub(crate) channel: u8,
+    pub(crate) generation: u8,
+    pub(crate) credentials: Option<ShellyCredentials>,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+}
+
+impl ShellyRelay {
+    /// Connects to the Shelly at `ip`, one relay per channel. The generation is
+    /// asked from the device unless the options give it.
+    pub fn new(
+        ip: String,
+        channels: Vec<(u8, String)>,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Vec<ShellyRelay>, Error> {
+        let shelly: ShellyOptions = options.parse()?;
+        let generation = match shelly.generation {
+            Some(generation) => generation,
+            None => Self::detect_generation(&ip)?,
+        };
+        let credentials = shelly.password.map(|password| ShellyCredentials {
+            username: shelly.username.unwrap_or("admin".to_string()),
+            password,
+        });
+
+        let mut relays: Vec<ShellyRelay> = Vec::new();
+        for (channel, name) in channels {
+            let mut relay = ShellyRelay {
+                ip: ip.clone(),
+                channel,
+                generation,
+                credentials: credentials.clone(),
+                name,
+                status: false,
+                room: room.clone(),
+                tags: tags.clone(),
+                options: options.clone(),
+            };
+            relay.get_status()?;
+            relays.push(relay);
+        }
+
+        Ok(relays)
+    }
+
+    /// Gen1 devices don't report a generation in `/shelly`, later ones do.
+    pub fn detect_generation(ip: &str) -> Result<u8, Error> {
+        let info = shelly_network_functions::get::<ShellyInfo>(ip, "/shelly", None)?;
+        Ok(info.gen.unwrap_or(1))
+    }
+
+    fn set(&mut self, on: bool) -> Result<Value, Error> {
+        let turn = if on { "on" } else { "off" };
+        let credentials = self.credentials.clone();
+        match self.generation {
+            1 => {
+                let path = format!("/relay/{}?turn={}", self.channel, turn);
+                let response = shelly_network_functions::get::<ShellyRelayStatus>(
+                    &self.ip,
+                    &path,
+                    credentials.as_ref(),
+                )?;
+                self.status = response.ison;
+            }
+            _ => {
+                let path = format!("/rpc/Switch.Set?id={}&on={}", self.channel, on);
+                shelly_network_functions::get::<ShellySwitchSet>(
+                    &self.ip,
+                    &path,
+                    credentials.as_ref(),
+                )?;
+                self.status = on;
+            }
+        }
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for ShellyRelay {
+    relay_fields!("Shelly", ip, options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Shelly",
+            "ip": &self.ip,
+            "channel": self.channel,
+            "generation": self.generation,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let credentials = self.credentials.clone();
+        let status = match self.generation {
+            1 => {
+                let path = format!("/relay/{}", self.channel);
+                shelly_network_functions::get::<ShellyRelayStatus>(
+                    &self.ip,
+                    &path,
+                    credentials.as_ref(),
+                )?
+                .ison
+            }
+            _ => {
+                let path = format!("/rpc/Switch.GetStatus?id={}", self.channel);
+                shelly_network_functions::get::<ShellySwitchStatus>(
+                    &self.ip,
+                    &path,
+                    credentials.as_ref(),
+                )?
+                .output
+            }
+        };
+        self.status = status;
+        Ok(status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set(false)
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set(true)
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        match self.status {
+            true => self.turn_off(),
+            false => self.turn_on(),
+        }
+    }
+}
+
+pub(crate) struct ShellyDriver;
+
+impl RelayDriver for ShellyDriver {
+    fn type_name(&self) -> &'static str {
+        "Shelly"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        ShellyOptions::json_schema(gen)
+    }
+
+    fn relay_names(&self, relay: &ConfigRelay) -> Vec<String> {
+        entry_names(relay)
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        check_channel_names(label, relay, issues);
+        let options: ShellyOptions = check_options(label, relay, issues);
+        if options.generation == Some(0) {
+            issues.push(ConfigIssue::error(format!(
+                "{} has generation 0, Shelly generations start at 1",
+                label
+            )));
+        }
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let channel = relay.options.parse::<ShellyOptions>()?.channel;
+            let channels = channels(&relay, 0, channel);
+            let shellies =
+                ShellyRelay::new(relay.ip, channels, relay.room, relay.tags, relay.options)?;
+            Ok(shellies
+                .into_iter()
+                .map(|shelly| Box::new(shelly) as RelayType)
+                .collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::test_support::{http_stub, ok};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_shelly_gen2_with_digest_auth() {
+        let (ip, requests) = http_stub(Box::new(|path, authorization| {
+            if path == "/shelly" {
+                return ok(r#"{"gen": 2, "app": "Plus1"}"#);
+            }
+            match authorization {
+                Some(authorization) if authorization.starts_with("Digest username=\"admin\"") => {
+                    match path.starts_with("/rpc/Switch.Set") {
+                        true => ok(r#"{"was_on": false}"#),
+                        false => ok(r#"{"id": 1, "output": false}"#),
+                    }
+                }
+                _ => (
+                    401,
+                    "WWW-Authenticate: Digest qop=\"auth\", realm=\"shellyplus1\", nonce=\"60dc59c6\", algorithm=SHA-256\r\n".to_string(),
+                    String::new(),
+                ),
+            }
+        }));
+        let options = serde_json::from_value(json!({"password": "secret"})).unwrap();
+
+        let mut shellies = ShellyRelay::new(
+            ip,
+            vec![(1, "Porch".to_string())],
+            "outside".to_string(),
+            vec![],
+            options,
+        )
+        .unwrap();
+        assert_eq!(shellies[0].generation, 2);
+        assert!(!shellies[0].status);
+
+        shellies[0].turn_on().unwrap();
+        assert!(shellies[0].status);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .contains(&"/rpc/Switch.Set?id=1&on=true".to_string()));
+    }
+
+    #[test]
+    fn test_shelly_gen1_channels() {
+        let (ip, requests) = http_stub(Box::new(|path, _| match path {
+            "/shelly" => ok(r#"{"type": "SHSW-25", "auth": false}"#),
+            "/relay/0" => ok(r#"{"ison": false}"#),
+            "/relay/1" => ok(r#"{"ison": true}"#),
+            "/relay/0?turn=on" => ok(r#"{"ison": true}"#),
+            _ => (404, String::new(), String::new()),
+        }));
+
+        let mut shellies = ShellyRelay::new(
+            ip,
+            vec![(0, "Fan".to_string()), (1, "Vent".to_string())],
+            "bathroom".to_string(),
+            vec![],
+            RelayOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(shellies.len(), 2);
+        assert_eq!(shellies[0].generation, 1);
+        assert!(shellies[1].status);
+
+        shellies[0].switch().unwrap();
+        assert!(shellies[0].status);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .contains(&"/relay/0?turn=on".to_string()));
+    }
+
+    #[test]
+    fn test_shelly_without_credentials_is_denied() {
+        let (ip, _) = http_stub(Box::new(|_, _| {
+            (
+                401,
+                "WWW-Authenticate: Basic realm=\"shelly1\"\r\n".to_string(),
+                String::new(),
+            )
+        }));
+        let options = serde_json::from_value(json!({"generation": 1})).unwrap();
+
+        let error = ShellyRelay::new(
+            ip,
+            vec![(0, "Porch".to_string())],
+            "outside".to_string(),
+            vec![],
+            options,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+}
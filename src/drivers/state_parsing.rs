@@ -0,0 +1,246 @@
+//! What the `Http` and `Exec` drivers share: filling in their request and
+//! command templates, reading a state out of what comes back and running
+//! status reads in the background.
+
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::drivers::state_text;
+use crate::utils::config_validation::ConfigIssue;
+
+/// How long a status read waits for its request or command before it leaves
+/// it running in the background and answers with the last known status.
+const STATUS_WAIT: Duration = Duration::from_millis(500);
+
+/// Fills in the `{{key}}` placeholders of a request or command template.
+pub(crate) fn render(template: &str, variables: &[(&str, &str)]) -> String {
+    variables
+        .iter()
+        .fold(template.to_string(), |text, (key, value)| {
+            text.replace(&format!("{{{{{}}}}}", key), value)
+        })
+}
+
+/// How a state is read out of a status response or command output.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StateParsing {
+    /// JSON pointer to the state in the status response, e.g. `/relay/0/on`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_pointer: Option<String>,
+    /// Regex finding the state in the status response, the first group when it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_regex: Option<String>,
+    /// State reported when on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_on: Option<String>,
+    /// State reported when off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) state_off: Option<String>,
+}
+
+impl StateParsing {
+    /// Whether any way of reading the state is set.
+    #[cfg(feature = "exec")]
+    pub(crate) fn is_set(&self) -> bool {
+        *self != StateParsing::default()
+    }
+
+    /// Reads the state out of a status response, at `statePointer` of a JSON
+    /// response, from `stateRegex` or the whole body. The state is compared
+    /// against `stateOn` and `stateOff`; with only one of them set anything
+    /// else is the other state, with neither `on`, `true` and `1` are on and
+    /// `off`, `false` and `0` are off.
+    pub(crate) fn parse(&self, body: &str) -> Result<bool, Error> {
+        let text = if let Some(pointer) = &self.state_pointer {
+            let response: Value = serde_json::from_str(body)
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+            let state = response.pointer(pointer).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Status response has nothing at {}", pointer),
+                )
+            })?;
+            if let (Value::Bool(state), None, None) = (state, &self.state_on, &self.state_off) {
+                return Ok(*state);
+            }
+            state_text(state)
+        } else if let Some(pattern) = &self.state_regex {
+            let regex =
+                Regex::new(pattern).map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+            let captures = regex.captures(body).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Status response doesn't match {}", pattern),
+                )
+            })?;
+            captures
+                .get(1)
+                .or_else(|| captures.get(0))
+                .map(|found| found.as_str().to_string())
+                .unwrap_or_default()
+        } else {
+            body.to_string()
+        };
+
+        let text = text.trim();
+        match (self.state_on.as_deref(), self.state_off.as_deref()) {
+            (Some(state_on), _) if text == state_on => Ok(true),
+            (_, Some(state_off)) if text == state_off => Ok(false),
+            (Some(_), None) => Ok(false),
+            (None, Some(_)) => Ok(true),
+            (Some(_), Some(_)) => Err(unknown_state(text)),
+            (None, None) => match text.to_lowercase().as_str() {
+                "on" | "true" | "1" => Ok(true),
+                "off" | "false" | "0" => Ok(false),
+                _ => Err(unknown_state(text)),
+            },
+        }
+    }
+
+    /// Checks how an entry reads its state out of the response or output of
+    /// its status request or command.
+    pub(crate) fn check(
+        &self,
+        label: &str,
+        has_status: bool,
+        status_field: &str,
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        if let Some(pattern) = &self.state_regex {
+            if let Err(error) = Regex::new(pattern) {
+                issues.push(ConfigIssue::error(format!(
+                    "{} has an invalid `stateRegex`: {}",
+                    label, error
+                )));
+            }
+        }
+        if self.state_pointer.is_some() && self.state_regex.is_some() {
+            issues.push(ConfigIssue::warning(format!(
+                "{} has a `statePointer`, its `stateRegex` is ignored",
+                label
+            )));
+        }
+        if (self.state_pointer.is_some() || self.state_regex.is_some()) && !has_status {
+            issues.push(ConfigIssue::warning(format!(
+                "{} parses a state but has no `{}`",
+                label, status_field
+            )));
+        }
+    }
+}
+
+/// Status read still running from an earlier one. Reads run on their own
+/// thread, so a slow device or command can't hold up the data thread.
+#[derive(Debug, Default)]
+pub(crate) struct PendingStatus(Option<Receiver<Result<bool, Error>>>);
+
+impl PendingStatus {
+    /// Result of the read still running, or else of a new one started with
+    /// `read`. A read that takes longer than `STATUS_WAIT` keeps running in
+    /// the background, `last` is returned until the next call picks up its
+    /// result.
+    pub(crate) fn read(
+        &mut self,
+        last: bool,
+        read: impl FnOnce() -> Result<bool, Error> + Send + 'static,
+    ) -> Result<bool, Error> {
+        if let Some(pending) = self.0.take() {
+            match pending.try_recv() {
+                Ok(status) => return status,
+                Err(TryRecvError::Empty) => {
+                    self.0 = Some(pending);
+                    return Ok(last);
+                }
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(read());
+        });
+        match receiver.recv_timeout(STATUS_WAIT) {
+            Ok(status) => status,
+            Err(RecvTimeoutError::Timeout) => {
+                self.0 = Some(receiver);
+                Ok(last)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::other("Status read stopped without a result"))
+            }
+        }
+    }
+
+    /// Drops the read still running, a switch makes its result stale.
+    pub(crate) fn clear(&mut self) {
+        self.0 = None;
+    }
+}
+
+fn unknown_state(text: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Unknown relay state {:?} in the status response", text),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendering_and_parsing_states() {
+        let variables = [("ip", "10.0.0.5"), ("name", "Fan"), ("state", "on")];
+        assert_eq!(
+            render("http://{{ip}}/relay?turn={{state}}&n={{name}}", &variables),
+            "http://10.0.0.5/relay?turn=on&n=Fan"
+        );
+
+        let pointer = StateParsing {
+            state_pointer: Some("/relays/0/ison".to_string()),
+            ..StateParsing::default()
+        };
+        assert!(pointer.parse(r#"{"relays": [{"ison": true}]}"#).unwrap());
+        assert!(pointer.parse(r#"{"relays": []}"#).is_err());
+
+        let regex = StateParsing {
+            state_regex: Some(r"Relay: (\w+)".to_string()),
+            state_on: Some("Closed".to_string()),
+            ..StateParsing::default()
+        };
+        assert!(regex.parse("<p>Relay: Closed</p>").unwrap());
+        assert!(!regex.parse("<p>Relay: Open</p>").unwrap());
+
+        assert!(!StateParsing::default().parse(" OFF\n").unwrap());
+        assert!(StateParsing::default().parse("maybe").is_err());
+    }
+
+    #[test]
+    fn test_slow_status_reads_run_in_the_background() {
+        let mut pending = PendingStatus::default();
+        let started = std::time::Instant::now();
+        let slow = || {
+            thread::sleep(Duration::from_secs(1));
+            Ok(true)
+        };
+        assert!(!pending.read(false, slow).unwrap());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The slow read is still running, no other one starts
+        assert!(!pending.read(false, || Ok(true)).unwrap());
+        thread::sleep(Duration::from_millis(1500));
+        assert!(pending.read(false, || Ok(false)).unwrap());
+
+        pending.read(false, slow).unwrap();
+        pending.clear();
+        assert!(!pending.read(true, || Ok(false)).unwrap());
+    }
+}
@@ -0,0 +1,291 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{Error, ErrorKind};
+
+use crate::drivers::{
+    blocking, channels, check_channel_names, check_options, entry_names, DriverFuture, RelayDriver,
+};
+use crate::models::config_models::{ConfigRelay, RelayOptions};
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::models::tasmota_network_models::TasmotaResponse;
+use crate::utils::config_validation::ConfigIssue;
+use crate::utils::tasmota_network_functions;
+
+/// Options of a `Tasmota` entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TasmotaOptions {
+    /// POWER channel switched by a relay with a single `name`, 1 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) channel: Option<u8>,
+    /// Username, `admin` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) username: Option<String>,
+    /// Password, for devices with authentication turned on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password: Option<String>,
+}
+
+impl TasmotaOptions {
+    /// Username and password sent with each command.
+    fn credentials(self) -> Option<(String, String)> {
+        let username = self.username.unwrap_or("admin".to_string());
+        self.password.map(|password| (username, password))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TasmotaRelay {
+    pub(crate) ip: String,
+    pub(crate) channel: u8,
+    pub(crate) credentials: Option<(String, String)>,
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) options: RelayOptions,
+}
+
+impl TasmotaRelay {
+    /// Connects to the Tasmota at `ip`, one relay per POWER channel, reading
+    /// every channel's state with a single `Power0`.
+    pub fn new(
+        ip: String,
+        channels: Vec<(u8, String)>,
+        room: String,
+        tags: Vec<String>,
+        options: RelayOptions,
+    ) -> Result<Vec<TasmotaRelay>, Error> {
+        let credentials = options.parse::<TasmotaOptions>()?.credentials();
+        let mut relays: Vec<TasmotaRelay> = channels
+            .into_iter()
+            .map(|(channel, name)| TasmotaRelay {
+                ip: ip.clone(),
+                channel,
+                credentials: credentials.clone(),
+                name,
+                status: false,
+                room: room.clone(),
+                tags: tags.clone(),
+                options: options.clone(),
+            })
+            .collect();
+
+        if let Some(first) = relays.first() {
+            let response = first.send("Power0")?;
+            for relay in relays.iter_mut() {
+                relay.status = relay.state_from(&response)?;
+            }
+        }
+
+        Ok(relays)
+    }
+
+    /// Number of POWER channels of the Tasmota at `ip`.
+    pub fn channel_count(ip: &str, options: &RelayOptions) -> Result<usize, Error> {
+        let relay = TasmotaRelay {
+            ip: ip.to_string(),
+            channel: 1,
+            credentials: options.parse::<TasmotaOptions>()?.credentials(),
+            name: String::new(),
+            status: false,
+            room: String::new(),
+            tags: vec![],
+            options: options.clone(),
+        };
+        Ok(relay.send("Power0")?.power_count())
+    }
+
+    fn send(&self, command: &str) -> Result<TasmotaResponse, Error> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        tasmota_network_functions::send(&self.ip, command, credentials)
+    }
+
+    fn state_from(&self, response: &TasmotaResponse) -> Result<bool, Error> {
+        response.power(self.channel).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Tasmota {} has no POWER{}", self.ip, self.channel),
+            )
+        })
+    }
+
+    fn set(&mut self, state: &str) -> Result<Value, Error> {
+        let response = self.send(&format!("Power{} {}", self.channel, state))?;
+        self.status = self.state_from(&response)?;
+        Ok(self.to_json())
+    }
+}
+
+impl RelayActions for TasmotaRelay {
+    relay_fields!("Tasmota", ip, options);
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        self.get_status()?;
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Tasmota",
+            "ip": &self.ip,
+            "channel": self.channel,
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        let response = self.send(&format!("Power{}", self.channel))?;
+        self.status = self.state_from(&response)?;
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.set("Off")
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.set("On")
+    }
+
+    /// Sets the opposite of the last known state rather than sending
+    /// `Toggle`, so a request repeated after a lost reply can't switch twice.
+    fn switch(&mut self) -> Result<Value, Error> {
+        self.set(if self.status { "Off" } else { "On" })
+    }
+}
+
+/// Tasmota numbers its POWER channels from 1.
+pub(crate) struct TasmotaDriver;
+
+impl RelayDriver for TasmotaDriver {
+    fn type_name(&self) -> &'static str {
+        "Tasmota"
+    }
+
+    fn options_schema(&self, gen: &mut SchemaGenerator) -> Schema {
+        TasmotaOptions::json_schema(gen)
+    }
+
+    fn relay_names(&self, relay: &ConfigRelay) -> Vec<String> {
+        entry_names(relay)
+    }
+
+    fn check(
+        &self,
+        label: &str,
+        relay: &ConfigRelay,
+        _relays: &[ConfigRelay],
+        issues: &mut Vec<ConfigIssue>,
+    ) {
+        check_channel_names(label, relay, issues);
+        check_options::<TasmotaOptions>(label, relay, issues);
+    }
+
+    /// Also reports more `names` than the device has POWER channels.
+    fn probe<'a>(
+        &'a self,
+        label: &'a str,
+        relay: &'a ConfigRelay,
+        issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async move {
+            let (ip, options) = (relay.ip.clone(), relay.options.clone());
+            match blocking(move || TasmotaRelay::channel_count(&ip, &options)).await {
+                Ok(channels) if relay.names.len() > channels => {
+                    issues.push(ConfigIssue::error(format!(
+                        "{} lists {} names but the device at {} has {} POWER channels",
+                        label,
+                        relay.names.len(),
+                        relay.ip,
+                        channels
+                    )))
+                }
+                Ok(_) => {}
+                Err(error) => issues.push(ConfigIssue::warning(format!(
+                    "{} at {} is unreachable: {}",
+                    label, relay.ip, error
+                ))),
+            }
+        })
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        blocking(move || {
+            let channel = relay.options.parse::<TasmotaOptions>()?.channel;
+            let channels = channels(&relay, 1, channel);
+            let tasmotas =
+                TasmotaRelay::new(relay.ip, channels, relay.room, relay.tags, relay.options)?;
+            Ok(tasmotas
+                .into_iter()
+                .map(|tasmota| Box::new(tasmota) as RelayType)
+                .collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::test_support::{http_stub, ok};
+
+    #[test]
+    fn test_tasmota_power_channels() {
+        let (ip, requests) = http_stub(Box::new(|path, _| {
+            match path.split_once("cmnd=").map(|(_, command)| command) {
+                Some("Power0&user=admin&password=secret") => {
+                    ok(r#"{"POWER1": "OFF", "POWER2": "ON"}"#)
+                }
+                Some("Power2+Off&user=admin&password=secret") => ok(r#"{"POWER2": "OFF"}"#),
+                _ => ok(r#"{"WARNING": "Need user=<username>&password=<password>"}"#),
+            }
+        }));
+        let options = serde_json::from_value(json!({"password": "secret"})).unwrap();
+
+        let mut tasmotas = TasmotaRelay::new(
+            ip,
+            vec![(1, "Lamp".to_string()), (2, "Fan".to_string())],
+            "living".to_string(),
+            vec![],
+            options,
+        )
+        .unwrap();
+        assert!(!tasmotas[0].status);
+        assert!(tasmotas[1].status);
+
+        tasmotas[1].switch().unwrap();
+        assert!(!tasmotas[1].status);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_tasmota_single_relay_and_warning() {
+        let (ip, _) = http_stub(Box::new(|path, _| match path {
+            "/cm?cmnd=Power0" | "/cm?cmnd=Power1+On" => ok(r#"{"POWER": "ON"}"#),
+            _ => ok(r#"{"WARNING": "Need user=<username>&password=<password>"}"#),
+        }));
+
+        let mut tasmotas = TasmotaRelay::new(
+            ip,
+            vec![(1, "Heater".to_string())],
+            "office".to_string(),
+            vec![],
+            RelayOptions::default(),
+        )
+        .unwrap();
+        assert!(tasmotas[0].status);
+        tasmotas[0].turn_on().unwrap();
+
+        let error = tasmotas[0].turn_off().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,92 @@
+use serde_json::{json, Value};
+use std::io::Error;
+
+use crate::drivers::{DriverFuture, RelayDriver};
+use crate::models::config_models::ConfigRelay;
+use crate::models::relays::{relay_fields, RelayActions, RelayType};
+use crate::utils::config_validation::ConfigIssue;
+
+#[derive(Debug, PartialEq)]
+pub struct VirtualRelay {
+    pub(crate) name: String,
+    pub(crate) status: bool,
+    pub(crate) room: String,
+    pub(crate) tags: Vec<String>,
+}
+
+impl VirtualRelay {
+    pub fn new(name: String, room: String, tags: Vec<String>) -> Self {
+        VirtualRelay {
+            name,
+            status: false,
+            room,
+            tags,
+        }
+    }
+}
+
+impl RelayActions for VirtualRelay {
+    relay_fields!("Virtual");
+
+    fn connected(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "Virtual",
+            "name": &self.name,
+            "status": self.status,
+            "room": &self.room,
+            "tags": &self.tags,
+        })
+    }
+
+    fn get_status(&mut self) -> Result<bool, Error> {
+        Ok(self.status)
+    }
+
+    fn turn_off(&mut self) -> Result<Value, Error> {
+        self.status = false;
+        Ok(self.to_json())
+    }
+
+    fn turn_on(&mut self) -> Result<Value, Error> {
+        self.status = true;
+        Ok(self.to_json())
+    }
+
+    fn switch(&mut self) -> Result<Value, Error> {
+        self.status = !self.status;
+        Ok(self.to_json())
+    }
+}
+
+pub(crate) struct VirtualDriver;
+
+impl RelayDriver for VirtualDriver {
+    fn type_name(&self) -> &'static str {
+        "Virtual"
+    }
+
+    fn needs_ip(&self) -> bool {
+        false
+    }
+
+    /// Nothing to reach, the state only lives in memory.
+    fn probe<'a>(
+        &'a self,
+        _label: &'a str,
+        _relay: &'a ConfigRelay,
+        _issues: &'a mut Vec<ConfigIssue>,
+    ) -> DriverFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    fn connect(&self, relay: ConfigRelay) -> DriverFuture<'static, Result<Vec<RelayType>, Error>> {
+        Box::pin(async move {
+            let relay = VirtualRelay::new(relay.name, relay.room, relay.tags);
+            Ok(vec![Box::new(relay) as RelayType])
+        })
+    }
+}
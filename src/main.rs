@@ -1,35 +1,198 @@
+mod drivers;
 mod models;
 mod routes;
 mod utils;
 
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 use std::vec;
 
+use crate::routes::automation_routes::automations_route;
+use crate::routes::history_routes::{preset_history_route, relay_history_route};
 use crate::routes::index_routes::{index_route, refresh_route, status_route};
 use crate::routes::preset_routes::{get_preset_names_route, set_preset_route};
-use crate::routes::relay_routes::{set_relay_command_route, set_relays_by_tag_command_route};
+use crate::routes::relay_routes::{
+    set_relay_brightness_route, set_relay_color_temp_route, set_relay_command_route,
+    set_relay_hsv_route, set_relays_by_tag_brightness_route, set_relays_by_tag_command_route,
+};
+use crate::routes::schema_routes::config_schema_route;
 
 use crate::models::channels_models::Channels;
+use crate::models::config_models::{RestorePolicy, ServerSettings};
 use crate::models::data_thread_models::{DataThreadCommand, DataThreadResponse};
 use crate::models::rocket_cors::Cors;
+use crate::utils::config_transfer::{run_transfer_command, TransferMode};
+use crate::utils::config_validation::run_validate_command;
+use crate::utils::config_watcher::setup_config_watcher;
 use crate::utils::data_thread_handling::setup_data_thread;
-use crate::utils::load_config::ConfigLocation;
-use clap::Parser;
+use crate::utils::history_store::HistoryStore;
+use crate::utils::kasa_plug_network_functions;
+use crate::utils::load_config::{ConfigLocation, ConfigSettings};
+use crate::utils::local_config_utils::{config_schema, load_server_settings};
+use crate::utils::mongodb_utils::MongoSettings;
+use crate::utils::mongodb_watcher::setup_mongodb_watcher;
+use crate::utils::mqtt_bridge::setup_mqtt_bridge;
+use crate::utils::mqtt_network_functions::{self, MqttSettings};
+use crate::utils::relay_state_file::RelayStateFile;
+use clap::{Parser, Subcommand};
+use dotenv::dotenv;
 
 #[macro_use]
 extern crate rocket;
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Work with the relay config without starting the server
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Check the config for mistakes and print a report
+    Validate {
+        /// Also connect to every relay to check it answers
+        #[arg(long)]
+        probe: bool,
+    },
+    /// Print the JSON Schema of the config file format
+    Schema {
+        /// Write the schema to a file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Copy the config from a backend into a file
+    Export {
+        /// Backend to read the config from
+        #[arg(long, value_enum)]
+        from: ConfigLocation,
+
+        /// File to write, format is picked by extension
+        #[arg(long)]
+        to: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = TransferMode::Merge)]
+        mode: TransferMode,
+
+        /// Print what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy the config from a file into a backend
+    Import {
+        /// File to read, format is picked by extension
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Backend to write the config to
+        #[arg(long, value_enum)]
+        to: ConfigLocation,
+
+        #[arg(long, value_enum, default_value_t = TransferMode::Merge)]
+        mode: TransferMode,
+
+        /// Print what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+const DEFAULT_PORT: u16 = 5000;
+const DEFAULT_REFRESH_INTERVAL: u64 = 10;
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Server settings are taken from, in order of precedence: command line flags, \
+REMOTERELAY_* environment variables, the `server` section of a local config file, then the defaults."
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     config: Option<String>,
+
+    /// Config file used with `--config local`, format is picked by extension
+    /// (.json, .json5, .yaml, .yml or .toml)
+    #[arg(long, default_value = "config.json")]
+    config_file: PathBuf,
+
+    /// SQLite database used with `--config sqlite`
+    #[arg(long, default_value = "remoterelay.db")]
+    db: String,
+
+    /// SQLite file that relay and preset history is written to
+    #[arg(long, default_value = "history.db")]
+    history_db: String,
+
+    /// Days of history to keep, 0 keeps everything
+    #[arg(long, default_value_t = 30)]
+    history_retention_days: u64,
+
+    /// File the desired relay states and active preset are saved to
+    #[arg(long, default_value = "relay_state.json")]
+    state_file: String,
+
+    /// Restore policy for relays that don't set `restore` in the config
+    #[arg(long, value_enum, default_value_t = RestorePolicy::Leave)]
+    restore_policy: RestorePolicy,
+
+    #[command(flatten)]
+    mongo: MongoSettings,
+
+    #[command(flatten)]
+    server: ServerSettings,
+
+    #[command(flatten)]
+    mqtt: MqttSettings,
+
+    /// Don't watch the local config file or Mongo collections for changes
+    #[arg(long)]
+    no_watch_config: bool,
 }
 
-fn get_config_location(args: Args) -> ConfigLocation {
-    match args.config.unwrap_or("local".to_string()).as_str() {
+/// Prints the config schema, or writes it to `out`. Returns whether it succeeded.
+fn write_config_schema(out: Option<&Path>) -> bool {
+    let schema = serde_json::to_string_pretty(&config_schema()).expect("Schema is valid JSON");
+    match out {
+        Some(path) => match fs::write(path, schema) {
+            Ok(()) => true,
+            Err(error) => {
+                eprintln!("Unable to write schema to {}: {}", path.display(), error);
+                false
+            }
+        },
+        None => {
+            println!("{}", schema);
+            true
+        }
+    }
+}
+
+/// Combines the server flags with the `server` section of a local config file.
+/// Problems with the file are left for the config loader to report.
+fn resolve_server_settings(args: &Args, location: ConfigLocation) -> ServerSettings {
+    let file_settings = match location {
+        ConfigLocation::LOCAL => load_server_settings(&args.config_file).unwrap_or_default(),
+        _ => ServerSettings::default(),
+    };
+    args.server.clone().or(file_settings)
+}
+
+fn get_config_location(args: &Args) -> ConfigLocation {
+    match args.config.clone().unwrap_or("local".to_string()).as_str() {
         "local" => ConfigLocation::LOCAL,
         "mongodb" | "mongo" => ConfigLocation::MONGODB,
+        "sqlite" => ConfigLocation::SQLITE,
         _ => {
             eprintln!("No config= argument found, defaulting to local");
             ConfigLocation::LOCAL
@@ -39,12 +202,77 @@ fn get_config_location(args: Args) -> ConfigLocation {
 
 #[launch]
 async fn rocket() -> _ {
+    dotenv().ok();
     let args: Args = Args::parse();
 
-    let config_location = get_config_location(args);
+    let config_location = get_config_location(&args);
+    let config_settings = ConfigSettings {
+        location: config_location,
+        config_file: args.config_file.clone(),
+        sqlite_path: args.db.clone(),
+        mongo: args.mongo.clone(),
+        watched: Arc::new(AtomicBool::new(false)),
+    };
+    mqtt_network_functions::configure(&args.mqtt);
+
+    if let Some(Command::Config { command }) = &args.command {
+        let success = match command {
+            ConfigCommand::Validate { probe } => run_validate_command(&config_settings, *probe),
+            ConfigCommand::Schema { out } => write_config_schema(out.as_deref()),
+            ConfigCommand::Export {
+                from,
+                to,
+                mode,
+                dry_run,
+            } => run_transfer_command(
+                &ConfigSettings {
+                    location: *from,
+                    ..config_settings.clone()
+                },
+                &ConfigSettings {
+                    location: ConfigLocation::LOCAL,
+                    config_file: to.clone(),
+                    ..config_settings.clone()
+                },
+                *mode,
+                *dry_run,
+            ),
+            ConfigCommand::Import {
+                from,
+                to,
+                mode,
+                dry_run,
+            } => run_transfer_command(
+                &ConfigSettings {
+                    location: ConfigLocation::LOCAL,
+                    config_file: from.clone(),
+                    ..config_settings.clone()
+                },
+                &ConfigSettings {
+                    location: *to,
+                    ..config_settings.clone()
+                },
+                *mode,
+                *dry_run,
+            ),
+        };
+        std::process::exit(if success { 0 } else { 1 });
+    }
 
     println!("Loading config from: {config_location}");
 
+    let server = resolve_server_settings(&args, config_location);
+    kasa_plug_network_functions::configure(
+        Duration::from_millis(
+            server
+                .device_timeout
+                .unwrap_or(kasa_plug_network_functions::DEFAULT_TIMEOUT_MS),
+        ),
+        server
+            .device_retries
+            .unwrap_or(kasa_plug_network_functions::DEFAULT_RETRIES),
+    );
+
     let (route_to_data_sender, route_to_data_receiver) = mpsc::channel::<DataThreadCommand>();
     let (data_to_route_sender, data_to_route_receiver) = mpsc::channel::<DataThreadResponse>();
 
@@ -54,23 +282,88 @@ async fn rocket() -> _ {
         data_to_route_receiver: Arc::new(Mutex::new(data_to_route_receiver)),
     };
 
+    let history = match HistoryStore::open(&args.history_db, args.history_retention_days) {
+        Ok(history) => Some(history),
+        Err(error) => {
+            eprintln!(
+                "Unable to open history database {}: {}",
+                &args.history_db, error
+            );
+            None
+        }
+    };
+
+    match (config_location, args.no_watch_config) {
+        (ConfigLocation::LOCAL, false) => {
+            if let Err(error) = setup_config_watcher(
+                args.config_file.clone(),
+                route_to_data_sender.clone(),
+                &config_settings.watched,
+            ) {
+                eprintln!(
+                    "Unable to watch config file {}: {}",
+                    args.config_file.display(),
+                    error
+                );
+            }
+        }
+        (ConfigLocation::MONGODB, false) => {
+            setup_mongodb_watcher(
+                args.mongo.clone(),
+                route_to_data_sender.clone(),
+                &config_settings.watched,
+            );
+        }
+        _ => {}
+    };
+
+    let bridge = args.mqtt.bridge || args.mqtt.discovery;
+    let mqtt_bridge = match (bridge, &args.mqtt.broker) {
+        (true, Some(broker)) => {
+            match setup_mqtt_bridge(broker, &args.mqtt, route_to_data_sender.clone()) {
+                Ok(mqtt_bridge) => Some(mqtt_bridge),
+                Err(error) => {
+                    eprintln!("Unable to start MQTT bridge: {}", error);
+                    None
+                }
+            }
+        }
+        (true, None) => {
+            eprintln!("The MQTT bridge needs --mqtt-broker, not starting it");
+            None
+        }
+        (false, _) => None,
+    };
+
     let data_thread = setup_data_thread(
         data_to_route_sender,
         route_to_data_receiver,
         route_to_data_sender.clone(),
-        config_location,
+        config_settings,
+        history,
+        Some(RelayStateFile::load(&args.state_file, args.restore_policy)),
+        server
+            .refresh_interval
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL)
+            .max(1),
+        mqtt_bridge,
     );
 
+    let mut rocket_config = rocket::Config {
+        address: server.address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        port: server.port.unwrap_or(DEFAULT_PORT),
+        ..rocket::Config::default()
+    };
+    if let Some(log_level) = server.log_level {
+        rocket_config.log_level = log_level.into();
+    }
+
     let _ = data_thread.thread();
 
-    let server = rocket::build()
+    rocket::build()
         .attach(Cors)
         .manage(channels)
-        .configure(rocket::Config {
-            address: "0.0.0.0".parse().unwrap(),
-            port: 5000,
-            ..rocket::Config::default()
-        })
+        .configure(rocket_config)
         .mount(
             "/",
             routes![
@@ -80,9 +373,15 @@ async fn rocket() -> _ {
                 set_preset_route,
                 get_preset_names_route,
                 set_relay_command_route,
-                set_relays_by_tag_command_route
+                set_relays_by_tag_command_route,
+                set_relay_brightness_route,
+                set_relay_color_temp_route,
+                set_relay_hsv_route,
+                set_relays_by_tag_brightness_route,
+                automations_route,
+                relay_history_route,
+                preset_history_route,
+                config_schema_route
             ],
-        );
-
-    server
+        )
 }
@@ -14,7 +14,7 @@ pub(crate) struct ApiResponse {
 
 impl<'r> Responder<'r, 'r> for ApiResponse {
     fn respond_to(self, req: &Request) -> response::Result<'r> {
-        Response::build_from(self.value.respond_to(&req)?)
+        Response::build_from(self.value.respond_to(req)?)
             .status(self.status)
             .header(ContentType::JSON)
             .ok()
@@ -0,0 +1,142 @@
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+fn enabled_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct Automation {
+    pub(crate) name: String,
+    #[serde(default = "enabled_default")]
+    pub(crate) enabled: bool,
+    pub(crate) when: AutomationTrigger,
+    pub(crate) then: Vec<AutomationAction>,
+}
+
+/// Condition that has to become true for an automation to fire. Exactly one of
+/// `relay`, `tag` or `room` selects which relays are watched.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct AutomationTrigger {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) relay: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) room: Option<String>,
+    pub(crate) state: bool,
+    #[serde(default, rename = "for", skip_serializing_if = "Option::is_none")]
+    pub(crate) held_for: Option<AutomationDuration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) before: Option<String>,
+}
+
+/// Either a number of seconds or a string such as `"2h"`, `"90m"` or `"1h30m"`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum AutomationDuration {
+    Seconds(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum AutomationAction {
+    Relay { relay: String, set: bool },
+    Tag { tag: String, set: bool },
+    Preset { preset: String },
+    Notify { notify: String },
+}
+
+impl AutomationDuration {
+    pub fn to_duration(&self) -> Result<Duration, Error> {
+        match self {
+            AutomationDuration::Seconds(seconds) => Ok(Duration::from_secs(*seconds)),
+            AutomationDuration::Text(text) => parse_duration(text),
+        }
+    }
+}
+
+fn parse_duration(text: &str) -> Result<Duration, Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid duration: {}", text),
+        )
+    };
+
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+
+    for character in text.trim().chars() {
+        if character.is_ascii_digit() {
+            digits.push(character);
+            continue;
+        }
+
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        digits.clear();
+
+        total += match character {
+            'h' => value * 3600,
+            'm' => value * 60,
+            's' => value,
+            _ => return Err(invalid()),
+        };
+    }
+
+    if !digits.is_empty() {
+        total += digits.parse::<u64>().map_err(|_| invalid())?;
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parsing_durations() {
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+
+    #[test]
+    fn test_deserializing_automation() {
+        let automation: Automation = serde_json::from_str(
+            r#"{
+                "name": "Heater timeout",
+                "when": {"relay": "Heater", "state": true, "for": "2h"},
+                "then": [{"relay": "Heater", "set": false}, {"notify": "Heater turned off"}]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(automation.enabled);
+        assert_eq!(
+            automation.when.held_for.unwrap().to_duration().unwrap(),
+            Duration::from_secs(7200)
+        );
+        assert_eq!(
+            automation.then[0],
+            AutomationAction::Relay {
+                relay: "Heater".to_string(),
+                set: false
+            }
+        );
+        assert_eq!(
+            automation.then[1],
+            AutomationAction::Notify {
+                notify: "Heater turned off".to_string()
+            }
+        );
+    }
+}
@@ -1,34 +1,320 @@
+use crate::drivers::{self, RelayDriver};
+use crate::models::automations::Automation;
 use crate::models::presets::Preset;
 use crate::models::relays::RelayType;
 use rocket::serde::{Deserialize, Serialize};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserializer, Serializer};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
 
 fn empty_list() -> Vec<String> {
     Vec::new()
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct Config {
     pub(crate) relays: HashMap<String, RelayType>,
     pub(crate) presets: HashMap<String, Preset>,
+    pub(crate) automations: Vec<Automation>,
+    pub(crate) restore_policies: HashMap<String, RestorePolicy>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ConfigRelayType {
-    KasaPlug,
-    KasaMultiPlug,
+/// Type of a configured relay, naming the driver that makes it. A type no
+/// built-in driver has still deserializes, so that one entry is skipped and
+/// reported instead of failing the whole config.
+#[derive(Clone)]
+pub struct ConfigRelayType {
+    name: String,
+    driver: Option<&'static dyn RelayDriver>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl ConfigRelayType {
+    #[cfg(test)]
+    pub(crate) fn named(name: &str) -> Option<Self> {
+        drivers::driver(name).map(|driver| ConfigRelayType {
+            name: name.to_string(),
+            driver: Some(driver),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The driver of the type, `None` when it isn't built in.
+    pub(crate) fn driver(&self) -> Option<&'static dyn RelayDriver> {
+        self.driver
+    }
+
+    /// Explains why an entry of a type without a driver is skipped.
+    pub(crate) fn unknown_message(&self) -> String {
+        format!(
+            "has unknown type {:?}, expected one of {}",
+            self.name,
+            drivers::driver_names().join(", ")
+        )
+    }
+}
+
+impl PartialEq for ConfigRelayType {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Debug for ConfigRelayType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Display for ConfigRelayType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for ConfigRelayType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigRelayType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        let driver = drivers::driver(&name);
+        Ok(ConfigRelayType { name, driver })
+    }
+}
+
+impl JsonSchema for ConfigRelayType {
+    fn schema_name() -> String {
+        "ConfigRelayType".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        Schema::Object(SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            enum_values: Some(
+                drivers::driver_names()
+                    .into_iter()
+                    .map(Value::from)
+                    .collect(),
+            ),
+            ..SchemaObject::default()
+        })
+    }
+}
+
+/// Log level of the web server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, clap::ValueEnum, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Critical,
+    Normal,
+    Debug,
+}
+
+impl From<LogLevel> for rocket::config::LogLevel {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => rocket::config::LogLevel::Off,
+            LogLevel::Critical => rocket::config::LogLevel::Critical,
+            LogLevel::Normal => rocket::config::LogLevel::Normal,
+            LogLevel::Debug => rocket::config::LogLevel::Debug,
+        }
+    }
+}
+
+/// Server settings, given as flags, REMOTERELAY_* environment variables or the
+/// `server` section of the config file, in that order of precedence.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema, clap::Args)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerSettings {
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, env = "REMOTERELAY_ADDRESS")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) address: Option<IpAddr>,
+
+    /// Port to listen on [default: 5000]
+    #[arg(long, env = "REMOTERELAY_PORT")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) port: Option<u16>,
+
+    /// Seconds between config refreshes and relay status polls [default: 10]
+    #[arg(long, env = "REMOTERELAY_REFRESH_INTERVAL")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) refresh_interval: Option<u64>,
+
+    /// Milliseconds to wait for a device to answer [default: 300]
+    #[arg(long, env = "REMOTERELAY_DEVICE_TIMEOUT")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) device_timeout: Option<u64>,
+
+    /// Times a failed device request is retried [default: 0]
+    #[arg(long, env = "REMOTERELAY_DEVICE_RETRIES")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) device_retries: Option<u32>,
+
+    /// Web server log level [default: normal in debug builds, critical in release builds]
+    #[arg(long, value_enum, env = "REMOTERELAY_LOG_LEVEL")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) log_level: Option<LogLevel>,
+}
+
+impl ServerSettings {
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == ServerSettings::default()
+    }
+
+    /// Fills in every setting that isn't set here from `fallback`.
+    pub(crate) fn or(self, fallback: ServerSettings) -> ServerSettings {
+        ServerSettings {
+            address: self.address.or(fallback.address),
+            port: self.port.or(fallback.port),
+            refresh_interval: self.refresh_interval.or(fallback.refresh_interval),
+            device_timeout: self.device_timeout.or(fallback.device_timeout),
+            device_retries: self.device_retries.or(fallback.device_retries),
+            log_level: self.log_level.or(fallback.log_level),
+        }
+    }
+}
+
+/// What to do with a relay once the registry is loaded after a restart.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, clap::ValueEnum, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum RestorePolicy {
+    Restore,
+    Leave,
+    ForceOff,
+    ForceOn,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub(crate) struct ConfigRelay {
+    /// Type of relay, case-sensitive
     #[serde(rename = "type")]
     pub(crate) relay_type: ConfigRelayType,
-    #[serde(default)]
+    /// Name of a single relay
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub(crate) name: String,
-    #[serde(default = "empty_list")]
+    /// Names of a multi-plug's outlets, in outlet order
+    #[serde(default = "empty_list", skip_serializing_if = "Vec::is_empty")]
     pub(crate) names: Vec<String>,
+    /// Address of the device on the local network, not used by MQTT, GPIO,
+    /// Group or Virtual relays
+    #[serde(default)]
     pub(crate) ip: String,
     pub(crate) room: String,
     #[serde(default = "empty_list")]
     pub(crate) tags: Vec<String>,
+    /// What to do with the relay after a restart, overrides `--restore-policy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) restore: Option<RestorePolicy>,
+    #[serde(flatten)]
+    pub(crate) options: RelayOptions,
+}
+
+/// Everything a config entry sets besides the fields every entry has. Each
+/// driver reads its own options out of them, see `RelayOptions::parse`, so the
+/// options of a driver left out of the build don't exist here either.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+#[serde(transparent)]
+pub(crate) struct RelayOptions(pub(crate) Map<String, Value>);
+
+/// Mongo documents carry an `_id` that isn't an option of any driver, so it's
+/// left out rather than warned about, exported and compared on a reload.
+impl<'de> Deserialize<'de> for RelayOptions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut options = Map::deserialize(deserializer)?;
+        options.remove("_id");
+        Ok(RelayOptions(options))
+    }
+}
+
+impl RelayOptions {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Names of the options that are set.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+
+    /// Reads the options of a driver, failing when one has the wrong type.
+    /// Options the driver doesn't know are left alone.
+    pub(crate) fn parse<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        serde_json::from_value(Value::Object(self.0.clone())).map_err(|error| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid options: {}", error),
+            )
+        })
+    }
+}
+
+/// Any options are allowed here, the config schema limits each `type` to the
+/// options of its driver.
+impl JsonSchema for RelayOptions {
+    fn schema_name() -> String {
+        "RelayOptions".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        Schema::Object(SchemaObject {
+            instance_type: Some(InstanceType::Object.into()),
+            ..SchemaObject::default()
+        })
+    }
+}
+
+impl ConfigRelay {
+    /// Names of the relays the entry makes. An entry of an unknown type
+    /// names them the way most drivers do, so it can still be reported.
+    pub(crate) fn relay_names(&self) -> Vec<String> {
+        match self.relay_type.driver() {
+            Some(driver) => driver.relay_names(self),
+            None if !self.names.is_empty() => self.names.clone(),
+            None => vec![self.name.clone()],
+        }
+    }
+
+    /// The driver of the entry's type, an error naming the entry when it
+    /// isn't built in.
+    pub(crate) fn driver(&self) -> Result<&'static dyn RelayDriver, Error> {
+        self.relay_type.driver().ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "Relay {} {}",
+                    self.relay_names().join(", "),
+                    self.relay_type.unknown_message()
+                ),
+            )
+        })
+    }
+}
+
+/// Restore policies of the relays that set one explicitly.
+pub(crate) fn restore_policies(relays: &[ConfigRelay]) -> HashMap<String, RestorePolicy> {
+    let mut policies: HashMap<String, RestorePolicy> = HashMap::new();
+    for relay in relays {
+        if let Some(policy) = relay.restore {
+            for name in relay.relay_names() {
+                policies.insert(name, policy);
+            }
+        }
+    }
+    policies
 }
@@ -1,5 +1,8 @@
+use crate::models::relays::LightLevels;
+use crate::utils::local_config_utils::LoadedConfig;
 use rocket::serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::net::IpAddr;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum DataThreadResponse {
@@ -16,33 +19,118 @@ pub(crate) enum DataThreadCommand {
     Relay(RelayCommand),
     Tag(TagCommand),
     Preset(PresetCommand),
+    Automations,
+    History(HistoryCommand),
+    ConfigReload(LoadedConfig),
+    /// Commands received by the MQTT bridge, which don't get a response
+    Mqtt(MqttCommand),
+    /// A state message arrived for MQTT relays, which don't get a response
+    MqttState,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) enum MqttCommand {
+    Relay(String, RelayCommands),
+    Tag(String, RelayCommands),
+    Preset(String),
+}
+
+impl MqttCommand {
+    /// The route command doing the same thing.
+    pub(crate) fn into_command(self) -> DataThreadCommand {
+        match self {
+            MqttCommand::Relay(name, command) => DataThreadCommand::Relay(RelayCommand {
+                name,
+                command,
+                client_ip: None,
+            }),
+            MqttCommand::Tag(tag, command) => DataThreadCommand::Tag(TagCommand {
+                tag,
+                command,
+                client_ip: None,
+            }),
+            MqttCommand::Preset(name) => DataThreadCommand::Preset(PresetCommand::Set(name, None)),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct TagCommand {
     pub(crate) tag: String,
     pub(crate) command: RelayCommands,
+    pub(crate) client_ip: Option<IpAddr>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) struct RelayCommand {
     pub(crate) name: String,
     pub(crate) command: RelayCommands,
+    pub(crate) client_ip: Option<IpAddr>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub(crate) enum PresetCommand {
-    Set(String),
+    Set(String, Option<IpAddr>),
     Names,
     // CurrentPreset,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub(crate) enum RelayCommands {
     #[serde(rename = "true")]
     TRUE,
     #[serde(rename = "false")]
     FALSE,
     SWITCH,
-    STATUS
+    STATUS,
+    /// Brightness and colour of dimmers and bulbs
+    LEVELS(LightLevels),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub(crate) struct RelayStateChange {
+    pub(crate) name: String,
+    pub(crate) previous: Option<bool>,
+    pub(crate) current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum ChangeSource {
+    Api,
+    Preset,
+    Automation,
+    Poller,
+    ExternalDrift,
+    Restore,
+    Mqtt,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) enum HistoryCommand {
+    Relays(HistoryQuery),
+    Presets(HistoryQuery),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub(crate) struct HistoryQuery {
+    pub(crate) relay: Option<String>,
+    pub(crate) since: Option<i64>,
+    pub(crate) until: Option<i64>,
+    pub(crate) limit: Option<u32>,
+}
+
+impl std::fmt::Display for ChangeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let source = match *self {
+            ChangeSource::Api => "api",
+            ChangeSource::Preset => "preset",
+            ChangeSource::Automation => "automation",
+            ChangeSource::Poller => "poller",
+            ChangeSource::ExternalDrift => "external_drift",
+            ChangeSource::Restore => "restore",
+            ChangeSource::Mqtt => "mqtt",
+        };
+        write!(f, "{}", source)
+    }
 }
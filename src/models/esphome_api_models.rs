@@ -0,0 +1,94 @@
+//! Messages of the ESPHome native API used to drive switches, from the
+//! device's `api.proto`. Fields that aren't used are left out, protobuf skips
+//! them when decoding.
+
+pub const HELLO_REQUEST: u16 = 1;
+pub const HELLO_RESPONSE: u16 = 2;
+pub const CONNECT_REQUEST: u16 = 3;
+pub const CONNECT_RESPONSE: u16 = 4;
+pub const DISCONNECT_REQUEST: u16 = 5;
+pub const DISCONNECT_RESPONSE: u16 = 6;
+pub const PING_REQUEST: u16 = 7;
+pub const PING_RESPONSE: u16 = 8;
+pub const LIST_ENTITIES_REQUEST: u16 = 11;
+pub const LIST_ENTITIES_SWITCH_RESPONSE: u16 = 17;
+pub const LIST_ENTITIES_DONE_RESPONSE: u16 = 19;
+pub const SUBSCRIBE_STATES_REQUEST: u16 = 20;
+pub const SWITCH_STATE_RESPONSE: u16 = 26;
+pub const SWITCH_COMMAND_REQUEST: u16 = 33;
+pub const GET_TIME_REQUEST: u16 = 36;
+pub const GET_TIME_RESPONSE: u16 = 37;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HelloRequest {
+    #[prost(string, tag = "1")]
+    pub client_info: String,
+    #[prost(uint32, tag = "2")]
+    pub api_version_major: u32,
+    #[prost(uint32, tag = "3")]
+    pub api_version_minor: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HelloResponse {
+    #[prost(uint32, tag = "1")]
+    pub api_version_major: u32,
+    #[prost(uint32, tag = "2")]
+    pub api_version_minor: u32,
+    #[prost(string, tag = "3")]
+    pub server_info: String,
+    #[prost(string, tag = "4")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectRequest {
+    #[prost(string, tag = "1")]
+    pub password: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ConnectResponse {
+    #[prost(bool, tag = "1")]
+    pub invalid_password: bool,
+}
+
+/// Request without fields, e.g. `PingRequest` or `ListEntitiesRequest`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListEntitiesSwitchResponse {
+    #[prost(string, tag = "1")]
+    pub object_id: String,
+    #[prost(fixed32, tag = "2")]
+    pub key: u32,
+    #[prost(string, tag = "3")]
+    pub name: String,
+    #[prost(string, tag = "4")]
+    pub unique_id: String,
+    #[prost(bool, tag = "6")]
+    pub assumed_state: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SwitchStateResponse {
+    #[prost(fixed32, tag = "1")]
+    pub key: u32,
+    #[prost(bool, tag = "2")]
+    pub state: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SwitchCommandRequest {
+    #[prost(fixed32, tag = "1")]
+    pub key: u32,
+    #[prost(bool, tag = "2")]
+    pub state: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetTimeResponse {
+    #[prost(fixed32, tag = "1")]
+    pub epoch_seconds: u32,
+}
@@ -102,3 +102,111 @@ pub_struct!(PlugMutateSystem {
 pub_struct!(ErrCode {
     err_code: i32,
 });
+
+// `get_sysinfo` of a dimmer, which reports its brightness next to the relay
+// state.
+pub_struct!(DimmerSystemInfo {
+    relay_state: i32,
+    brightness: u8,
+});
+
+pub_struct!(DimmerGetSystemInfo {
+    get_sysinfo: DimmerSystemInfo,
+});
+
+pub_struct!(DimmerStatus {
+    system: DimmerGetSystemInfo,
+});
+
+/// State of a bulb or light strip. A light that is off reports the levels it
+/// turns back on with in `dft_on_state`, without an `on_off`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LightState {
+    #[serde(default)]
+    pub on_off: i32,
+    pub brightness: Option<u8>,
+    pub color_temp: Option<u16>,
+    pub hue: Option<u16>,
+    pub saturation: Option<u8>,
+    pub dft_on_state: Option<Box<LightState>>,
+    #[serde(default)]
+    pub err_code: i32,
+}
+
+/// `get_sysinfo` of a bulb. Light strips also report their `length`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BulbSystemInfo {
+    pub light_state: LightState,
+    #[serde(default)]
+    pub is_dimmable: i32,
+    #[serde(default)]
+    pub is_color: i32,
+    #[serde(default)]
+    pub is_variable_color_temp: i32,
+    pub length: Option<u32>,
+}
+
+pub_struct!(BulbGetSystemInfo {
+    get_sysinfo: BulbSystemInfo,
+});
+
+pub_struct!(BulbStatus {
+    system: BulbGetSystemInfo,
+});
+
+/// Answer to `get_sysinfo` sent together with `emeter.get_realtime`. Plugs
+/// without an energy meter answer the emeter part with an error instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlugStatusWithEmeter {
+    pub system: PlugGetSystemInfo,
+    #[serde(default)]
+    pub emeter: Option<EmeterGetRealtime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmeterGetRealtime {
+    #[serde(default)]
+    pub get_realtime: Option<EmeterRealtime>,
+}
+
+/// Realtime energy readings. Older hardware reports W, V, A and kWh, newer
+/// hardware mW, mV, mA and Wh.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmeterRealtime {
+    pub power: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+    pub total: Option<f64>,
+    pub power_mw: Option<f64>,
+    pub voltage_mv: Option<f64>,
+    pub current_ma: Option<f64>,
+    pub total_wh: Option<f64>,
+    #[serde(default)]
+    pub err_code: i32,
+}
+
+/// Energy meter reading in W, V, A and kWh.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmeterReading {
+    pub power: f64,
+    pub voltage: f64,
+    pub current: f64,
+    pub total: f64,
+}
+
+impl EmeterRealtime {
+    pub fn reading(&self) -> Option<EmeterReading> {
+        if self.err_code != 0 {
+            return None;
+        }
+        let value =
+            |value: Option<f64>, milli: Option<f64>| value.or(milli.map(|milli| milli / 1000.0));
+
+        Some(EmeterReading {
+            power: value(self.power, self.power_mw)?,
+            voltage: value(self.voltage, self.voltage_mv)?,
+            current: value(self.current, self.current_ma)?,
+            total: value(self.total, self.total_wh)?,
+        })
+    }
+}
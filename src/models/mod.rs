@@ -1,9 +1,16 @@
 pub mod relays;
 
 pub mod api_response;
+pub mod automations;
 pub mod channels_models;
 pub mod config_models;
 pub mod data_thread_models;
+#[cfg(feature = "esphome")]
+pub mod esphome_api_models;
 pub mod kasa_network_models;
 pub mod presets;
 pub mod rocket_cors;
+#[cfg(feature = "shelly")]
+pub mod shelly_network_models;
+#[cfg(feature = "tasmota")]
+pub mod tasmota_network_models;
@@ -1,43 +1,88 @@
-use crate::models::relays::{RelayActions, RelayType};
+use crate::models::relays::{
+    expand_groups, is_group, set_relay, set_relay_levels, LightLevels, RelayType,
+};
 use rocket::log;
 use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::io::Error;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct Preset {
     pub(crate) name: String,
     pub(crate) enabled: bool,
     pub(crate) relays: HashMap<String, bool>,
+    /// Brightness and colour of dimmers and bulbs the preset turns on
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) levels: HashMap<String, LightLevels>,
 }
 
 pub(crate) fn set_preset(
     preset: &Preset,
     relays: &mut HashMap<String, RelayType>,
 ) -> Result<Value, Error> {
-    for (relay_name, relay) in relays.iter_mut() {
-        let result = match preset.relays.get_key_value(relay_name) {
-            Some((_, &value)) => {
-                if value {
-                    relay.turn_on()
-                } else {
-                    relay.turn_off()
+    // Relays left out of a preset are off, unless a group in it sets them,
+    // and relays named in the preset override their groups
+    let mut targets: HashMap<String, bool> = relays
+        .iter()
+        .filter(|(_, relay)| !is_group(relay))
+        .map(|(name, _)| (name.clone(), false))
+        .collect();
+    for (name, &value) in &preset.relays {
+        if relays.get(name).is_some_and(is_group) {
+            for member in expand_groups(relays, std::slice::from_ref(name)) {
+                targets.insert(member, value);
+            }
+        }
+    }
+    for (name, &value) in &preset.relays {
+        if targets.contains_key(name) {
+            targets.insert(name.clone(), value);
+        }
+    }
+
+    // Levels of a group go to its dimmable members, levels set on a member
+    // override its group's
+    let mut target_levels: HashMap<String, LightLevels> = HashMap::new();
+    for (name, levels) in &preset.levels {
+        if relays.get(name).is_some_and(is_group) && preset.relays.get(name) == Some(&true) {
+            for member in expand_groups(relays, std::slice::from_ref(name)) {
+                if relays[&member].levels().is_some() {
+                    target_levels.insert(member, *levels);
                 }
             }
-            None => relay.turn_off(),
+        }
+    }
+    for (name, levels) in &preset.levels {
+        if targets.contains_key(name) {
+            target_levels.insert(name.clone(), *levels);
+        }
+    }
+
+    // Dimmers and bulbs are switched on with their levels, and a relay that
+    // fails doesn't stop the rest of the preset
+    let mut failed: Vec<String> = Vec::new();
+    for (relay_name, value) in targets {
+        let result = match (value, target_levels.get(&relay_name)) {
+            (true, Some(levels)) => set_relay_levels(relays, &relay_name, *levels),
+            _ => set_relay(relays, &relay_name, Some(value)),
         };
-        if result.is_err() {
-            log::warn_!("Failed to set relay turn on: {}", relay_name);
-            return Ok(json!({"presetSet": false}));
+        if let Err(error) = result {
+            log::warn_!("Failed to set relay {}: {}", relay_name, error);
+            failed.push(relay_name);
         }
     }
 
-    Ok(json!({"presetSet": true}))
+    if failed.is_empty() {
+        return Ok(json!({"presetSet": true}));
+    }
+    failed.sort();
+    Ok(json!({"presetSet": false, "failedRelays": failed}))
 }
 
 pub(crate) fn get_preset_names(presets: &HashMap<String, Preset>) -> Result<Vec<Value>, Error> {
     let mut keys: Vec<String> = presets.keys().map(|key| key.clone().to_string()).collect();
     keys.sort();
-    Ok(keys.into_iter().map(|s| Value::from(s)).collect())
+    Ok(keys.into_iter().map(Value::from).collect())
 }
@@ -1,41 +1,63 @@
-use serde_json::Value;
-use std::collections::HashMap;
+use rocket::serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-
-use rocket::serde::Deserialize;
-use serde::Serialize;
-use serde_json::json;
 use std::io::{Error, ErrorKind};
 
-use crate::models::kasa_network_models::{MultiPlugStatus, PlugMutateResponse, PlugStatus};
-use crate::utils::kasa_plug_network_functions;
-
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub enum RelayType {
-    KasaPlug(KasaPlug),
-    KasaMultiPlug(KasaMultiPlug),
+use crate::models::config_models::RelayOptions;
+use crate::models::kasa_network_models::EmeterReading;
+
+/// A connected relay, made by the driver of its config `type`.
+pub type RelayType = Box<dyn RelayActions>;
+
+/// Brightness and colour of a dimmable relay. Fields left out are left as
+/// they are when set.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LightLevels {
+    /// Brightness in percent, 0 to 100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) brightness: Option<u8>,
+    /// Colour temperature in kelvin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) color_temp: Option<u16>,
+    /// Hue in degrees, 0 to 360
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) hue: Option<u16>,
+    /// Saturation in percent, 0 to 100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) saturation: Option<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct KasaPlug {
-    pub(crate) ip: String,
-    pub(crate) name: String,
-    pub(crate) status: bool,
-    pub(crate) room: String,
-    pub(crate) tags: Vec<String>,
-}
+impl LightLevels {
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        let out_of_range = [
+            ("brightness", self.brightness.map(u16::from), 100),
+            ("hue", self.hue, 360),
+            ("saturation", self.saturation.map(u16::from), 100),
+        ]
+        .into_iter()
+        .find(|(_, value, max)| value.is_some_and(|value| value > *max));
+
+        match out_of_range {
+            Some((field, Some(value), max)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} {} is out of range, it goes from 0 to {}",
+                    field, value, max
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct KasaMultiPlug {
-    pub(crate) ip: String,
-    pub(crate) id: String,
-    pub(crate) name: String,
-    pub(crate) status: bool,
-    pub(crate) room: String,
-    pub(crate) tags: Vec<String>,
+    pub(crate) fn has_color(&self) -> bool {
+        self.hue.is_some() || self.saturation.is_some()
+    }
 }
 
-pub trait RelayActions<'a>: Debug + Deserialize<'a> + Serialize {
+pub trait RelayActions: Debug + Send {
     fn connected(&mut self) -> Result<bool, Error>;
 
     fn to_json(&self) -> Value;
@@ -47,298 +69,377 @@ pub trait RelayActions<'a>: Debug + Deserialize<'a> + Serialize {
     fn turn_on(&mut self) -> Result<Value, Error>;
 
     fn switch(&mut self) -> Result<Value, Error>;
+
+    /// Config `type` of the driver that made the relay.
+    fn driver(&self) -> &'static str;
+
+    fn name(&self) -> &String;
+
+    fn status(&self) -> bool;
+
+    /// Address of the device, `None` for relays that don't have one.
+    fn ip(&self) -> Option<&String> {
+        None
+    }
+
+    fn room(&self) -> &String;
+
+    fn tags(&self) -> &Vec<String>;
+
+    /// Moves the relay to another room and tags, which a config reload
+    /// changes without reconnecting the device.
+    fn set_placement(&mut self, room: String, tags: Vec<String>);
+
+    /// Options as configured, compared on a config reload. `None` for relays
+    /// without any.
+    fn options(&self) -> Option<&RelayOptions> {
+        None
+    }
+
+    /// Relays switched together through this one, `None` unless it's a
+    /// group. Groups are switched through their members rather than directly.
+    fn members(&self) -> Option<&Vec<String>> {
+        None
+    }
+
+    /// Takes a group's status from the statuses of its connected members.
+    fn follow_members(&mut self, _statuses: &[bool]) {}
+
+    /// Last energy meter reading, `None` for relays without a meter.
+    fn emeter(&self) -> Option<&EmeterReading> {
+        None
+    }
+
+    /// Brightness and colour, `None` for relays that are only on or off.
+    fn levels(&self) -> Option<LightLevels> {
+        None
+    }
+
+    /// Turns a dimmable relay on at the given brightness and colour.
+    fn set_levels(&mut self, _levels: LightLevels) -> Result<Value, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("{} has no brightness or colour to set", self.name()),
+        ))
+    }
+
+    /// Whether the device answered the last status read.
+    fn reachable(&self) -> bool {
+        true
+    }
+
+    /// Flags the relay as not answering, for a relay kept while its changed
+    /// config entry can't be reconnected.
+    fn mark_unreachable(&mut self) {}
+
+    /// Frees what the relay holds on to once a reload removes it.
+    fn release(&mut self) {}
 }
 
-impl KasaPlug {
-    pub fn new(ip: String, name: String, room: String, tags: Vec<String>) -> Self {
-        KasaPlug {
-            ip,
-            name,
-            status: false,
-            tags,
-            room,
-        }
+/// A relay as kept in the registry, remembering whether its device answered
+/// the last status read. A relay that stops answering keeps its place and
+/// last known status, flagged `"reachable": false`, until it answers again.
+#[derive(Debug)]
+pub(crate) struct TrackedRelay {
+    relay: RelayType,
+    reachable: bool,
+}
+
+impl TrackedRelay {
+    pub(crate) fn track(relay: RelayType) -> RelayType {
+        Box::new(TrackedRelay {
+            relay,
+            reachable: true,
+        })
+    }
+
+    /// Runs a switch on the relay, a success also shows the device is back.
+    fn switched(
+        &mut self,
+        switch: impl FnOnce(&mut RelayType) -> Result<Value, Error>,
+    ) -> Result<Value, Error> {
+        switch(&mut self.relay)?;
+        self.reachable = true;
+        Ok(self.to_json())
     }
 }
 
-impl RelayActions<'_> for KasaPlug {
+/// Forwards every method, provided ones included, to the relay.
+impl RelayActions for TrackedRelay {
     fn connected(&mut self) -> Result<bool, Error> {
-        self.get_status()
+        let connected = self.relay.connected();
+        self.reachable = connected.is_ok();
+        connected
     }
 
     fn to_json(&self) -> Value {
-        json!({
-            "type": "Kasa Plug",
-            "ip": &self.ip,
-            "name": &self.name,
-            "status": &self.status,
-            "room": &self.room,
-            "tags": &self.tags,
-        })
+        let mut json = self.relay.to_json();
+        json["reachable"] = json!(self.reachable);
+        json
     }
 
     fn get_status(&mut self) -> Result<bool, Error> {
-        let cmd = json!({"system": {"get_sysinfo": {}}});
-        let response = kasa_plug_network_functions::send::<PlugStatus>(&self.ip, &cmd.to_string())?;
-        let relay_state = response.system.get_sysinfo.relay_state == 1;
-        self.status = relay_state;
-        Ok(relay_state)
+        let status = self.relay.get_status();
+        self.reachable = status.is_ok();
+        status
     }
 
     fn turn_off(&mut self) -> Result<Value, Error> {
-        let cmd = json!({"system": {"set_relay_state": {"state": 0}}});
-
-        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
-            Ok(..) => {
-                self.status = false;
-                Ok(self.to_json())
-            }
-            Err(..) => Err(Error::new(
-                ErrorKind::ConnectionRefused,
-                "Can't Connect To Plug".to_string(),
-            )),
-        }
+        self.switched(|relay| relay.turn_off())
     }
 
     fn turn_on(&mut self) -> Result<Value, Error> {
-        let cmd = json!({"system": {"set_relay_state": {"state": 1}}});
-        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
-            Ok(..) => {
-                self.status = true;
-                Ok(self.to_json())
-            }
-            Err(..) => Err(Error::new(
-                ErrorKind::ConnectionRefused,
-                "Can't Connect To Plug".to_string(),
-            )),
-        }
+        self.switched(|relay| relay.turn_on())
     }
 
     fn switch(&mut self) -> Result<Value, Error> {
-        match self.status {
-            true => self.turn_off(),
-            false => self.turn_on(),
-        }
+        self.switched(|relay| relay.switch())
     }
-}
 
-impl KasaMultiPlug {
-    pub fn new(
-        ip: String,
-        names: Vec<String>,
-        room: String,
-        tags: Vec<String>,
-    ) -> Result<Vec<KasaMultiPlug>, Error> {
-        let command = json!({"system": {"get_sysinfo": {}}});
-        let response =
-            match kasa_plug_network_functions::send::<MultiPlugStatus>(&ip, &command.to_string()) {
-                Ok(response) => response,
-                Err(..) => {
-                    return Err(Error::new(
-                        ErrorKind::NotConnected,
-                        format!("Unable to connect to KasaMultiPlug {}", ip),
-                    ))
-                }
-            };
-
-        let mut multi_plug_children: Vec<KasaMultiPlug> = Vec::new();
-
-        for (child, name) in response
-            .system
-            .get_sysinfo
-            .children
-            .iter()
-            .zip(names.iter())
-        {
-            multi_plug_children.push(KasaMultiPlug {
-                ip: ip.clone(),
-                id: child.id.to_string(),
-                name: name.clone(),
-                status: child.state == 1,
-                room: room.clone(),
-                tags: tags.clone(),
-            })
-        }
+    fn driver(&self) -> &'static str {
+        self.relay.driver()
+    }
 
-        Ok(multi_plug_children)
+    fn name(&self) -> &String {
+        self.relay.name()
     }
-}
 
-impl RelayActions<'_> for KasaMultiPlug {
-    fn connected(&mut self) -> Result<bool, Error> {
-        let command = json!({"system": {"get_sysinfo": {}}});
-        let _ =
-            kasa_plug_network_functions::send::<MultiPlugStatus>(&self.ip, &command.to_string())?;
-        Ok(true)
+    fn status(&self) -> bool {
+        self.relay.status()
     }
 
-    fn to_json(&self) -> Value {
-        json!({
-            "type": "Kasa Plug",
-            "ip": &self.ip,
-            "id": &self.id,
-            "name": &self.name,
-            "status": self.status,
-            "room": &self.room,
-            "tags": &self.tags,
-        })
+    fn ip(&self) -> Option<&String> {
+        self.relay.ip()
     }
 
-    fn get_status(&mut self) -> Result<bool, Error> {
-        let cmd = json!({"system": {"get_sysinfo": {}}});
-        let result =
-            kasa_plug_network_functions::send::<MultiPlugStatus>(&self.ip, &cmd.to_string())?;
-
-        for child in result.system.get_sysinfo.children {
-            if child.id == self.id {
-                let relay_state = child.state == 1;
-                self.status = relay_state;
-                return Ok(relay_state);
-            }
-        }
+    fn room(&self) -> &String {
+        self.relay.room()
+    }
 
-        Err(Error::new(
-            ErrorKind::NotFound,
-            "Can't Connect to To Plug".to_string(),
-        ))
+    fn tags(&self) -> &Vec<String> {
+        self.relay.tags()
     }
 
-    fn turn_off(&mut self) -> Result<Value, Error> {
-        let cmd = json!({"context": {"child_ids": [self.id.clone()]}, "system": {"set_relay_state": {"state": 0}}});
-        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
-            Ok(..) => {
-                self.status = false;
-                Ok(self.to_json())
-            }
-            Err(..) => Err(Error::new(
-                ErrorKind::ConnectionRefused,
-                "Can't Connect To Plug".to_string(),
-            )),
-        }
+    fn set_placement(&mut self, room: String, tags: Vec<String>) {
+        self.relay.set_placement(room, tags)
     }
 
-    fn turn_on(&mut self) -> Result<Value, Error> {
-        let cmd = json!({"context": {"child_ids": [self.id.clone()]}, "system": {"set_relay_state": {"state": 1}}});
-        match kasa_plug_network_functions::send::<PlugMutateResponse>(&self.ip, &cmd.to_string()) {
-            Ok(..) => {
-                self.status = true;
-                Ok(self.to_json())
-            }
-            Err(..) => Err(Error::new(
-                ErrorKind::ConnectionRefused,
-                "Can't Connect To Plug".to_string(),
-            )),
-        }
+    fn options(&self) -> Option<&RelayOptions> {
+        self.relay.options()
     }
 
-    fn switch(&mut self) -> Result<Value, Error> {
-        match self.status {
-            true => self.turn_off(),
-            false => self.turn_on(),
-        }
+    fn members(&self) -> Option<&Vec<String>> {
+        self.relay.members()
+    }
+
+    fn follow_members(&mut self, statuses: &[bool]) {
+        self.relay.follow_members(statuses)
+    }
+
+    fn emeter(&self) -> Option<&EmeterReading> {
+        self.relay.emeter()
+    }
+
+    fn levels(&self) -> Option<LightLevels> {
+        self.relay.levels()
+    }
+
+    fn set_levels(&mut self, levels: LightLevels) -> Result<Value, Error> {
+        self.switched(|relay| relay.set_levels(levels))
+    }
+
+    fn reachable(&self) -> bool {
+        self.reachable
+    }
+
+    fn mark_unreachable(&mut self) {
+        self.reachable = false;
+    }
+
+    fn release(&mut self) {
+        self.relay.release()
     }
 }
 
-impl RelayActions<'_> for RelayType {
-    fn connected(&mut self) -> Result<bool, Error> {
-        match self {
-            RelayType::KasaPlug(relay_plug) => relay_plug.connected(),
-            RelayType::KasaMultiPlug(relay_plug) => relay_plug.connected(),
+/// Implements the `RelayActions` accessors of a relay struct with `name`,
+/// `status`, `room` and `tags` fields. `ip` and `options` after the driver
+/// name also implement the accessors of those fields, for relays that have
+/// them.
+macro_rules! relay_fields {
+    ($driver:literal $(, $field:ident)*) => {
+        fn driver(&self) -> &'static str {
+            $driver
         }
-    }
 
-    fn to_json(&self) -> Value {
-        match self {
-            RelayType::KasaPlug(relay_plug) => relay_plug.to_json(),
-            RelayType::KasaMultiPlug(relay_plug) => relay_plug.to_json(),
+        fn name(&self) -> &String {
+            &self.name
         }
-    }
 
-    fn get_status(&mut self) -> Result<bool, Error> {
-        match self {
-            RelayType::KasaPlug(relay_plug) => relay_plug.get_status(),
-            RelayType::KasaMultiPlug(relay_plug) => relay_plug.get_status(),
+        fn status(&self) -> bool {
+            self.status
         }
-    }
 
-    fn turn_off(&mut self) -> Result<Value, Error> {
-        match self {
-            RelayType::KasaPlug(relay_plug) => relay_plug.turn_off(),
-            RelayType::KasaMultiPlug(relay_plug) => relay_plug.turn_off(),
+        fn room(&self) -> &String {
+            &self.room
         }
-    }
 
-    fn turn_on(&mut self) -> Result<Value, Error> {
-        match self {
-            RelayType::KasaPlug(relay_plug) => relay_plug.turn_on(),
-            RelayType::KasaMultiPlug(relay_plug) => relay_plug.turn_on(),
+        fn tags(&self) -> &Vec<String> {
+            &self.tags
         }
-    }
 
-    fn switch(&mut self) -> Result<Value, Error> {
-        match self {
-            RelayType::KasaPlug(relay_plug) => relay_plug.switch(),
-            RelayType::KasaMultiPlug(relay_plug) => relay_plug.switch(),
+        fn set_placement(&mut self, room: String, tags: Vec<String>) {
+            self.room = room;
+            self.tags = tags;
         }
-    }
+
+        $(crate::models::relays::relay_field!($field);)*
+    };
 }
 
-pub fn config_equals<T>(map1: &HashMap<String, T>, map2: &HashMap<String, T>) -> bool
-where
-    T: PartialEq,
-{
-    if map1.len() != map2.len() {
-        return false;
+macro_rules! relay_field {
+    (ip) => {
+        fn ip(&self) -> Option<&String> {
+            Some(&self.ip)
+        }
+    };
+    (options) => {
+        fn options(&self) -> Option<&crate::models::config_models::RelayOptions> {
+            Some(&self.options)
+        }
+    };
+}
+pub(crate) use relay_field;
+pub(crate) use relay_fields;
+
+pub(crate) fn is_group(relay: &RelayType) -> bool {
+    relay.members().is_some()
+}
+
+/// Takes every group's status from its connected members.
+pub(crate) fn update_groups(relays: &mut HashMap<String, RelayType>) {
+    let statuses: HashMap<String, bool> = relays
+        .iter()
+        .filter(|(_, relay)| !is_group(relay))
+        .map(|(name, relay)| (name.clone(), relay.status()))
+        .collect();
+
+    for relay in relays.values_mut() {
+        let member_statuses: Option<Vec<bool>> = relay.members().map(|members| {
+            members
+                .iter()
+                .filter_map(|member| statuses.get(member).copied())
+                .collect()
+        });
+        if let Some(member_statuses) = member_statuses {
+            relay.follow_members(&member_statuses);
+        }
     }
+}
 
-    for (key, value1) in map1 {
-        match map2.get(key) {
-            Some(value2) => {
-                if value1 != value2 {
-                    return false;
-                }
+/// Names of the relays switched for `names`, groups replaced by their
+/// members, each relay once.
+pub(crate) fn expand_groups(relays: &HashMap<String, RelayType>, names: &[String]) -> Vec<String> {
+    let mut seen: HashSet<&String> = HashSet::new();
+    let mut expanded: Vec<String> = Vec::new();
+
+    for name in names {
+        let members = match relays.get(name).and_then(|relay| relay.members()) {
+            Some(members) => members.iter().collect(),
+            None => vec![name],
+        };
+        for member in members {
+            let switchable = relays.get(member).is_some_and(|relay| !is_group(relay));
+            if switchable && seen.insert(member) {
+                expanded.push(member.clone());
             }
-            None => return false,
         }
     }
 
-    true
+    expanded
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::models::relays::{KasaMultiPlug, RelayActions};
-
-    // #[test]
-    // fn test_singleplug_timeouts() {
-    //     let mut plug = KasaPlug::new("192.168.0.107".to_string(), "ScentLight".to_string(), "bedroom".to_string());
-    //     println!("{:?}", &plug.connected());
-    // }
-
-    #[test]
-    fn test_multiplug_stuff() {
-        let ip = "192.168.0.218".to_string();
-
-        let mut plugs: Vec<KasaMultiPlug> = KasaMultiPlug::new(
-            ip,
-            vec![
-                "BedframeLight".parse().unwrap(),
-                "BedroomLight".parse().unwrap(),
-            ],
-            "Bedroom".parse().unwrap(),
-            vec![],
-        )
-        .unwrap();
-        assert_eq!(plugs.len(), 2);
-
-        for plug in &plugs {
-            println!("{} \t {}", &plug.name, &plug.id);
+/// Turns the relay `name` on or off, or switches it when `state` is `None`.
+/// A group sets all of its connected members the same way, switching them to
+/// the opposite of the group's status.
+pub(crate) fn set_relay(
+    relays: &mut HashMap<String, RelayType>,
+    name: &str,
+    state: Option<bool>,
+) -> Result<Value, Error> {
+    let relay = relays
+        .get_mut(name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Unknown relay: {}", name)))?;
+
+    let result = match is_group(relay) {
+        true => {
+            let on = state.unwrap_or(!relay.status());
+            let members = expand_groups(relays, &[name.to_string()]);
+            let mut result = Ok(());
+            for member in members {
+                if let Some(relay) = relays.get_mut(&member) {
+                    let switched = match on {
+                        true => relay.turn_on(),
+                        false => relay.turn_off(),
+                    };
+                    if let (Err(error), Ok(())) = (switched, &result) {
+                        result = Err(error);
+                    }
+                }
+            }
+            result
         }
+        false => match state {
+            Some(true) => relay.turn_on(),
+            Some(false) => relay.turn_off(),
+            None => relay.switch(),
+        }
+        .map(|_| ()),
+    };
+
+    update_groups(relays);
+    result?;
+    Ok(relays[name].to_json())
+}
 
-        plugs
-            .get_mut(0)
-            .unwrap()
-            .turn_on()
-            .expect("TODO: panic message");
+/// Sets the brightness and colour of the relay `name`, or of every dimmable
+/// member of a group. A brightness of 0 turns the relay off instead.
+pub(crate) fn set_relay_levels(
+    relays: &mut HashMap<String, RelayType>,
+    name: &str,
+    levels: LightLevels,
+) -> Result<Value, Error> {
+    levels.check()?;
+    if levels.brightness == Some(0) {
+        return set_relay(relays, name, Some(false));
     }
+
+    let relay = relays
+        .get(name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Unknown relay: {}", name)))?;
+    let targets = match is_group(relay) {
+        true => expand_groups(relays, &[name.to_string()])
+            .into_iter()
+            .filter(|member| relays[member].levels().is_some())
+            .collect(),
+        false => vec![name.to_string()],
+    };
+    if targets.is_empty() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Group {} has no dimmable members", name),
+        ));
+    }
+
+    let mut result = Ok(());
+    for target in targets {
+        if let Some(relay) = relays.get_mut(&target) {
+            if let (Err(error), Ok(())) = (relay.set_levels(levels), &result) {
+                result = Err(error);
+            }
+        }
+    }
+
+    update_groups(relays);
+    result?;
+    Ok(relays[name].to_json())
 }
@@ -0,0 +1,28 @@
+use rocket::serde::{Deserialize, Serialize};
+
+/// Answer of `/shelly`, which every generation serves without authentication.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShellyInfo {
+    /// Only reported by Gen2 and later devices
+    #[serde(default)]
+    pub gen: Option<u8>,
+}
+
+/// Answer of the Gen1 `/relay/<channel>` endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShellyRelayStatus {
+    pub ison: bool,
+}
+
+/// Answer of the Gen2 `Switch.GetStatus` RPC.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShellySwitchStatus {
+    pub id: u8,
+    pub output: bool,
+}
+
+/// Answer of the Gen2 `Switch.Set` RPC.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShellySwitchSet {
+    pub was_on: bool,
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+use rocket::serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Answer of a Tasmota `/cm` command, e.g. `{"POWER1": "ON"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TasmotaResponse(pub HashMap<String, Value>);
+
+impl TasmotaResponse {
+    /// State of a POWER channel. Devices with a single relay answer with a
+    /// plain `POWER` key.
+    pub fn power(&self, channel: u8) -> Option<bool> {
+        let state = self
+            .0
+            .get(&format!("POWER{}", channel))
+            .or_else(|| match channel {
+                1 => self.0.get("POWER"),
+                _ => None,
+            })?;
+        match state.as_str()? {
+            "ON" => Some(true),
+            "OFF" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Number of POWER channels in a `Power0` answer.
+    pub fn power_count(&self) -> usize {
+        self.0.keys().filter(|key| key.starts_with("POWER")).count()
+    }
+
+    /// Set when the device refused the command, usually for missing credentials.
+    pub fn warning(&self) -> Option<&str> {
+        self.0.get("WARNING").and_then(Value::as_str)
+    }
+}
@@ -0,0 +1,33 @@
+use crate::models::api_response::ApiResponse;
+use crate::models::channels_models::Channels;
+use crate::models::data_thread_models::DataThreadCommand::Automations;
+use crate::models::data_thread_models::DataThreadResponse;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde_json::json;
+
+#[get("/automations")]
+pub(crate) async fn automations_route(channels: &State<Channels>) -> ApiResponse {
+    let error_message = ApiResponse {
+        value: Json(json!({"Error": "Could not get automations"})),
+        status: Status::new(500),
+    };
+
+    if channels.route_to_data_sender.send(Automations).is_err() {
+        return error_message;
+    }
+
+    match channels
+        .data_to_route_receiver
+        .lock()
+        .expect("Got data from channel")
+        .recv()
+    {
+        Ok(DataThreadResponse::Value(final_response)) => ApiResponse {
+            value: Json(final_response),
+            status: Status::Ok,
+        },
+        Err(_) | Ok(_) => error_message,
+    }
+}
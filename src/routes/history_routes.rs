@@ -0,0 +1,114 @@
+use crate::models::api_response::ApiResponse;
+use crate::models::channels_models::Channels;
+use crate::models::data_thread_models::DataThreadCommand::History;
+use crate::models::data_thread_models::{DataThreadResponse, HistoryCommand, HistoryQuery};
+use chrono::DateTime;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde_json::json;
+
+/// Accepts either unix seconds or an RFC 3339 timestamp, returning unix milliseconds.
+fn parse_timestamp(input: Option<&str>) -> Result<Option<i64>, ApiResponse> {
+    let input = match input {
+        Some(input) => input,
+        None => return Ok(None),
+    };
+
+    let bad_request = || ApiResponse {
+        value: Json(json!({"Error": format!("Could not parse timestamp: {}", input)})),
+        status: Status::BadRequest,
+    };
+
+    if let Ok(seconds) = input.parse::<i64>() {
+        // Seconds past what fits in milliseconds are rejected rather than wrapped
+        return match seconds.checked_mul(1000) {
+            Some(milliseconds) => Ok(Some(milliseconds)),
+            None => Err(bad_request()),
+        };
+    }
+
+    match DateTime::parse_from_rfc3339(input) {
+        Ok(time) => Ok(Some(time.timestamp_millis())),
+        Err(_) => Err(bad_request()),
+    }
+}
+
+fn query_history(command: HistoryCommand, channels: &State<Channels>) -> ApiResponse {
+    let error_message = ApiResponse {
+        value: Json(json!({"Error": "Could not get history"})),
+        status: Status::new(500),
+    };
+
+    if channels
+        .route_to_data_sender
+        .send(History(command))
+        .is_err()
+    {
+        return error_message;
+    }
+
+    match channels
+        .data_to_route_receiver
+        .lock()
+        .expect("Got data from channel")
+        .recv()
+    {
+        Ok(DataThreadResponse::Value(final_response)) => ApiResponse {
+            value: Json(final_response),
+            status: Status::Ok,
+        },
+        Ok(DataThreadResponse::Error(final_response)) => ApiResponse {
+            value: Json(json!({"Error": final_response})),
+            status: Status::new(500),
+        },
+        Err(_) | Ok(_) => error_message,
+    }
+}
+
+#[get("/history?<relay>&<since>&<until>&<limit>")]
+pub(crate) async fn relay_history_route(
+    relay: Option<&str>,
+    since: Option<&str>,
+    until: Option<&str>,
+    limit: Option<u32>,
+    channels: &State<Channels>,
+) -> ApiResponse {
+    let query = HistoryQuery {
+        relay: relay.map(String::from),
+        since: match parse_timestamp(since) {
+            Ok(since) => since,
+            Err(response) => return response,
+        },
+        until: match parse_timestamp(until) {
+            Ok(until) => until,
+            Err(response) => return response,
+        },
+        limit,
+    };
+
+    query_history(HistoryCommand::Relays(query), channels)
+}
+
+#[get("/history/presets?<since>&<until>&<limit>")]
+pub(crate) async fn preset_history_route(
+    since: Option<&str>,
+    until: Option<&str>,
+    limit: Option<u32>,
+    channels: &State<Channels>,
+) -> ApiResponse {
+    let query = HistoryQuery {
+        relay: None,
+        since: match parse_timestamp(since) {
+            Ok(since) => since,
+            Err(response) => return response,
+        },
+        until: match parse_timestamp(until) {
+            Ok(until) => until,
+            Err(response) => return response,
+        },
+        limit,
+    };
+
+    query_history(HistoryCommand::Presets(query), channels)
+}
@@ -57,8 +57,8 @@ pub async fn refresh_route(channels: &State<Channels>) -> ApiResponse {
         .expect("Got data from channel")
         .recv()
     {
-        Ok(DataThreadResponse::Bool(final_response)) => ApiResponse {
-            value: Json(json!({"refresh" : final_response})),
+        Ok(DataThreadResponse::Value(final_response)) => ApiResponse {
+            value: Json(final_response),
             status: Status::Ok,
         },
         Ok(DataThreadResponse::Error(final_response)) => ApiResponse {
@@ -1,3 +1,6 @@
+pub mod automation_routes;
+pub mod history_routes;
 pub mod index_routes;
 pub mod preset_routes;
 pub mod relay_routes;
+pub mod schema_routes;
@@ -7,11 +7,20 @@ use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
 use serde_json::json;
+use std::net::IpAddr;
+
 #[get("/preset/set/<preset_name>")]
-pub(crate) async fn set_preset_route(preset_name: &str, channels: &State<Channels>) -> ApiResponse {
+pub(crate) async fn set_preset_route(
+    preset_name: &str,
+    client_ip: Option<IpAddr>,
+    channels: &State<Channels>,
+) -> ApiResponse {
     if channels
         .route_to_data_sender
-        .send(Preset(PresetCommand::Set(preset_name.parse().unwrap())))
+        .send(Preset(PresetCommand::Set(
+            preset_name.parse().unwrap(),
+            client_ip,
+        )))
         .is_err()
     {
         return ApiResponse {
@@ -30,7 +39,7 @@ pub(crate) async fn set_preset_route(preset_name: &str, channels: &State<Channel
             value: Json(result),
             status: Status::Ok,
         },
-        Err(_) | _ => ApiResponse {
+        _ => ApiResponse {
             value: Json(
                 json!({"Error": format!("Could not find preset to set: {}", &preset_name)}),
             ),
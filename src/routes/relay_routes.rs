@@ -1,5 +1,9 @@
 use crate::models::channels_models::Channels;
-use crate::models::data_thread_models::{DataThreadCommand::Relay, RelayCommand, TagCommand};
+use crate::models::data_thread_models::{
+    DataThreadCommand, DataThreadCommand::Relay, DataThreadResponse, RelayCommand, RelayCommands,
+    TagCommand,
+};
+use crate::models::relays::LightLevels;
 use crate::utils::data_thread_handling::{handle_command_input, unwrap_response};
 
 use crate::models::api_response::ApiResponse;
@@ -9,11 +13,13 @@ use crate::models::data_thread_models::DataThreadCommand::Tag;
 use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
+use std::net::IpAddr;
 
 #[get("/relay/<relay_name>/<command_input>")]
 pub(crate) async fn set_relay_command_route(
     relay_name: &str,
     command_input: &str,
+    client_ip: Option<IpAddr>,
     channels: &State<Channels>,
 ) -> ApiResponse {
     let command_processed = match handle_command_input(command_input) {
@@ -26,33 +32,19 @@ pub(crate) async fn set_relay_command_route(
         }
     };
 
-    match channels.route_to_data_sender.send(Relay(RelayCommand {
+    let command = Relay(RelayCommand {
         name: relay_name.parse().unwrap(),
         command: command_processed,
-    })){
-        Ok(something ) => {println!("{:?}", something)},
-        Err(error) => return ApiResponse {
-            value: Json(json!({"Error": format!("Could not find relay name in relays {}", error)})),
-            status: Status::NotFound,
-        }
-    }
-
-    match channels.data_to_route_receiver.lock().unwrap().recv() {
-        Ok(response) => ApiResponse {
-            value: unwrap_response(response),
-            status: Status::Ok,
-        },
-        Err(error) => ApiResponse {
-            value: Json(json!({"Error": format!("Could not find relay name in relays {}", error)})),
-            status: Status::NotFound,
-        },
-    }
+        client_ip,
+    });
+    send_command(command, channels)
 }
 
 #[get("/relays/<tag>/<command_input>")]
 pub(crate) async fn set_relays_by_tag_command_route(
     tag: &str,
     command_input: &str,
+    client_ip: Option<IpAddr>,
     channels: &State<Channels>,
 ) -> ApiResponse {
     let command_processed = match handle_command_input(command_input) {
@@ -65,10 +57,18 @@ pub(crate) async fn set_relays_by_tag_command_route(
         }
     };
 
-    if let Err(_) = channels.route_to_data_sender.send(Tag(TagCommand {
+    let command = Tag(TagCommand {
         tag: tag.parse().unwrap(),
         command: command_processed,
-    })) {
+        client_ip,
+    });
+    send_command(command, channels)
+}
+
+/// Sends a relay or tag command to the data thread and returns its answer.
+/// Unknown relays and relays that fail to switch answer with their error.
+fn send_command(command: DataThreadCommand, channels: &State<Channels>) -> ApiResponse {
+    if channels.route_to_data_sender.send(command).is_err() {
         return ApiResponse {
             value: Json(json!({"Error": "Channel closed"})),
             status: Status::new(500),
@@ -76,6 +76,10 @@ pub(crate) async fn set_relays_by_tag_command_route(
     }
 
     match channels.data_to_route_receiver.lock().unwrap().recv() {
+        Ok(DataThreadResponse::Error(error)) => ApiResponse {
+            value: Json(json!({ "Error": error })),
+            status: Status::NotAcceptable,
+        },
         Ok(response) => ApiResponse {
             value: unwrap_response(response),
             status: Status::Ok,
@@ -86,3 +90,125 @@ pub(crate) async fn set_relays_by_tag_command_route(
         },
     }
 }
+
+fn relay_levels(
+    relay_name: &str,
+    levels: LightLevels,
+    client_ip: Option<IpAddr>,
+) -> DataThreadCommand {
+    Relay(RelayCommand {
+        name: relay_name.to_string(),
+        command: RelayCommands::LEVELS(levels),
+        client_ip,
+    })
+}
+
+#[get("/relay/<relay_name>/brightness/<brightness>")]
+pub(crate) async fn set_relay_brightness_route(
+    relay_name: &str,
+    brightness: u8,
+    client_ip: Option<IpAddr>,
+    channels: &State<Channels>,
+) -> ApiResponse {
+    let levels = LightLevels {
+        brightness: Some(brightness),
+        ..LightLevels::default()
+    };
+    send_command(relay_levels(relay_name, levels, client_ip), channels)
+}
+
+#[get("/relay/<relay_name>/color_temp/<kelvin>")]
+pub(crate) async fn set_relay_color_temp_route(
+    relay_name: &str,
+    kelvin: u16,
+    client_ip: Option<IpAddr>,
+    channels: &State<Channels>,
+) -> ApiResponse {
+    let levels = LightLevels {
+        color_temp: Some(kelvin),
+        ..LightLevels::default()
+    };
+    send_command(relay_levels(relay_name, levels, client_ip), channels)
+}
+
+#[get("/relay/<relay_name>/hsv/<hue>/<saturation>/<value>")]
+pub(crate) async fn set_relay_hsv_route(
+    relay_name: &str,
+    hue: u16,
+    saturation: u8,
+    value: u8,
+    client_ip: Option<IpAddr>,
+    channels: &State<Channels>,
+) -> ApiResponse {
+    let levels = LightLevels {
+        brightness: Some(value),
+        hue: Some(hue),
+        saturation: Some(saturation),
+        ..LightLevels::default()
+    };
+    send_command(relay_levels(relay_name, levels, client_ip), channels)
+}
+
+#[get("/relays/<tag>/brightness/<brightness>")]
+pub(crate) async fn set_relays_by_tag_brightness_route(
+    tag: &str,
+    brightness: u8,
+    client_ip: Option<IpAddr>,
+    channels: &State<Channels>,
+) -> ApiResponse {
+    let levels = LightLevels {
+        brightness: Some(brightness),
+        ..LightLevels::default()
+    };
+    let command = Tag(TagCommand {
+        tag: tag.to_string(),
+        command: RelayCommands::LEVELS(levels),
+        client_ip,
+    });
+    send_command(command, channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Client for the relay routes, answered by a data thread without relays.
+    fn client() -> Client {
+        let (route_to_data_sender, route_to_data_receiver) = channel::<DataThreadCommand>();
+        let (data_to_route_sender, data_to_route_receiver) = channel();
+
+        thread::spawn(move || {
+            for command in route_to_data_receiver {
+                if let Relay(relay_command) = command {
+                    let error = format!("Unknown relay: {}", relay_command.name);
+                    let _ = data_to_route_sender.send(DataThreadResponse::Error(error));
+                }
+            }
+        });
+
+        let channels = Channels {
+            route_to_data_sender,
+            data_to_route_receiver: Arc::new(Mutex::new(data_to_route_receiver)),
+        };
+        let rocket = rocket::build()
+            .manage(channels)
+            .mount("/", routes![set_relay_command_route]);
+        Client::tracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn test_unknown_relay_is_an_error() {
+        let client = client();
+        let response = client.get("/relay/Missing/switch").dispatch();
+
+        assert_eq!(response.status(), Status::NotAcceptable);
+        assert_eq!(
+            response.into_json::<serde_json::Value>().unwrap(),
+            json!({"Error": "Unknown relay: Missing"})
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use crate::models::api_response::ApiResponse;
+use crate::utils::local_config_utils::config_schema;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use serde_json::json;
+
+#[get("/schema/config")]
+pub(crate) async fn config_schema_route() -> ApiResponse {
+    match serde_json::to_value(config_schema()) {
+        Ok(schema) => ApiResponse {
+            value: Json(schema),
+            status: Status::Ok,
+        },
+        Err(_) => ApiResponse {
+            value: Json(json!({"Error": "Could not generate config schema"})),
+            status: Status::new(500),
+        },
+    }
+}
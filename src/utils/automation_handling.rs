@@ -0,0 +1,542 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, NaiveTime};
+use rocket::log;
+use serde_json::{json, Value};
+
+use crate::models::automations::{Automation, AutomationAction, AutomationTrigger};
+use crate::models::data_thread_models::RelayStateChange;
+use crate::models::presets::{set_preset, Preset};
+use crate::models::relays::{expand_groups, set_relay, RelayType};
+use crate::utils::data_thread_handling::{relay_states, state_changes};
+
+/// Number of times automations may trigger each other off a single event before
+/// the cascade is cut off.
+const MAX_CASCADE_DEPTH: usize = 8;
+
+#[derive(Debug, Default)]
+struct AutomationRecord {
+    last_triggered: Option<DateTime<Local>>,
+    trigger_count: u64,
+    last_relay: Option<String>,
+    last_message: Option<String>,
+    last_error: Option<String>,
+}
+
+/// Relay state changes and preset applications made by one automation run.
+#[derive(Debug, Default)]
+pub(crate) struct AutomationRun {
+    pub(crate) changes: Vec<RelayStateChange>,
+    pub(crate) presets: Vec<(String, bool)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct AutomationEngine {
+    automations: Vec<Automation>,
+    state_since: HashMap<String, (bool, Instant)>,
+    matched: HashSet<(String, String)>,
+    primed: HashSet<String>,
+    records: HashMap<String, AutomationRecord>,
+}
+
+pub(crate) fn parse_time(time: &Option<String>) -> Result<Option<NaiveTime>, String> {
+    match time {
+        Some(time) => NaiveTime::parse_from_str(time, "%H:%M")
+            .map(Some)
+            .map_err(|_| format!("Invalid time of day: {}", time)),
+        None => Ok(None),
+    }
+}
+
+fn within_window(trigger: &AutomationTrigger, time_of_day: NaiveTime) -> Result<bool, String> {
+    let after = parse_time(&trigger.after)?;
+    let before = parse_time(&trigger.before)?;
+
+    Ok(match (after, before) {
+        (Some(after), Some(before)) if after <= before => {
+            time_of_day >= after && time_of_day < before
+        }
+        (Some(after), Some(before)) => time_of_day >= after || time_of_day < before,
+        (Some(after), None) => time_of_day >= after,
+        (None, Some(before)) => time_of_day < before,
+        (None, None) => true,
+    })
+}
+
+fn watches(trigger: &AutomationTrigger, relay: &RelayType) -> bool {
+    if let Some(name) = &trigger.relay {
+        return relay.name() == name;
+    }
+    if let Some(tag) = &trigger.tag {
+        return relay.tags().contains(tag);
+    }
+    if let Some(room) = &trigger.room {
+        return relay.room() == room;
+    }
+    false
+}
+
+impl AutomationEngine {
+    pub(crate) fn new(automations: Vec<Automation>, relays: &HashMap<String, RelayType>) -> Self {
+        let mut engine = AutomationEngine {
+            automations,
+            state_since: HashMap::new(),
+            matched: HashSet::new(),
+            primed: HashSet::new(),
+            records: HashMap::new(),
+        };
+        engine.evaluate(relays, Instant::now(), Local::now().time());
+        engine
+    }
+
+    pub(crate) fn automations(&self) -> &Vec<Automation> {
+        &self.automations
+    }
+
+    /// Replaces the configured automations, keeping trigger history for the ones
+    /// that still exist. New or edited automations are primed against the current
+    /// relay states so they only fire on the next transition.
+    pub(crate) fn set_automations(
+        &mut self,
+        automations: Vec<Automation>,
+        relays: &HashMap<String, RelayType>,
+    ) {
+        for automation in &automations {
+            if !self.automations.contains(automation) {
+                self.primed.remove(&automation.name);
+                self.matched.retain(|(name, _)| name != &automation.name);
+            }
+        }
+
+        let names: HashSet<&String> = automations.iter().map(|a| &a.name).collect();
+        self.records.retain(|name, _| names.contains(name));
+        self.primed.retain(|name| names.contains(name));
+        self.matched.retain(|(name, _)| names.contains(name));
+
+        self.automations = automations;
+        self.evaluate(relays, Instant::now(), Local::now().time());
+    }
+
+    pub(crate) fn observe(&mut self, changes: &[RelayStateChange]) {
+        let now = Instant::now();
+        for change in changes {
+            self.state_since
+                .insert(change.name.clone(), (change.current, now));
+        }
+    }
+
+    /// Returns the automations (by index) and relay names whose trigger became
+    /// true since the previous evaluation.
+    fn evaluate(
+        &mut self,
+        relays: &HashMap<String, RelayType>,
+        now: Instant,
+        time_of_day: NaiveTime,
+    ) -> Vec<(usize, String)> {
+        let mut firing: Vec<(usize, String)> = Vec::new();
+
+        for relay in relays.values() {
+            match self.state_since.get(relay.name()) {
+                Some((status, _)) if *status == relay.status() => {}
+                _ => {
+                    self.state_since
+                        .insert(relay.name().clone(), (relay.status(), now));
+                }
+            }
+        }
+
+        for (index, automation) in self.automations.iter().enumerate() {
+            if !automation.enabled {
+                self.primed.remove(&automation.name);
+                continue;
+            }
+
+            let trigger = &automation.when;
+            let held_for = match &trigger.held_for {
+                Some(duration) => match duration.to_duration() {
+                    Ok(duration) => duration,
+                    Err(error) => {
+                        self.records
+                            .entry(automation.name.clone())
+                            .or_default()
+                            .last_error = Some(error.to_string());
+                        continue;
+                    }
+                },
+                None => Duration::ZERO,
+            };
+            let in_window = match within_window(trigger, time_of_day) {
+                Ok(in_window) => in_window,
+                Err(error) => {
+                    self.records
+                        .entry(automation.name.clone())
+                        .or_default()
+                        .last_error = Some(error);
+                    continue;
+                }
+            };
+
+            for relay in relays.values().filter(|relay| watches(trigger, relay)) {
+                let satisfied = in_window
+                    && match self.state_since.get(relay.name()) {
+                        Some((status, since)) => {
+                            *status == trigger.state && now.duration_since(*since) >= held_for
+                        }
+                        None => false,
+                    };
+
+                let key = (automation.name.clone(), relay.name().clone());
+                if !satisfied {
+                    self.matched.remove(&key);
+                } else if self.matched.insert(key) && self.primed.contains(&automation.name) {
+                    firing.push((index, relay.name().clone()));
+                }
+            }
+
+            self.primed.insert(automation.name.clone());
+        }
+
+        firing
+    }
+
+    /// Evaluates every automation against the current relay states and runs the
+    /// actions of those that triggered, following any further automations those
+    /// actions set off.
+    pub(crate) fn run(
+        &mut self,
+        relays: &mut HashMap<String, RelayType>,
+        presets: &HashMap<String, Preset>,
+        current_preset: &Mutex<String>,
+    ) -> AutomationRun {
+        let mut produced = AutomationRun::default();
+        let mut fired: HashSet<String> = HashSet::new();
+
+        for depth in 0..=MAX_CASCADE_DEPTH {
+            let firing: Vec<(usize, String)> = self
+                .evaluate(relays, Instant::now(), Local::now().time())
+                .into_iter()
+                .filter(|(index, relay_name)| {
+                    let name = &self.automations[*index].name;
+                    if fired.contains(name) {
+                        log::warn_!(
+                            "Automation {} suppressed for {}: already ran in this cascade",
+                            name,
+                            relay_name
+                        );
+                        return false;
+                    }
+                    true
+                })
+                .collect();
+
+            if firing.is_empty() {
+                break;
+            }
+
+            if depth == MAX_CASCADE_DEPTH {
+                log::warn_!(
+                    "Automation cascade stopped after {} levels",
+                    MAX_CASCADE_DEPTH
+                );
+                break;
+            }
+
+            let before = relay_states(relays);
+            for (index, relay_name) in &firing {
+                self.execute(
+                    *index,
+                    relay_name,
+                    relays,
+                    presets,
+                    current_preset,
+                    &mut produced.presets,
+                );
+            }
+            fired.extend(
+                firing
+                    .iter()
+                    .map(|(index, _)| self.automations[*index].name.clone()),
+            );
+
+            let changes = state_changes(&before, relays);
+            self.observe(&changes);
+            produced.changes.extend(changes);
+        }
+
+        produced
+    }
+
+    fn execute(
+        &mut self,
+        index: usize,
+        relay_name: &str,
+        relays: &mut HashMap<String, RelayType>,
+        presets: &HashMap<String, Preset>,
+        current_preset: &Mutex<String>,
+        applied_presets: &mut Vec<(String, bool)>,
+    ) {
+        let automation = self.automations[index].clone();
+        let record = self.records.entry(automation.name.clone()).or_default();
+
+        record.last_triggered = Some(Local::now());
+        record.trigger_count += 1;
+        record.last_relay = Some(relay_name.to_string());
+        record.last_error = None;
+
+        for action in &automation.then {
+            let result = match action {
+                AutomationAction::Relay { relay, set } => match relays.contains_key(relay) {
+                    true => {
+                        *current_preset.lock().unwrap() = "Custom".to_string();
+                        set_relay(relays, relay, Some(*set))
+                            .map(|_| ())
+                            .map_err(|error| error.to_string())
+                    }
+                    false => Err(format!("Unknown relay: {}", relay)),
+                },
+                AutomationAction::Tag { tag, set } => {
+                    *current_preset.lock().unwrap() = "Custom".to_string();
+                    let tagged: Vec<String> = relays
+                        .iter()
+                        .filter(|(_, target)| target.tags().contains(tag))
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    // A relay that fails doesn't stop the rest of the tag
+                    let mut result = Ok(());
+                    for name in expand_groups(relays, &tagged) {
+                        let switched = set_relay(relays, &name, Some(*set));
+                        if let (Err(error), Ok(())) = (switched, &result) {
+                            result = Err(error.to_string());
+                        }
+                    }
+                    result
+                }
+                AutomationAction::Preset { preset } => match presets.get(preset) {
+                    Some(preset) => match set_preset(preset, relays) {
+                        Ok(value) if value["presetSet"] == true => {
+                            applied_presets.push((preset.name.clone(), true));
+                            *current_preset.lock().unwrap() = preset.name.clone();
+                            Ok(())
+                        }
+                        Ok(_) => {
+                            applied_presets.push((preset.name.clone(), false));
+                            Err(format!("Could not set preset: {}", preset.name))
+                        }
+                        Err(error) => {
+                            applied_presets.push((preset.name.clone(), false));
+                            Err(error.to_string())
+                        }
+                    },
+                    None => Err(format!("Unknown preset: {}", preset)),
+                },
+                AutomationAction::Notify { notify } => {
+                    let message = notify.replace("{relay}", relay_name);
+                    log::warn_!("Automation {}: {}", automation.name, message);
+                    record.last_message = Some(message);
+                    Ok(())
+                }
+            };
+
+            if let Err(error) = result {
+                log::warn_!("Automation {} failed: {}", automation.name, error);
+                record.last_error = Some(error);
+            }
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let automations: Vec<Value> = self
+            .automations
+            .iter()
+            .map(|automation| {
+                let record = self.records.get(&automation.name);
+                json!({
+                    "name": &automation.name,
+                    "enabled": automation.enabled,
+                    "lastTriggered": record
+                        .and_then(|record| record.last_triggered)
+                        .map(|time| time.to_rfc3339()),
+                    "triggerCount": record.map(|record| record.trigger_count).unwrap_or(0),
+                    "lastRelay": record.and_then(|record| record.last_relay.clone()),
+                    "lastMessage": record.and_then(|record| record.last_message.clone()),
+                    "lastError": record.and_then(|record| record.last_error.clone()),
+                })
+            })
+            .collect();
+
+        Value::Array(automations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::kasa::KasaPlug;
+    use crate::drivers::virtual_relay::VirtualRelay;
+
+    fn relay(name: &str, status: bool, tags: Vec<&str>) -> (String, RelayType) {
+        let mut plug = KasaPlug::new(
+            "127.0.0.1".to_string(),
+            name.to_string(),
+            "kitchen".to_string(),
+            tags.into_iter().map(String::from).collect(),
+        );
+        plug.status = status;
+        (name.to_string(), Box::new(plug) as RelayType)
+    }
+
+    fn automation(json: Value) -> Automation {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn set_status(relays: &mut HashMap<String, RelayType>, name: &str, status: bool) {
+        let tags: Vec<&str> = relays[name].tags().iter().map(String::as_str).collect();
+        let (name, plug) = relay(name, status, tags);
+        relays.insert(name, plug);
+    }
+
+    fn noon() -> NaiveTime {
+        NaiveTime::from_hms_opt(12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_fires_only_on_transition() {
+        let mut relays: HashMap<String, RelayType> =
+            HashMap::from([relay("DeskLamp", true, vec![])]);
+        let mut engine = AutomationEngine::new(
+            vec![automation(json!({
+                "name": "Monitor follows lamp",
+                "when": {"relay": "DeskLamp", "state": true},
+                "then": [{"notify": "{relay} on"}]
+            }))],
+            &relays,
+        );
+        let now = Instant::now();
+
+        assert!(engine.evaluate(&relays, now, noon()).is_empty());
+
+        set_status(&mut relays, "DeskLamp", false);
+        assert!(engine.evaluate(&relays, now, noon()).is_empty());
+
+        set_status(&mut relays, "DeskLamp", true);
+        assert_eq!(
+            engine.evaluate(&relays, now, noon()),
+            vec![(0, "DeskLamp".to_string())]
+        );
+        assert!(engine.evaluate(&relays, now, noon()).is_empty());
+    }
+
+    #[test]
+    fn test_fires_after_held_duration() {
+        let relays: HashMap<String, RelayType> = HashMap::from([relay("Heater", true, vec![])]);
+        let mut engine = AutomationEngine::new(
+            vec![automation(json!({
+                "name": "Heater timeout",
+                "when": {"relay": "Heater", "state": true, "for": "2h"},
+                "then": [{"relay": "Heater", "set": false}]
+            }))],
+            &relays,
+        );
+        let start = engine.state_since["Heater"].1;
+
+        assert!(engine
+            .evaluate(&relays, start + Duration::from_secs(3600), noon())
+            .is_empty());
+        assert_eq!(
+            engine.evaluate(&relays, start + Duration::from_secs(7200), noon()),
+            vec![(0, "Heater".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_tag_trigger_respects_time_window() {
+        let relays: HashMap<String, RelayType> = HashMap::from([
+            relay("Kettle", true, vec!["kitchen"]),
+            relay("Toaster", false, vec!["kitchen"]),
+        ]);
+        let mut engine = AutomationEngine::new(
+            vec![automation(json!({
+                "name": "Kitchen left on",
+                "when": {"tag": "kitchen", "state": true, "after": "23:00", "before": "06:00"},
+                "then": [{"notify": "{relay} is still on"}]
+            }))],
+            &relays,
+        );
+        let now = Instant::now();
+
+        assert!(engine.evaluate(&relays, now, noon()).is_empty());
+        assert_eq!(
+            engine.evaluate(&relays, now, NaiveTime::from_hms_opt(23, 30, 0).unwrap()),
+            vec![(0, "Kettle".to_string())]
+        );
+        assert!(engine
+            .evaluate(&relays, now, NaiveTime::from_hms_opt(1, 0, 0).unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_notify_records_trigger() {
+        let mut relays: HashMap<String, RelayType> =
+            HashMap::from([relay("DeskLamp", false, vec![])]);
+        let presets: HashMap<String, Preset> = HashMap::new();
+        let current_preset = Mutex::new("Custom".to_string());
+        let mut engine = AutomationEngine::new(
+            vec![automation(json!({
+                "name": "Lamp notice",
+                "when": {"relay": "DeskLamp", "state": true},
+                "then": [{"notify": "{relay} turned on"}]
+            }))],
+            &relays,
+        );
+
+        set_status(&mut relays, "DeskLamp", true);
+        assert!(engine
+            .run(&mut relays, &presets, &current_preset)
+            .changes
+            .is_empty());
+
+        let status = engine.to_json();
+        assert_eq!(status[0]["triggerCount"], 1);
+        assert_eq!(status[0]["lastMessage"], "DeskLamp turned on");
+        assert!(status[0]["lastTriggered"].is_string());
+    }
+
+    #[test]
+    fn test_tag_actions_switch_past_a_failing_relay() {
+        let virtual_relay = |name: &str| {
+            let relay = VirtualRelay::new(
+                name.to_string(),
+                "kitchen".to_string(),
+                vec!["downstairs".to_string()],
+            );
+            (name.to_string(), Box::new(relay) as RelayType)
+        };
+        // Nothing listens on the Kasa port of localhost
+        let mut relays: HashMap<String, RelayType> = HashMap::from([
+            relay("DeskLamp", false, vec![]),
+            relay("Heater", false, vec!["downstairs"]),
+            virtual_relay("Fan"),
+            virtual_relay("Radio"),
+        ]);
+        let presets: HashMap<String, Preset> = HashMap::new();
+        let current_preset = Mutex::new("Custom".to_string());
+        let mut engine = AutomationEngine::new(
+            vec![automation(json!({
+                "name": "Downstairs on",
+                "when": {"relay": "DeskLamp", "state": true},
+                "then": [{"tag": "downstairs", "set": true}]
+            }))],
+            &relays,
+        );
+
+        set_status(&mut relays, "DeskLamp", true);
+        engine.run(&mut relays, &presets, &current_preset);
+
+        assert!(relays["Fan"].status());
+        assert!(relays["Radio"].status());
+        assert!(!relays["Heater"].status());
+        assert!(engine.to_json()[0]["lastError"].is_string());
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::models::config_models::ConfigRelay;
+use crate::models::presets::Preset;
+use crate::models::relays::RelayType;
+
+/// What a config reload changed, keyed by relay and preset name.
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConfigDiff {
+    pub(crate) relays_added: Vec<String>,
+    pub(crate) relays_removed: Vec<String>,
+    pub(crate) relays_changed: Vec<String>,
+    pub(crate) relays_unreachable: Vec<String>,
+    pub(crate) presets_added: Vec<String>,
+    pub(crate) presets_removed: Vec<String>,
+    pub(crate) presets_changed: Vec<String>,
+    pub(crate) automations_changed: bool,
+}
+
+impl ConfigDiff {
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == ConfigDiff::default()
+    }
+
+    /// Whether anything was added, removed or edited. Relays that are only
+    /// unreachable don't count, they are retried on every refresh.
+    pub(crate) fn has_changes(&self) -> bool {
+        let mut diff = self.clone();
+        diff.relays_unreachable.clear();
+        !diff.is_empty()
+    }
+}
+
+/// Config entries that have to be (re)connected or only relabelled, and the
+/// relay names no entry describes anymore.
+#[derive(Debug, Default)]
+pub(crate) struct RelayDiff {
+    pub(crate) connect: Vec<ConfigRelay>,
+    /// Entries whose relays only moved room or changed tags
+    pub(crate) relabel: Vec<ConfigRelay>,
+    pub(crate) remove: Vec<String>,
+}
+
+/// Whether the relay is connected the way the entry describes, so it can be
+/// kept as is.
+fn same_connection(relay: &RelayType, entry: &ConfigRelay) -> bool {
+    let same_options = relay
+        .options()
+        .is_none_or(|options| options == &entry.options);
+
+    relay.driver() == entry.relay_type.name()
+        && same_options
+        && relay.ip().is_none_or(|ip| ip == &entry.ip)
+}
+
+fn same_placement(relay: &RelayType, entry: &ConfigRelay) -> bool {
+    relay.room() == &entry.room && relay.tags() == &entry.tags
+}
+
+/// Compares config entries against the running relays. An entry is only
+/// reconnected when its type, ip or options changed, or a relay it describes
+/// isn't running, so unchanged relays keep their connection and cached status.
+/// Entries that only changed room or tags are relabelled in place.
+pub(crate) fn diff_relays(
+    relays: &HashMap<String, RelayType>,
+    entries: Vec<ConfigRelay>,
+) -> RelayDiff {
+    let mut diff = RelayDiff::default();
+    let mut configured: HashSet<String> = HashSet::new();
+
+    for entry in entries {
+        // Entries without a built-in driver are skipped, validation reports them
+        if entry.relay_type.driver().is_none() {
+            continue;
+        }
+        let names = entry.relay_names();
+        configured.extend(names.iter().cloned());
+        let running: Vec<&RelayType> = names.iter().filter_map(|name| relays.get(name)).collect();
+
+        let connected = !names.is_empty()
+            && running.len() == names.len()
+            && running.iter().all(|relay| same_connection(relay, &entry));
+        if !connected {
+            diff.connect.push(entry);
+        } else if !running.iter().all(|relay| same_placement(relay, &entry)) {
+            diff.relabel.push(entry);
+        }
+    }
+
+    diff.remove.extend(
+        relays
+            .keys()
+            .filter(|name| !configured.contains(*name))
+            .cloned(),
+    );
+    diff.remove.sort();
+    diff
+}
+
+/// Applies a relay diff, connecting the changed entries with `connect`.
+/// Returns the resulting added, removed, changed and unreachable names. A
+/// relay whose changed entry can't be connected keeps running as it was,
+/// flagged unreachable, and counts as changed once a later refresh connects
+/// it.
+pub(crate) fn apply_relay_diff(
+    relays: &mut HashMap<String, RelayType>,
+    relay_diff: RelayDiff,
+    connect: impl FnOnce(Vec<ConfigRelay>) -> HashMap<String, RelayType>,
+    diff: &mut ConfigDiff,
+) {
+    for name in relay_diff.remove {
+        if let Some(mut relay) = relays.remove(&name) {
+            relay.release();
+        }
+        diff.relays_removed.push(name);
+    }
+
+    for entry in relay_diff.relabel {
+        for name in entry.relay_names() {
+            if let Some(relay) = relays.get_mut(&name) {
+                relay.set_placement(entry.room.clone(), entry.tags.clone());
+                diff.relays_changed.push(name);
+            }
+        }
+    }
+
+    let wanted: Vec<String> = relay_diff
+        .connect
+        .iter()
+        .flat_map(|entry| entry.relay_names())
+        .collect();
+    let connected = connect(relay_diff.connect);
+    for name in wanted {
+        match (connected.contains_key(&name), relays.get_mut(&name)) {
+            (true, Some(_)) => diff.relays_changed.push(name),
+            (true, None) => diff.relays_added.push(name),
+            (false, Some(relay)) => {
+                relay.mark_unreachable();
+                diff.relays_unreachable.push(name);
+            }
+            (false, None) => diff.relays_unreachable.push(name),
+        }
+    }
+    relays.extend(connected);
+
+    diff.relays_added.sort();
+    diff.relays_changed.sort();
+    diff.relays_unreachable.sort();
+}
+
+/// Replaces the presets, recording which were added, removed or edited.
+pub(crate) fn apply_preset_diff(
+    presets: &mut HashMap<String, Preset>,
+    new_presets: HashMap<String, Preset>,
+    diff: &mut ConfigDiff,
+) {
+    for (name, preset) in &new_presets {
+        match presets.get(name) {
+            None => diff.presets_added.push(name.clone()),
+            Some(existing) if existing != preset => diff.presets_changed.push(name.clone()),
+            Some(_) => {}
+        }
+    }
+    for name in presets.keys() {
+        if !new_presets.contains_key(name) {
+            diff.presets_removed.push(name.clone());
+        }
+    }
+
+    diff.presets_added.sort();
+    diff.presets_removed.sort();
+    diff.presets_changed.sort();
+    *presets = new_presets;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::kasa::KasaPlug;
+    use crate::models::config_models::{ConfigRelayType, RelayOptions};
+    use crate::models::relays::TrackedRelay;
+
+    fn running(name: &str, ip: &str) -> (String, RelayType) {
+        let mut plug = KasaPlug::new(
+            ip.to_string(),
+            name.to_string(),
+            "office".to_string(),
+            vec![],
+        );
+        plug.status = true;
+        (name.to_string(), TrackedRelay::track(Box::new(plug)))
+    }
+
+    fn entry(name: &str, ip: &str) -> ConfigRelay {
+        ConfigRelay {
+            relay_type: ConfigRelayType::named("KasaPlug").unwrap(),
+            name: name.to_string(),
+            names: vec![],
+            ip: ip.to_string(),
+            room: "office".to_string(),
+            tags: vec![],
+            restore: None,
+            options: RelayOptions::default(),
+        }
+    }
+
+    fn connect_all(entries: Vec<ConfigRelay>) -> HashMap<String, RelayType> {
+        entries
+            .into_iter()
+            .filter(|entry| entry.ip != "unreachable")
+            .map(|entry| {
+                let plug = KasaPlug::new(entry.ip, entry.name.clone(), entry.room, entry.tags);
+                (entry.name, Box::new(plug) as RelayType)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unchanged_relays_are_kept() {
+        let mut relays: HashMap<String, RelayType> = HashMap::from([
+            running("DeskLamp", "192.168.0.10"),
+            running("Heater", "192.168.0.11"),
+            running("Fan", "192.168.0.12"),
+            running("Kettle2", "192.168.0.14"),
+            running("Lamp", "192.168.0.15"),
+        ]);
+        let mut lamp = entry("Lamp", "192.168.0.15");
+        lamp.room = "bedroom".to_string();
+        lamp.tags = vec!["night".to_string()];
+
+        let relay_diff = diff_relays(
+            &relays,
+            vec![
+                entry("DeskLamp", "192.168.0.10"),
+                entry("Heater", "192.168.0.99"),
+                entry("Kettle", "192.168.0.13"),
+                entry("Toaster", "unreachable"),
+                entry("Kettle2", "unreachable"),
+                lamp,
+            ],
+        );
+        assert_eq!(relay_diff.connect.len(), 4);
+        assert_eq!(relay_diff.relabel.len(), 1);
+        assert_eq!(relay_diff.remove, vec!["Fan"]);
+
+        let mut diff = ConfigDiff::default();
+        apply_relay_diff(&mut relays, relay_diff, connect_all, &mut diff);
+
+        assert_eq!(diff.relays_added, vec!["Kettle"]);
+        assert_eq!(diff.relays_removed, vec!["Fan"]);
+        assert_eq!(diff.relays_changed, vec!["Heater", "Lamp"]);
+        assert_eq!(diff.relays_unreachable, vec!["Kettle2", "Toaster"]);
+
+        assert!(relays["DeskLamp"].status(), "untouched relay keeps status");
+        assert_eq!(relays["Heater"].ip().unwrap(), "192.168.0.99");
+        assert!(!relays.contains_key("Fan"));
+
+        // A relay whose new entry can't be reached keeps running, unreachable
+        assert_eq!(relays["Kettle2"].ip().unwrap(), "192.168.0.14");
+        assert!(!relays["Kettle2"].reachable());
+
+        // Moving a relay keeps its connection and status
+        assert!(relays["Lamp"].status());
+        assert_eq!(relays["Lamp"].room(), "bedroom");
+        assert_eq!(relays["Lamp"].tags(), &vec!["night".to_string()]);
+    }
+
+    #[test]
+    fn test_preset_diff() {
+        let preset = |name: &str, enabled: bool| Preset {
+            name: name.to_string(),
+            enabled,
+            relays: HashMap::new(),
+            levels: HashMap::new(),
+        };
+        let mut presets: HashMap<String, Preset> = HashMap::from([
+            ("Evening".to_string(), preset("Evening", true)),
+            ("Morning".to_string(), preset("Morning", true)),
+        ]);
+
+        let mut diff = ConfigDiff::default();
+        apply_preset_diff(
+            &mut presets,
+            HashMap::from([
+                ("Evening".to_string(), preset("Evening", false)),
+                ("Night".to_string(), preset("Night", true)),
+            ]),
+            &mut diff,
+        );
+
+        assert_eq!(diff.presets_added, vec!["Night"]);
+        assert_eq!(diff.presets_removed, vec!["Morning"]);
+        assert_eq!(diff.presets_changed, vec!["Evening"]);
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn test_unreachable_relays_are_not_changes() {
+        let diff = ConfigDiff {
+            relays_unreachable: vec!["Toaster".to_string()],
+            ..ConfigDiff::default()
+        };
+        assert!(!diff.is_empty());
+        assert!(!diff.has_changes());
+    }
+}
@@ -0,0 +1,314 @@
+use std::io::Error;
+use std::thread;
+
+use crate::models::automations::Automation;
+use crate::models::config_models::{ConfigRelay, ServerSettings};
+use crate::models::presets::Preset;
+use crate::utils::load_config::{load_config, ConfigLocation, ConfigSettings};
+use crate::utils::local_config_utils::{write_config_file, LoadedConfig};
+use crate::utils::mongodb_utils::{mongo_connection, write_mongo_config};
+use crate::utils::sqlite_config_utils::{open_sqlite_config, write_sqlite_config};
+
+/// How a transferred config is combined with what the target already holds.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub(crate) enum TransferMode {
+    /// Add new entries and replace ones with the same name, keep the rest
+    Merge,
+    /// Replace the target config entirely
+    Overwrite,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SectionChanges {
+    pub(crate) added: Vec<String>,
+    pub(crate) updated: Vec<String>,
+    pub(crate) removed: Vec<String>,
+}
+
+impl SectionChanges {
+    fn print(&self, section: &str) {
+        println!(
+            "{}: {} added, {} updated, {} removed",
+            section,
+            self.added.len(),
+            self.updated.len(),
+            self.removed.len()
+        );
+        for name in &self.added {
+            println!("  + {}", name);
+        }
+        for name in &self.updated {
+            println!("  ~ {}", name);
+        }
+        for name in &self.removed {
+            println!("  - {}", name);
+        }
+    }
+}
+
+/// Combines one section of the config. Entries are matched with `same` and
+/// named in the report with `label`.
+fn transfer_section<T: Clone + PartialEq>(
+    existing: &[T],
+    incoming: &[T],
+    mode: TransferMode,
+    same: impl Fn(&T, &T) -> bool,
+    label: impl Fn(&T) -> String,
+) -> (Vec<T>, SectionChanges) {
+    let mut changes = SectionChanges::default();
+    let mut result: Vec<T> = match mode {
+        TransferMode::Merge => existing.to_vec(),
+        TransferMode::Overwrite => Vec::new(),
+    };
+
+    for entry in incoming {
+        match existing.iter().find(|current| same(current, entry)) {
+            Some(current) if current != entry => changes.updated.push(label(entry)),
+            Some(_) => {}
+            None => changes.added.push(label(entry)),
+        }
+
+        match result.iter_mut().find(|current| same(current, entry)) {
+            Some(current) => *current = entry.clone(),
+            None => result.push(entry.clone()),
+        }
+    }
+
+    if mode == TransferMode::Overwrite {
+        changes.removed = existing
+            .iter()
+            .filter(|current| !incoming.iter().any(|entry| same(current, entry)))
+            .map(&label)
+            .collect();
+    }
+
+    (result, changes)
+}
+
+/// Presets every backend fills in on load, they aren't worth transferring.
+fn is_default_preset(preset: &Preset) -> bool {
+    ["Custom", "FullOff"].contains(&preset.name.as_str())
+        && !preset.enabled
+        && preset.relays.is_empty()
+}
+
+/// Merges or overwrites `existing` with `incoming`, reporting what changes.
+pub(crate) fn transfer_config(
+    existing: &LoadedConfig,
+    incoming: &LoadedConfig,
+    mode: TransferMode,
+) -> (LoadedConfig, [SectionChanges; 3]) {
+    let (relays, relay_changes) = transfer_section(
+        &existing.relays,
+        &incoming.relays,
+        mode,
+        |current: &ConfigRelay, entry: &ConfigRelay| {
+            let names = entry.relay_names();
+            current
+                .relay_names()
+                .iter()
+                .any(|name| names.contains(name))
+        },
+        |relay| relay.relay_names().join(", "),
+    );
+
+    let without_defaults = |presets: &[Preset]| -> Vec<Preset> {
+        presets
+            .iter()
+            .filter(|preset| !is_default_preset(preset))
+            .cloned()
+            .collect()
+    };
+    let (presets, preset_changes) = transfer_section(
+        &without_defaults(&existing.presets),
+        &without_defaults(&incoming.presets),
+        mode,
+        |current: &Preset, entry: &Preset| current.name == entry.name,
+        |preset| preset.name.clone(),
+    );
+
+    let (automations, automation_changes) = transfer_section(
+        &existing.automations,
+        &incoming.automations,
+        mode,
+        |current: &Automation, entry: &Automation| current.name == entry.name,
+        |automation| automation.name.clone(),
+    );
+
+    let server = match mode {
+        TransferMode::Merge => incoming.server.clone().or(existing.server.clone()),
+        TransferMode::Overwrite => incoming.server.clone(),
+    };
+
+    (
+        LoadedConfig {
+            relays,
+            presets,
+            automations,
+            server,
+        },
+        [relay_changes, preset_changes, automation_changes],
+    )
+}
+
+fn describe(settings: &ConfigSettings) -> String {
+    match settings.location {
+        ConfigLocation::LOCAL => settings.config_file.display().to_string(),
+        ConfigLocation::SQLITE => format!("SQLite {}", settings.sqlite_path),
+        ConfigLocation::MONGODB => format!("MongoDB {}", settings.mongo.database),
+    }
+}
+
+/// Reads the target's current config. A local file that doesn't exist yet
+/// counts as empty.
+fn read_target(settings: &ConfigSettings) -> Result<LoadedConfig, Error> {
+    if let ConfigLocation::LOCAL = settings.location {
+        if !settings.config_file.exists() {
+            return Ok(LoadedConfig {
+                relays: Vec::new(),
+                presets: Vec::new(),
+                automations: Vec::new(),
+                server: ServerSettings::default(),
+            });
+        }
+    }
+
+    load_config(settings)
+        .join()
+        .expect("Unable to join config thread")
+}
+
+fn write_target(settings: &ConfigSettings, config: &LoadedConfig) -> Result<(), Error> {
+    match settings.location {
+        ConfigLocation::LOCAL => write_config_file(&settings.config_file, config),
+        ConfigLocation::SQLITE => {
+            let mut connection = open_sqlite_config(&settings.sqlite_path).map_err(Error::other)?;
+            write_sqlite_config(&mut connection, config).map_err(Error::other)
+        }
+        ConfigLocation::MONGODB => {
+            let settings = settings.clone();
+            let config = config.clone();
+            thread::spawn(move || {
+                let connection = mongo_connection(&settings.mongo).map_err(Error::other)?;
+                connection.block_on(write_mongo_config(
+                    connection.client(),
+                    &settings.mongo,
+                    &config,
+                ))
+            })
+            .join()
+            .expect("Unable to join config thread")
+        }
+    }
+}
+
+fn transfer(
+    source: &ConfigSettings,
+    target: &ConfigSettings,
+    mode: TransferMode,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let incoming = load_config(source)
+        .join()
+        .expect("Unable to join config thread")?;
+    let existing = read_target(target)?;
+
+    let (config, changes) = transfer_config(&existing, &incoming, mode);
+    for (section, section_changes) in ["relays", "presets", "automations"].iter().zip(&changes) {
+        section_changes.print(section);
+    }
+
+    if dry_run {
+        println!("Dry run, {} was not changed", describe(target));
+        return Ok(());
+    }
+
+    write_target(target, &config)?;
+    println!("Wrote config to {}", describe(target));
+    Ok(())
+}
+
+/// Runs `config export` or `config import`, copying the config from `source`
+/// into `target`. Returns whether it succeeded.
+pub(crate) fn run_transfer_command(
+    source: &ConfigSettings,
+    target: &ConfigSettings,
+    mode: TransferMode,
+    dry_run: bool,
+) -> bool {
+    println!(
+        "Copying config from {} to {}",
+        describe(source),
+        describe(target)
+    );
+
+    match transfer(source, target, mode, dry_run) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("Unable to copy config: {}", error);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(value: serde_json::Value) -> LoadedConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn existing() -> LoadedConfig {
+        config(json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office"},
+                {"type": "KasaPlug", "name": "Heater", "ip": "192.168.0.11", "room": "office"}
+            ],
+            "presets": [{"name": "Evening", "enabled": true, "relays": {"DeskLamp": true}}]
+        }))
+    }
+
+    fn incoming() -> LoadedConfig {
+        config(json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.20", "room": "office"},
+                {"type": "KasaPlug", "name": "Kettle", "ip": "192.168.0.12", "room": "kitchen"}
+            ],
+            "presets": [
+                {"name": "Evening", "enabled": true, "relays": {"DeskLamp": true}},
+                {"name": "FullOff", "enabled": false, "relays": {}}
+            ]
+        }))
+    }
+
+    #[test]
+    fn test_merge_keeps_existing_entries() {
+        let (merged, [relays, presets, _]) =
+            transfer_config(&existing(), &incoming(), TransferMode::Merge);
+
+        assert_eq!(relays.added, vec!["Kettle"]);
+        assert_eq!(relays.updated, vec!["DeskLamp"]);
+        assert!(relays.removed.is_empty());
+        assert_eq!(presets, SectionChanges::default());
+
+        let names: Vec<&str> = merged
+            .relays
+            .iter()
+            .map(|relay| relay.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["DeskLamp", "Heater", "Kettle"]);
+        assert_eq!(merged.relays[0].ip, "192.168.0.20");
+        assert_eq!(merged.presets.len(), 1, "default presets aren't copied");
+    }
+
+    #[test]
+    fn test_overwrite_replaces_target() {
+        let (overwritten, [relays, _, _]) =
+            transfer_config(&existing(), &incoming(), TransferMode::Overwrite);
+
+        assert_eq!(relays.removed, vec!["Heater"]);
+        assert_eq!(overwritten.relays, incoming().relays);
+    }
+}
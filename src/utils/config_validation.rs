@@ -0,0 +1,520 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+
+use futures::executor::block_on;
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::drivers::ignored_options;
+use crate::models::automations::{AutomationAction, AutomationTrigger};
+use crate::models::config_models::ConfigRelay;
+use crate::models::relays::LightLevels;
+use crate::utils::automation_handling::parse_time;
+use crate::utils::load_config::{load_config, ConfigLocation, ConfigSettings};
+use crate::utils::local_config_utils::{parse_config, LoadedConfig};
+
+/// Presets that always exist, even when the config doesn't define them.
+const BUILT_IN_PRESETS: [&str; 2] = ["Custom", "FullOff"];
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub(crate) struct ConfigIssue {
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+}
+
+impl ConfigIssue {
+    pub(crate) fn error(message: String) -> Self {
+        ConfigIssue {
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    pub(crate) fn warning(message: String) -> Self {
+        ConfigIssue {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+fn relay_label(index: usize, relay: &ConfigRelay) -> String {
+    match relay.relay_names().first() {
+        Some(name) if !name.is_empty() => format!("Relay \"{}\"", name),
+        _ => format!("Relay #{} ({})", index + 1, relay.ip),
+    }
+}
+
+/// Checks that every relay in a config that hasn't been deserialized yet has a
+/// `type`, so a missing one is reported against its relay instead of failing
+/// the whole file. Unknown types are reported with the other relay checks.
+pub(crate) fn check_relay_types(raw_config: &Value) -> Vec<ConfigIssue> {
+    let mut issues: Vec<ConfigIssue> = Vec::new();
+    let relays = match raw_config.get("relays").and_then(Value::as_array) {
+        Some(relays) => relays,
+        None => return issues,
+    };
+
+    for (index, relay) in relays.iter().enumerate() {
+        match relay.get("type") {
+            Some(Value::String(_)) => {}
+            None => issues.push(ConfigIssue::error(format!(
+                "Relay #{} has no type",
+                index + 1
+            ))),
+            Some(relay_type) => issues.push(ConfigIssue::error(format!(
+                "Relay #{} has type {}, which isn't a type name",
+                index + 1,
+                relay_type
+            ))),
+        }
+    }
+
+    issues
+}
+
+fn check_relays(relays: &[ConfigRelay], issues: &mut Vec<ConfigIssue>) {
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for (index, relay) in relays.iter().enumerate() {
+        let label = relay_label(index, relay);
+        let Some(driver) = relay.relay_type.driver() else {
+            issues.push(ConfigIssue::error(format!(
+                "{} {}, it's skipped",
+                label,
+                relay.relay_type.unknown_message()
+            )));
+            continue;
+        };
+
+        if relay.ip.trim().is_empty() && driver.needs_ip() {
+            issues.push(ConfigIssue::error(format!("{} has no ip", label)));
+        }
+
+        driver.check(&label, relay, relays, issues);
+
+        for option in ignored_options(relay) {
+            issues.push(ConfigIssue::warning(format!(
+                "{} is a {}, its `{}` is ignored",
+                label, relay.relay_type, option
+            )));
+        }
+
+        for name in relay.relay_names() {
+            if !name.is_empty() && !seen.insert(name.clone()) {
+                issues.push(ConfigIssue::error(format!(
+                    "Relay name \"{}\" is used more than once, only the last one is kept",
+                    name
+                )));
+            }
+        }
+    }
+}
+
+fn check_presets(
+    config: &LoadedConfig,
+    relay_names: &HashSet<String>,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    let mut seen: HashSet<&str> = HashSet::new();
+
+    for preset in &config.presets {
+        if !seen.insert(&preset.name) {
+            issues.push(ConfigIssue::warning(format!(
+                "Preset \"{}\" is defined more than once, only the last one is kept",
+                preset.name
+            )));
+        }
+
+        let mut unknown: Vec<&String> = preset
+            .relays
+            .keys()
+            .filter(|relay| !relay_names.contains(*relay))
+            .collect();
+        unknown.sort();
+        for relay in unknown {
+            issues.push(ConfigIssue::warning(format!(
+                "Preset \"{}\" refers to unknown relay \"{}\"",
+                preset.name, relay
+            )));
+        }
+
+        let mut levels: Vec<(&String, &LightLevels)> = preset.levels.iter().collect();
+        levels.sort_by_key(|(relay, _)| *relay);
+        for (relay, relay_levels) in levels {
+            if preset.relays.get(relay) != Some(&true) {
+                issues.push(ConfigIssue::warning(format!(
+                    "Preset \"{}\" sets levels for \"{}\" without turning it on, they're ignored",
+                    preset.name, relay
+                )));
+            }
+            if let Err(error) = relay_levels.check() {
+                issues.push(ConfigIssue::error(format!(
+                    "Preset \"{}\" has invalid levels for \"{}\": {}",
+                    preset.name, relay, error
+                )));
+            }
+        }
+    }
+}
+
+fn check_trigger(
+    automation: &str,
+    trigger: &AutomationTrigger,
+    config: &LoadedConfig,
+    relay_names: &HashSet<String>,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    let selectors = [&trigger.relay, &trigger.tag, &trigger.room]
+        .iter()
+        .filter(|selector| selector.is_some())
+        .count();
+    if selectors != 1 {
+        issues.push(ConfigIssue::error(format!(
+            "Automation \"{}\" must set exactly one of `relay`, `tag` or `room` in `when`",
+            automation
+        )));
+    }
+
+    if let Some(relay) = &trigger.relay {
+        if !relay_names.contains(relay) {
+            issues.push(ConfigIssue::warning(format!(
+                "Automation \"{}\" watches unknown relay \"{}\"",
+                automation, relay
+            )));
+        }
+    }
+    if let Some(tag) = &trigger.tag {
+        if !config.relays.iter().any(|relay| relay.tags.contains(tag)) {
+            issues.push(ConfigIssue::warning(format!(
+                "Automation \"{}\" watches tag \"{}\" that no relay has",
+                automation, tag
+            )));
+        }
+    }
+    if let Some(room) = &trigger.room {
+        if !config.relays.iter().any(|relay| &relay.room == room) {
+            issues.push(ConfigIssue::warning(format!(
+                "Automation \"{}\" watches room \"{}\" that no relay is in",
+                automation, room
+            )));
+        }
+    }
+
+    for time in [&trigger.after, &trigger.before] {
+        if let Err(error) = parse_time(time) {
+            issues.push(ConfigIssue::error(format!(
+                "Automation \"{}\": {}",
+                automation, error
+            )));
+        }
+    }
+
+    if let Some(Err(error)) = trigger
+        .held_for
+        .as_ref()
+        .map(|held_for| held_for.to_duration())
+    {
+        issues.push(ConfigIssue::error(format!(
+            "Automation \"{}\": {}",
+            automation, error
+        )));
+    }
+}
+
+fn check_automations(
+    config: &LoadedConfig,
+    relay_names: &HashSet<String>,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    let mut seen: HashSet<&str> = HashSet::new();
+
+    for automation in &config.automations {
+        if !seen.insert(&automation.name) {
+            issues.push(ConfigIssue::warning(format!(
+                "Automation \"{}\" is defined more than once",
+                automation.name
+            )));
+        }
+
+        check_trigger(
+            &automation.name,
+            &automation.when,
+            config,
+            relay_names,
+            issues,
+        );
+
+        for action in &automation.then {
+            let unknown = match action {
+                AutomationAction::Relay { relay, .. } if !relay_names.contains(relay) => {
+                    Some(format!("unknown relay \"{}\"", relay))
+                }
+                AutomationAction::Tag { tag, .. }
+                    if !config.relays.iter().any(|relay| relay.tags.contains(tag)) =>
+                {
+                    Some(format!("tag \"{}\" that no relay has", tag))
+                }
+                AutomationAction::Preset { preset }
+                    if !BUILT_IN_PRESETS.contains(&preset.as_str())
+                        && !config.presets.iter().any(|known| &known.name == preset) =>
+                {
+                    Some(format!("unknown preset \"{}\"", preset))
+                }
+                _ => None,
+            };
+
+            if let Some(unknown) = unknown {
+                issues.push(ConfigIssue::warning(format!(
+                    "Automation \"{}\" sets {}",
+                    automation.name, unknown
+                )));
+            }
+        }
+    }
+}
+
+/// Statically checks a config for mistakes that would otherwise be silently
+/// ignored while loading.
+pub(crate) fn validate_config(config: &LoadedConfig) -> Vec<ConfigIssue> {
+    let mut issues: Vec<ConfigIssue> = Vec::new();
+    let relay_names: HashSet<String> = config
+        .relays
+        .iter()
+        .flat_map(|relay| relay.relay_names())
+        .collect();
+
+    check_relays(&config.relays, &mut issues);
+    check_presets(config, &relay_names, &mut issues);
+    check_automations(config, &relay_names, &mut issues);
+
+    issues
+}
+
+/// Connects to every configured relay, reporting the ones that don't answer
+/// and devices that don't match their entry, like multi-plugs whose `names`
+/// don't match their number of outlets.
+pub(crate) fn probe_relays(relays: &[ConfigRelay]) -> Vec<ConfigIssue> {
+    let probes = relays.iter().enumerate().filter_map(|(index, relay)| {
+        let driver = relay.relay_type.driver()?;
+        Some(async move {
+            let mut issues: Vec<ConfigIssue> = Vec::new();
+            driver
+                .probe(&relay_label(index, relay), relay, &mut issues)
+                .await;
+            issues
+        })
+    });
+
+    block_on(join_all(probes)).into_iter().flatten().collect()
+}
+
+/// Reads the config for validation. A local file is first read loosely so
+/// every relay without a type can be reported, in which case there is no
+/// config to check further.
+fn read_config_for_validation(
+    settings: &ConfigSettings,
+    issues: &mut Vec<ConfigIssue>,
+) -> Result<Option<LoadedConfig>, std::io::Error> {
+    if let ConfigLocation::LOCAL = settings.location {
+        let path = &settings.config_file;
+        let data = fs::read_to_string(path).map_err(|error| {
+            std::io::Error::new(
+                error.kind(),
+                format!("Couldn't read config file '{}': {}", path.display(), error),
+            )
+        })?;
+
+        issues.extend(check_relay_types(&parse_config::<Value>(path, &data)?));
+        if !issues.is_empty() {
+            return Ok(None);
+        }
+    }
+
+    load_config(settings)
+        .join()
+        .expect("Unable to join config thread")
+        .map(Some)
+}
+
+/// Runs `config validate`, printing a report. Returns whether the config is
+/// free of errors.
+pub(crate) fn run_validate_command(settings: &ConfigSettings, probe: bool) -> bool {
+    let source = match settings.location {
+        ConfigLocation::LOCAL => settings.config_file.display().to_string(),
+        ConfigLocation::SQLITE => settings.sqlite_path.clone(),
+        ConfigLocation::MONGODB => settings.mongo.database.clone(),
+    };
+    println!("Checking {} config from {}", settings.location, source);
+
+    let mut issues: Vec<ConfigIssue> = Vec::new();
+    match read_config_for_validation(settings, &mut issues) {
+        Ok(Some(config)) => {
+            issues.extend(validate_config(&config));
+            if probe {
+                issues.extend(probe_relays(&config.relays));
+            }
+        }
+        Ok(None) => {}
+        Err(error) => issues.push(ConfigIssue::error(error.to_string())),
+    }
+
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .count();
+    println!("{} error(s), {} warning(s)", errors, issues.len() - errors);
+
+    errors == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(value: Value) -> LoadedConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn messages(issues: &[ConfigIssue]) -> Vec<String> {
+        issues.iter().map(|issue| issue.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_config_has_no_issues() {
+        let loaded_config = config(json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office", "tags": ["lights"]},
+                {"type": "KasaMultiPlug", "names": ["Kettle", "Toaster"], "ip": "192.168.0.11", "room": "kitchen"}
+            ],
+            "presets": [{"name": "Breakfast", "enabled": true, "relays": {"Kettle": true}}],
+            "automations": [{
+                "name": "Lights out",
+                "when": {"tag": "lights", "state": true, "for": "2h", "after": "23:00"},
+                "then": [{"preset": "FullOff"}]
+            }]
+        }));
+
+        assert!(validate_config(&loaded_config).is_empty());
+    }
+
+    #[test]
+    fn test_reports_config_mistakes() {
+        let loaded_config = config(json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office"},
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.12", "room": "office"},
+                {"type": "KasaMultiPlug", "name": "Strip", "ip": "192.168.0.11", "room": "kitchen"}
+            ],
+            "presets": [{
+                "name": "Evening",
+                "enabled": true,
+                "relays": {"DeskLmp": true, "DeskLamp": false},
+                "levels": {"DeskLamp": {"brightness": 140}}
+            }],
+            "automations": [{
+                "name": "Typo",
+                "when": {"relay": "DeskLamp", "room": "office", "state": true, "after": "25:00"},
+                "then": [{"relay": "Heater", "set": false}, {"preset": "Night"}]
+            }]
+        }));
+
+        assert_eq!(
+            messages(&validate_config(&loaded_config)),
+            vec![
+                "error: Relay name \"DeskLamp\" is used more than once, only the last one is kept",
+                "error: Relay #3 (192.168.0.11) is a KasaMultiPlug but lists no `names`",
+                "warning: Relay #3 (192.168.0.11) is a KasaMultiPlug, its `name` is ignored in favour of `names`",
+                "warning: Preset \"Evening\" refers to unknown relay \"DeskLmp\"",
+                "warning: Preset \"Evening\" sets levels for \"DeskLamp\" without turning it on, they're ignored",
+                "error: Preset \"Evening\" has invalid levels for \"DeskLamp\": brightness 140 is out of range, it goes from 0 to 100",
+                "error: Automation \"Typo\" must set exactly one of `relay`, `tag` or `room` in `when`",
+                "error: Automation \"Typo\": Invalid time of day: 25:00",
+                "warning: Automation \"Typo\" sets unknown relay \"Heater\"",
+                "warning: Automation \"Typo\" sets unknown preset \"Night\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_relay_type() {
+        let issues = check_relay_types(&json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office"},
+                {"name": "Toaster", "ip": "192.168.0.12", "room": "kitchen"},
+                {"type": 3, "name": "Kettle", "ip": "192.168.0.13", "room": "kitchen"}
+            ]
+        }));
+
+        assert_eq!(
+            messages(&issues),
+            vec![
+                "error: Relay #2 has no type",
+                "error: Relay #3 has type 3, which isn't a type name"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_relay_type_is_skipped() {
+        let loaded_config = config(json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office"},
+                {"type": "Toaster", "name": "Toaster", "ip": "192.168.0.12", "room": "kitchen"}
+            ],
+            "presets": []
+        }));
+
+        let issues = messages(&validate_config(&loaded_config));
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with(
+            "error: Relay \"Toaster\" has unknown type \"Toaster\", expected one of KasaPlug"
+        ));
+        assert!(issues[0].ends_with(", it's skipped"));
+    }
+
+    #[cfg(feature = "tasmota")]
+    #[test]
+    fn test_options_a_driver_doesnt_read_are_reported() {
+        let loaded_config = config(json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office", "commandTopic": "desk/set"},
+                {"type": "Tasmota", "name": "Heater", "ip": "192.168.0.12", "room": "office", "channel": 2, "generation": 2},
+                {"type": "Tasmota", "name": "Fan", "ip": "192.168.0.13", "room": "office", "channel": "two"}
+            ],
+            "presets": []
+        }));
+
+        assert_eq!(
+            messages(&validate_config(&loaded_config)),
+            vec![
+                "warning: Relay \"DeskLamp\" is a KasaPlug, its `commandTopic` is ignored",
+                "warning: Relay \"Heater\" is a Tasmota, its `generation` is ignored",
+                "error: Relay \"Fan\" has invalid options: invalid type: string \"two\", expected u8",
+            ]
+        );
+    }
+}
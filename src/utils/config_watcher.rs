@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use rocket::log;
+
+use crate::models::data_thread_models::DataThreadCommand;
+use crate::utils::load_config::WatchGuard;
+use crate::utils::local_config_utils::load_config_from_file;
+
+/// How long the file has to stay quiet before it is read, so editors that
+/// write in several steps only trigger one reload.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+fn is_config_event(event: &notify::Result<Event>, file_name: &Path) -> bool {
+    match event {
+        Ok(event) => event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == file_name.file_name()),
+        Err(error) => {
+            log::warn_!("Config watcher error: {}", error);
+            false
+        }
+    }
+}
+
+/// Watches the local config file and sends the parsed config to the data
+/// thread whenever it changes. A file that fails to parse is logged and the
+/// running config is kept. `watched` is set until the watcher stops.
+pub(crate) fn setup_config_watcher(
+    path: PathBuf,
+    route_to_data_sender: Sender<DataThreadCommand>,
+    watched: &Arc<AtomicBool>,
+) -> notify::Result<JoinHandle<()>> {
+    let (event_sender, event_receiver) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = RecommendedWatcher::new(event_sender, notify::Config::default())?;
+
+    // Watch the directory rather than the file, editors often replace the file
+    // on save which would end a watch on the file itself.
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    let guard = WatchGuard::new(watched);
+    Ok(thread::spawn(move || {
+        // Keep the watcher alive for as long as the thread runs
+        let _watcher = watcher;
+        let _guard = guard;
+
+        while let Ok(event) = event_receiver.recv() {
+            if !is_config_event(&event, &path) {
+                continue;
+            }
+
+            loop {
+                match event_receiver.recv_timeout(DEBOUNCE) {
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+
+            match load_config_from_file(&path) {
+                Ok(loaded_config) => {
+                    if route_to_data_sender
+                        .send(DataThreadCommand::ConfigReload(loaded_config))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(error) => {
+                    log::warn_!("Keeping current config, {}", error);
+                }
+            }
+        }
+    }))
+}
@@ -1,20 +1,32 @@
 use std::collections::{HashMap, HashSet};
 
 use crate::models::{
+    config_models::restore_policies,
     data_thread_models::{
-        DataThreadCommand, DataThreadResponse, PresetCommand, RelayCommand, RelayCommands,
-        TagCommand,
+        ChangeSource, DataThreadCommand, DataThreadResponse, HistoryCommand, PresetCommand,
+        RelayCommand, RelayCommands, RelayStateChange, TagCommand,
     },
     presets::{get_preset_names, set_preset, Preset},
-    relays::{config_equals, RelayActions, RelayType},
+    relays::{expand_groups, set_relay, set_relay_levels, update_groups, RelayType},
 };
 
-use crate::utils::load_config::{load_config, ConfigLocation};
+use crate::utils::automation_handling::{AutomationEngine, AutomationRun};
+use crate::utils::config_diff::{apply_preset_diff, apply_relay_diff, diff_relays, ConfigDiff};
+use crate::utils::config_validation::{validate_config, ConfigIssue, Severity};
+use crate::utils::history_store::HistoryStore;
+use crate::utils::load_config::{load_config, ConfigSettings};
+use crate::utils::local_config_utils::{build_config, load_presets, load_relays, LoadedConfig};
+use crate::utils::mqtt_bridge::MqttBridge;
+use crate::utils::mqtt_network_functions;
+use crate::utils::relay_state_file::RelayStateFile;
 
+use chrono::Local;
 use rocket::form::validate::Contains;
+use rocket::log;
 use rocket::serde::json::Json;
 use serde_json::{json, Value};
 use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -41,30 +53,140 @@ pub(crate) fn unwrap_response(package: DataThreadResponse) -> Json<Value> {
     }
 }
 
+pub(crate) fn relay_states(relays: &HashMap<String, RelayType>) -> HashMap<String, bool> {
+    relays
+        .iter()
+        .map(|(name, relay)| (name.clone(), relay.status()))
+        .collect()
+}
+
+pub(crate) fn state_changes(
+    before: &HashMap<String, bool>,
+    relays: &HashMap<String, RelayType>,
+) -> Vec<RelayStateChange> {
+    relays
+        .iter()
+        .filter_map(|(name, relay)| {
+            let previous = before.get(name).copied();
+            if previous == Some(relay.status()) {
+                return None;
+            }
+            Some(RelayStateChange {
+                name: name.clone(),
+                previous,
+                current: relay.status(),
+            })
+        })
+        .collect()
+}
+
+/// Where relay changes caused by a command came from, for the history log.
+fn command_origin(command: &DataThreadCommand) -> (ChangeSource, Option<IpAddr>) {
+    match command {
+        DataThreadCommand::Relay(RelayCommand {
+            command: RelayCommands::STATUS,
+            ..
+        })
+        | DataThreadCommand::Tag(TagCommand {
+            command: RelayCommands::STATUS,
+            ..
+        }) => (ChangeSource::Poller, None),
+        DataThreadCommand::Relay(relay_command) => (ChangeSource::Api, relay_command.client_ip),
+        DataThreadCommand::Tag(tag_command) => (ChangeSource::Api, tag_command.client_ip),
+        DataThreadCommand::Preset(PresetCommand::Set(_, client_ip)) => {
+            (ChangeSource::Preset, *client_ip)
+        }
+        DataThreadCommand::Refresh
+        | DataThreadCommand::AutoRefresh
+        | DataThreadCommand::ConfigReload(_)
+        | DataThreadCommand::MqttState => (ChangeSource::ExternalDrift, None),
+        DataThreadCommand::Mqtt(_) => (ChangeSource::Mqtt, None),
+        _ => (ChangeSource::Poller, None),
+    }
+}
+
+fn record_changes(
+    history: &Option<HistoryStore>,
+    changes: &[RelayStateChange],
+    source: ChangeSource,
+    client_ip: Option<IpAddr>,
+) {
+    if let Some(history) = history {
+        if let Err(error) = history.record_changes(changes, source, client_ip) {
+            log::warn_!("Unable to record relay history: {}", error);
+        }
+    }
+}
+
+fn record_preset(
+    history: &Option<HistoryStore>,
+    preset: &str,
+    applied: bool,
+    source: ChangeSource,
+    client_ip: Option<IpAddr>,
+) {
+    if let Some(history) = history {
+        if let Err(error) = history.record_preset(preset, applied, source, client_ip) {
+            log::warn_!("Unable to record preset history: {}", error);
+        }
+    }
+}
+
+fn record_automation_run(history: &Option<HistoryStore>, run: &AutomationRun) {
+    record_changes(history, &run.changes, ChangeSource::Automation, None);
+    for (preset, applied) in &run.presets {
+        record_preset(history, preset, *applied, ChangeSource::Automation, None);
+    }
+}
+
 fn handle_relay_command(
     relay_command: RelayCommand,
     relays: &mut HashMap<String, RelayType>,
     current_preset: &Mutex<String>,
 ) -> Result<DataThreadResponse, Error> {
-    if let Some(relay) = relays.get_mut(&relay_command.name) {
-        match relay_command.command {
-            RelayCommands::SWITCH | RelayCommands::TRUE | RelayCommands::FALSE => {
-                let mut temp_current_preset = current_preset.lock().unwrap();
-                *temp_current_preset = "Custom".to_string()
-            }
-            _ => {}
+    let unknown = || {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("Unknown relay: {}", relay_command.name),
+        )
+    };
+    if !relays.contains_key(&relay_command.name) {
+        return Err(unknown());
+    }
+
+    match relay_command.command {
+        RelayCommands::SWITCH
+        | RelayCommands::TRUE
+        | RelayCommands::FALSE
+        | RelayCommands::LEVELS(_) => {
+            let mut temp_current_preset = current_preset.lock().unwrap();
+            *temp_current_preset = "Custom".to_string()
         }
+        _ => {}
+    }
 
-        match relay_command.command {
-            RelayCommands::SWITCH => Ok(DataThreadResponse::Value(relay.switch()?)),
-            RelayCommands::TRUE => Ok(DataThreadResponse::Value(relay.turn_on()?)),
-            RelayCommands::FALSE => Ok(DataThreadResponse::Value(relay.turn_off()?)),
-            RelayCommands::STATUS => Ok(DataThreadResponse::Value(
+    let name = &relay_command.name;
+    match relay_command.command {
+        RelayCommands::SWITCH => Ok(DataThreadResponse::Value(set_relay(relays, name, None)?)),
+        RelayCommands::TRUE => Ok(DataThreadResponse::Value(set_relay(
+            relays,
+            name,
+            Some(true),
+        )?)),
+        RelayCommands::FALSE => Ok(DataThreadResponse::Value(set_relay(
+            relays,
+            name,
+            Some(false),
+        )?)),
+        RelayCommands::LEVELS(levels) => Ok(DataThreadResponse::Value(set_relay_levels(
+            relays, name, levels,
+        )?)),
+        RelayCommands::STATUS => match relays.get_mut(name) {
+            Some(relay) => Ok(DataThreadResponse::Value(
                 json!({"status": relay.get_status()?}),
             )),
-        }
-    } else {
-        Ok(DataThreadResponse::Bool(false))
+            None => Err(unknown()),
+        },
     }
 }
 
@@ -74,46 +196,65 @@ fn handle_tag_command(
     current_preset: &Mutex<String>,
 ) -> Result<DataThreadResponse, Error> {
     match tag_command.command {
-        RelayCommands::SWITCH | RelayCommands::TRUE | RelayCommands::FALSE => {
+        RelayCommands::SWITCH
+        | RelayCommands::TRUE
+        | RelayCommands::FALSE
+        | RelayCommands::LEVELS(_) => {
             let mut temp_current_preset = current_preset.lock().unwrap();
             *temp_current_preset = "Custom".to_string()
         }
         _ => {}
     }
 
-    let mut statuses: Vec<Value> = Vec::new();
+    let tagged: Vec<String> = relays
+        .iter()
+        .filter(|(_, relay)| relay.tags().contains(&tag_command.tag))
+        .map(|(name, _)| name.clone())
+        .collect();
+    let statuses: Vec<Value> = tagged
+        .iter()
+        .map(|name| json!({"status": relays[name].to_json()}))
+        .collect();
 
-    let mut found = false;
-    for relay in relays.values_mut().filter(|relay| {
-        let tags = match relay {
-            RelayType::KasaPlug(ref relay) => &relay.tags,
-            RelayType::KasaMultiPlug(ref relay) => &relay.tags,
-        };
-        tags.contains(&tag_command.tag)
-    }) {
-        found = true;
+    if tagged.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("No relays with tag: {} found", &tag_command.tag),
+        ));
+    }
+
+    // A tagged group switches its members, even ones without the tag
+    let targets = expand_groups(relays, &tagged);
+    if let RelayCommands::LEVELS(_) = tag_command.command {
+        if !targets.iter().any(|name| relays[name].levels().is_some()) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("No dimmable relays with tag: {}", &tag_command.tag),
+            ));
+        }
+    }
 
-        let _ = match tag_command.command {
+    for name in targets {
+        match tag_command.command {
             RelayCommands::SWITCH => {
-                let _ = relay.switch()?;
+                let _ = set_relay(relays, &name, None)?;
             }
             RelayCommands::TRUE => {
-                let _ = relay.turn_on()?;
+                let _ = set_relay(relays, &name, Some(true))?;
             }
             RelayCommands::FALSE => {
-                let _ = relay.turn_off()?;
+                let _ = set_relay(relays, &name, Some(false))?;
             }
-            RelayCommands::STATUS => statuses.push(json!({"status": relay.to_json()})),
+            // Relays without a brightness keep their state
+            RelayCommands::LEVELS(levels) => {
+                if relays[&name].levels().is_some() {
+                    let _ = set_relay_levels(relays, &name, levels)?;
+                }
+            }
+            RelayCommands::STATUS => break,
         };
     }
 
-    if !found {
-        return Err(Error::new(
-            ErrorKind::NotFound,
-            format!("No relays with tag: {} found", &tag_command.tag),
-        ));
-    }
-
     match tag_command.command {
         RelayCommands::STATUS => Ok(DataThreadResponse::Value(Value::from(statuses))),
         _ => Ok(DataThreadResponse::Bool(true)),
@@ -131,11 +272,15 @@ fn handle_preset_command(
             Ok(response) => Ok(DataThreadResponse::Value(Value::Array(response))),
             Err(error) => Err(error),
         },
-        PresetCommand::Set(preset_name) => match presets.get_mut(&preset_name) {
+        PresetCommand::Set(preset_name, _) => match presets.get_mut(&preset_name) {
             Some(preset) => match set_preset(preset, relays) {
                 Ok(value) => {
+                    // A preset that only partly applied leaves a custom state
                     let mut temp_current_preset = current_preset.lock().unwrap();
-                    *temp_current_preset = preset.name.clone().to_string();
+                    *temp_current_preset = match value["presetSet"] == true {
+                        true => preset.name.clone(),
+                        false => "Custom".to_string(),
+                    };
                     Ok(DataThreadResponse::Value(value))
                 }
                 Err(error) => Err(error),
@@ -145,11 +290,30 @@ fn handle_preset_command(
     }
 }
 
+fn handle_history_command(
+    history_command: HistoryCommand,
+    history: &Option<HistoryStore>,
+) -> Result<DataThreadResponse, Error> {
+    let history = match history {
+        Some(history) => history,
+        None => return Err(Error::new(ErrorKind::Unsupported, "History is not enabled")),
+    };
+
+    let result = match history_command {
+        HistoryCommand::Relays(query) => history.relay_history(&query),
+        HistoryCommand::Presets(query) => history.preset_history(&query),
+    };
+
+    result.map(DataThreadResponse::Value).map_err(Error::other)
+}
+
 fn handle_command(
     received: DataThreadCommand,
     relays: &mut HashMap<String, RelayType>,
     presets: &mut HashMap<String, Preset>,
     current_preset: &Mutex<String>,
+    automations: &AutomationEngine,
+    history: &Option<HistoryStore>,
 ) -> Result<DataThreadResponse, Error> {
     match received {
         DataThreadCommand::Relay(relay_command) => {
@@ -165,8 +329,25 @@ fn handle_command(
         DataThreadCommand::Tag(tag_command) => {
             handle_tag_command(tag_command, relays, current_preset)
         }
+        DataThreadCommand::Automations => Ok(DataThreadResponse::Value(automations.to_json())),
+        DataThreadCommand::History(history_command) => {
+            handle_history_command(history_command, history)
+        }
         DataThreadCommand::Refresh => Ok(DataThreadResponse::Bool(false)),
         DataThreadCommand::AutoRefresh => Ok(DataThreadResponse::Bool(false)),
+        DataThreadCommand::ConfigReload(_) => Ok(DataThreadResponse::Bool(false)),
+        DataThreadCommand::MqttState => {
+            read_mqtt_states(relays);
+            Ok(DataThreadResponse::Bool(true))
+        }
+        DataThreadCommand::Mqtt(mqtt_command) => handle_command(
+            mqtt_command.into_command(),
+            relays,
+            presets,
+            current_preset,
+            automations,
+            history,
+        ),
     }
 }
 
@@ -181,10 +362,7 @@ pub(crate) fn get_status(
     for (_, relay) in relays.iter() {
         relay_statuses.push(relay.to_json());
 
-        rooms.insert(match relay {
-            RelayType::KasaPlug(plug) => plug.room.clone(),
-            RelayType::KasaMultiPlug(plug) => plug.room.clone(),
-        });
+        rooms.insert(relay.room().clone());
     }
 
     result["relays"] = Value::Array(relay_statuses);
@@ -210,75 +388,600 @@ fn setup_update_thread(
     })
 }
 
+/// Consumers of relay state changes owned by the data thread.
+struct ChangeListeners {
+    automations: AutomationEngine,
+    history: Option<HistoryStore>,
+    state_file: Option<RelayStateFile>,
+    mqtt_bridge: Option<MqttBridge>,
+}
+
+impl ChangeListeners {
+    /// Hands the changes made since `before` to the history log and the
+    /// automations, then saves the desired relay states and mirrors them to
+    /// the MQTT bridge.
+    fn settle(
+        &mut self,
+        before: &HashMap<String, bool>,
+        relays: &mut HashMap<String, RelayType>,
+        presets: &HashMap<String, Preset>,
+        current_preset: &Mutex<String>,
+        source: ChangeSource,
+        client_ip: Option<IpAddr>,
+    ) {
+        update_groups(relays);
+        let changes = state_changes(before, relays);
+        record_changes(&self.history, &changes, source, client_ip);
+
+        self.automations.observe(&changes);
+        let run = self.automations.run(relays, presets, current_preset);
+        record_automation_run(&self.history, &run);
+
+        if let Some(state_file) = self.state_file.as_mut() {
+            let mut desired: Vec<RelayStateChange> = match source {
+                ChangeSource::Api | ChangeSource::Preset | ChangeSource::Mqtt => changes,
+                _ => Vec::new(),
+            };
+            desired.extend(run.changes);
+            state_file.update(&desired, &current_preset.lock().unwrap());
+        }
+
+        if let Some(mqtt_bridge) = &self.mqtt_bridge {
+            mqtt_bridge.publish_changes(relays, presets, &current_preset.lock().unwrap());
+        }
+    }
+}
+
+/// Applies a freshly read config as a per-relay diff, so relays whose entry
+/// didn't change keep their connection and cached status.
+fn apply_loaded_config(
+    loaded_config: LoadedConfig,
+    relays: &mut HashMap<String, RelayType>,
+    presets: &mut HashMap<String, Preset>,
+    automations: &mut AutomationEngine,
+) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+
+    let relay_diff = diff_relays(relays, loaded_config.relays);
+    apply_relay_diff(relays, relay_diff, load_relays, &mut diff);
+    apply_preset_diff(presets, load_presets(loaded_config.presets), &mut diff);
+
+    if *automations.automations() != loaded_config.automations {
+        automations.set_automations(loaded_config.automations, relays);
+        diff.automations_changed = true;
+    }
+
+    diff
+}
+
+/// Applies a reloaded config unless validating it found errors, in which case
+/// the running relays, presets and automations are kept and the errors are
+/// returned. Warnings don't stop a reload.
+fn apply_reloaded_config(
+    loaded_config: LoadedConfig,
+    issues: &[ConfigIssue],
+    relays: &mut HashMap<String, RelayType>,
+    presets: &mut HashMap<String, Preset>,
+    automations: &mut AutomationEngine,
+) -> Result<ConfigDiff, Vec<ConfigIssue>> {
+    let errors: Vec<ConfigIssue> = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .cloned()
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(apply_loaded_config(
+        loaded_config,
+        relays,
+        presets,
+        automations,
+    ))
+}
+
+/// Re-reads the status of every relay so changes made outside the server are
+/// picked up. Relays that stop answering stay in the registry with their last
+/// known status, flagged unreachable until a later poll reaches them again.
+fn poll_relay_statuses(relays: &mut HashMap<String, RelayType>) {
+    for (name, relay) in relays.iter_mut() {
+        let was_reachable = relay.reachable();
+        match relay.get_status() {
+            Ok(_) if !was_reachable => log::info_!("Relay {} is reachable again", name),
+            Err(error) if was_reachable => {
+                log::warn_!("Lost connection to relay {}: {}", name, error)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Takes the status of MQTT relays from the state messages they have
+/// received, which doesn't wait on their devices.
+fn read_mqtt_states(relays: &mut HashMap<String, RelayType>) {
+    for relay in relays.values_mut() {
+        if relay.driver() == "Mqtt" {
+            let _ = relay.get_status();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn setup_data_thread(
     sender: Sender<DataThreadResponse>,
     receiver: Receiver<DataThreadCommand>,
     route_to_data_sender: Sender<DataThreadCommand>,
-    config_location: ConfigLocation,
+    config_settings: ConfigSettings,
+    history: Option<HistoryStore>,
+    mut state_file: Option<RelayStateFile>,
+    refresh_interval: u64,
+    mqtt_bridge: Option<MqttBridge>,
 ) -> JoinHandle<()> {
-    let loaded_config = load_config(config_location)
+    let loaded_config = load_config(&config_settings)
         .join()
         .expect("Could not set up thread")
-        .unwrap();
+        .unwrap_or_else(|error| {
+            eprintln!("Unable to load config: {}", error);
+            std::process::exit(1);
+        });
+
+    let mut config_issues = validate_config(&loaded_config);
+    for issue in &config_issues {
+        eprintln!("Config {}", issue);
+    }
 
     thread::spawn(move || {
-        let relays = Arc::new(Mutex::new(loaded_config.relays));
+        let mut applied_config = loaded_config.clone();
+        let loaded_config = build_config(loaded_config);
+        let mut loaded_relays = loaded_config.relays;
+        update_groups(&mut loaded_relays);
+        let before = relay_states(&loaded_relays);
+        let restored_preset = match state_file.as_mut() {
+            Some(state_file) => {
+                state_file.restore(&mut loaded_relays, &loaded_config.restore_policies)
+            }
+            None => "Custom".to_string(),
+        };
+        update_groups(&mut loaded_relays);
+        record_changes(
+            &history,
+            &state_changes(&before, &loaded_relays),
+            ChangeSource::Restore,
+            None,
+        );
+
+        let relays = Arc::new(Mutex::new(loaded_relays));
         let presets = Arc::new(Mutex::new(loaded_config.presets));
-        let current_preset = Arc::new(Mutex::new("Custom".to_string()));
+        let current_preset = Arc::new(Mutex::new(restored_preset));
+        let mut listeners = ChangeListeners {
+            automations: AutomationEngine::new(
+                loaded_config.automations,
+                &relays.lock().expect("Failed to lock relays"),
+            ),
+            history,
+            state_file,
+            mqtt_bridge,
+        };
+        if let Some(mqtt_bridge) = &listeners.mqtt_bridge {
+            mqtt_bridge.publish_changes(
+                &relays.lock().expect("Failed to lock relays"),
+                &presets.lock().expect("Failed to lock presets"),
+                &current_preset.lock().unwrap(),
+            );
+        }
+        let mut last_reload: Option<Value> = None;
+        let mut rejected_errors: Vec<ConfigIssue> = Vec::new();
 
-        setup_update_thread(route_to_data_sender.clone(), 10);
+        setup_update_thread(route_to_data_sender.clone(), refresh_interval);
+        mqtt_network_functions::notify_states(route_to_data_sender.clone());
 
         for received in receiver {
             match received {
-                DataThreadCommand::Refresh | DataThreadCommand::AutoRefresh => {
-                    match load_config(config_location)
-                        .join()
-                        .expect("Unable to join config thread")
-                    {
-                        Ok(config) => {
+                DataThreadCommand::Refresh
+                | DataThreadCommand::AutoRefresh
+                | DataThreadCommand::ConfigReload(_) => {
+                    let respond = matches!(received, DataThreadCommand::Refresh);
+                    let poll = matches!(received, DataThreadCommand::AutoRefresh);
+                    let loaded_config = match received {
+                        DataThreadCommand::ConfigReload(loaded_config) => Ok(loaded_config),
+                        // A watcher already delivers config changes, so only
+                        // retry the relays that were unreachable
+                        DataThreadCommand::AutoRefresh if config_settings.watched() => {
+                            Ok(applied_config.clone())
+                        }
+                        _ => load_config(&config_settings)
+                            .join()
+                            .expect("Unable to join config thread"),
+                    };
+
+                    match loaded_config {
+                        Ok(loaded_config) => {
                             let mut relays = relays.lock().expect("Failed to lock relays");
                             let mut presets = presets.lock().expect("Failed to lock presets");
+                            let before = relay_states(&relays);
+                            let issues = validate_config(&loaded_config);
+
+                            let diff = match apply_reloaded_config(
+                                loaded_config.clone(),
+                                &issues,
+                                &mut relays,
+                                &mut presets,
+                                &mut listeners.automations,
+                            ) {
+                                Ok(diff) => {
+                                    config_issues = issues;
+                                    applied_config = loaded_config;
+                                    rejected_errors.clear();
+
+                                    if let Some(mqtt_bridge) = &listeners.mqtt_bridge {
+                                        mqtt_bridge.forget_relays(&diff.relays_removed);
+                                    }
+                                    if diff.has_changes() {
+                                        let event = json!({
+                                            "timestamp": Local::now().to_rfc3339(),
+                                            "changes": diff,
+                                        });
+                                        log::info_!("Config reloaded: {}", event);
+                                        for issue in &config_issues {
+                                            log::warn_!("Config {}", issue);
+                                        }
+                                        last_reload = Some(event);
+                                    }
+                                    diff
+                                }
+                                Err(errors) => {
+                                    // A config that keeps failing is only reported once
+                                    if errors != rejected_errors {
+                                        let event = json!({
+                                            "timestamp": Local::now().to_rfc3339(),
+                                            "rejected": true,
+                                            "errors": errors,
+                                        });
+                                        log::warn_!("Config reload rejected: {}", event);
+                                        last_reload = Some(event);
+                                        rejected_errors = errors;
+                                    }
+                                    ConfigDiff::default()
+                                }
+                            };
 
-                            if !config_equals::<RelayType>(&*relays, &config.relays) {
-                                *relays = config.relays;
+                            if poll {
+                                poll_relay_statuses(&mut relays);
                             }
 
-                            if !config_equals::<Preset>(&*presets, &config.presets) {
-                                *presets = config.presets;
+                            listeners.settle(
+                                &before,
+                                &mut relays,
+                                &presets,
+                                &current_preset,
+                                ChangeSource::ExternalDrift,
+                                None,
+                            );
+
+                            // Relays that only came up now still get their restore policy
+                            if let Some(state_file) = listeners.state_file.as_mut() {
+                                let before = relay_states(&relays);
+                                state_file.restore_pending(
+                                    &mut relays,
+                                    &restore_policies(&applied_config.relays),
+                                );
+                                if !state_changes(&before, &relays).is_empty() {
+                                    listeners.settle(
+                                        &before,
+                                        &mut relays,
+                                        &presets,
+                                        &current_preset,
+                                        ChangeSource::Restore,
+                                        None,
+                                    );
+                                }
                             }
 
-                            match received {
-                                DataThreadCommand::Refresh => sender
-                                    .send(DataThreadResponse::Bool(true))
-                                    .expect("Channel possibly not open"),
-                                _ => {}
+                            if let Some(history) = listeners.history.as_mut() {
+                                if let Err(error) = history.prune_if_due() {
+                                    log::warn_!("Unable to prune history: {}", error);
+                                }
+                            }
+
+                            if respond {
+                                let response = match rejected_errors.is_empty() {
+                                    true => DataThreadResponse::Value(json!({
+                                        "refresh": true,
+                                        "changes": diff,
+                                    })),
+                                    false => DataThreadResponse::Error(format!(
+                                        "Config has errors, keeping the running config: {}",
+                                        rejected_errors
+                                            .iter()
+                                            .map(|issue| issue.message.as_str())
+                                            .collect::<Vec<_>>()
+                                            .join("; ")
+                                    )),
+                                };
+                                sender.send(response).expect("Channel possibly not open")
                             }
                         }
-                        Err(_) => {
-                            sender
-                                .send(DataThreadResponse::Error(
-                                    "Could not refresh config".to_string(),
-                                ))
-                                .expect("Channel possibly not open");
+                        Err(error) => {
+                            log::warn_!("Unable to refresh config: {}", error);
+                            if respond {
+                                sender
+                                    .send(DataThreadResponse::Error(
+                                        "Could not refresh config".to_string(),
+                                    ))
+                                    .expect("Channel possibly not open");
+                            }
                         }
                     }
                 }
                 _ => {
                     let mut relays = relays.lock().expect("Failed to lock relays");
                     let mut presets = presets.lock().expect("Failed to lock presets");
+                    let before = relay_states(&relays);
+                    let (source, client_ip) = command_origin(&received);
+                    let respond = !matches!(
+                        received,
+                        DataThreadCommand::Mqtt(_) | DataThreadCommand::MqttState
+                    );
+                    let received = match received {
+                        DataThreadCommand::Mqtt(mqtt_command) => mqtt_command.into_command(),
+                        received => received,
+                    };
+                    let is_status = matches!(received, DataThreadCommand::SystemStatus);
+                    let preset_name = match &received {
+                        DataThreadCommand::Preset(PresetCommand::Set(name, _))
+                            if presets.contains_key(name) =>
+                        {
+                            Some(name.clone())
+                        }
+                        _ => None,
+                    };
 
-                    let response =
-                        handle_command(received, &mut *relays, &mut *presets, &current_preset)
-                            .unwrap_or_else(|error| DataThreadResponse::Error(error.to_string()));
+                    let mut response = handle_command(
+                        received,
+                        &mut relays,
+                        &mut presets,
+                        &current_preset,
+                        &listeners.automations,
+                        &listeners.history,
+                    )
+                    .unwrap_or_else(|error| DataThreadResponse::Error(error.to_string()));
 
-                    match response {
-                        DataThreadResponse::Error(error) => {
-                            eprintln!("Error sending command: {:?}", &error);
-                        }
-                        _ => sender.send(response).expect("Channel possibly not open"),
+                    if let Some(preset_name) = preset_name {
+                        let applied = matches!(
+                            &response,
+                            DataThreadResponse::Value(value) if value["presetSet"] == true
+                        );
+                        record_preset(&listeners.history, &preset_name, applied, source, client_ip);
+                    }
+
+                    if let (true, DataThreadResponse::Value(status)) = (is_status, &mut response) {
+                        status["lastConfigReload"] = last_reload.clone().unwrap_or(Value::Null);
+                        status["configWarnings"] = json!(config_issues);
+                    }
+
+                    listeners.settle(
+                        &before,
+                        &mut relays,
+                        &presets,
+                        &current_preset,
+                        source,
+                        client_ip,
+                    );
+
+                    if let DataThreadResponse::Error(error) = &response {
+                        log::warn_!("Error sending command: {}", error);
+                    }
+                    if respond {
+                        sender.send(response).expect("Channel possibly not open");
                     }
                 }
             }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::kasa::KasaPlug;
+    use crate::drivers::virtual_relay::VirtualRelay;
+    use crate::models::relays::{LightLevels, TrackedRelay};
+    use crate::utils::local_config_utils::parse_config;
+    use std::path::Path;
+
+    #[test]
+    fn test_reloads_with_errors_keep_the_running_config() {
+        let mut relays: HashMap<String, RelayType> = HashMap::from([(
+            "Scene".to_string(),
+            TrackedRelay::track(Box::new(VirtualRelay::new(
+                "Scene".to_string(),
+                "office".to_string(),
+                vec![],
+            ))),
+        )]);
+        let mut presets: HashMap<String, Preset> = HashMap::new();
+        let mut automations = AutomationEngine::new(vec![], &relays);
+
+        let loaded_config: LoadedConfig = parse_config(
+            Path::new("config.json"),
+            r#"{"relays": [
+                {"type": "Virtual", "name": "Fan", "room": "office"},
+                {"type": "Virtual", "name": "Fan", "room": "bedroom"}
+            ], "presets": []}"#,
+        )
+        .unwrap();
+        let issues = validate_config(&loaded_config);
+
+        let errors = apply_reloaded_config(
+            loaded_config,
+            &issues,
+            &mut relays,
+            &mut presets,
+            &mut automations,
+        )
+        .unwrap_err();
+        assert!(errors[0].message.contains("\"Fan\" is used more than once"));
+        assert_eq!(relays.keys().collect::<Vec<_>>(), vec!["Scene"]);
+    }
+
+    #[test]
+    fn test_unreachable_relays_stay_in_the_registry() {
+        // Nothing listens on the Kasa port of localhost
+        let mut plug = KasaPlug::new(
+            "127.0.0.1".to_string(),
+            "DeskLamp".to_string(),
+            "office".to_string(),
+            vec![],
+        );
+        plug.status = true;
+        let mut relays: HashMap<String, RelayType> =
+            HashMap::from([("DeskLamp".to_string(), TrackedRelay::track(Box::new(plug)))]);
+
+        poll_relay_statuses(&mut relays);
+        poll_relay_statuses(&mut relays);
+
+        let relay = &relays["DeskLamp"];
+        assert!(!relay.reachable());
+        assert!(relay.status());
+        assert_eq!(relay.to_json()["reachable"], json!(false));
+        assert_eq!(relay.to_json()["status"], json!(true));
+    }
+
+    #[test]
+    fn test_presets_report_the_relays_they_fail_to_set() {
+        let mut relays: HashMap<String, RelayType> = HashMap::from([
+            (
+                "Fan".to_string(),
+                Box::new(VirtualRelay::new(
+                    "Fan".to_string(),
+                    "office".to_string(),
+                    vec![],
+                )) as RelayType,
+            ),
+            (
+                "DeskLamp".to_string(),
+                Box::new(KasaPlug::new(
+                    "127.0.0.1".to_string(),
+                    "DeskLamp".to_string(),
+                    "office".to_string(),
+                    vec![],
+                )),
+            ),
+        ]);
+        let mut presets = HashMap::from([(
+            "Working".to_string(),
+            Preset {
+                name: "Working".to_string(),
+                enabled: true,
+                relays: HashMap::from([("Fan".to_string(), true), ("DeskLamp".to_string(), true)]),
+                levels: HashMap::new(),
+            },
+        )]);
+        let current_preset = Mutex::new("Off".to_string());
+
+        let response = handle_preset_command(
+            PresetCommand::Set("Working".to_string(), None),
+            &mut relays,
+            &mut presets,
+            &current_preset,
+        )
+        .unwrap();
+        let DataThreadResponse::Value(value) = response else {
+            panic!("expected a value");
+        };
+        assert_eq!(
+            value,
+            json!({"presetSet": false, "failedRelays": ["DeskLamp"]})
+        );
+        assert!(relays["Fan"].status());
+        assert_eq!(*current_preset.lock().unwrap(), "Custom");
+    }
+
+    #[test]
+    fn test_tag_levels_need_a_dimmable_relay() {
+        let mut relays: HashMap<String, RelayType> = HashMap::from([(
+            "Fan".to_string(),
+            Box::new(VirtualRelay::new(
+                "Fan".to_string(),
+                "office".to_string(),
+                vec!["office".to_string()],
+            )) as RelayType,
+        )]);
+        let levels = LightLevels {
+            brightness: Some(40),
+            ..LightLevels::default()
+        };
+
+        let error = handle_tag_command(
+            TagCommand {
+                tag: "office".to_string(),
+                command: RelayCommands::LEVELS(levels),
+                client_ip: None,
+            },
+            &mut relays,
+            &Mutex::new(String::new()),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+        assert!(!relays["Fan"].status());
+    }
+
+    #[cfg(feature = "mqtt")]
+    #[test]
+    fn test_mqtt_states_reach_the_registry_without_a_poll() {
+        use crate::drivers::mqtt::MqttRelay;
+        use crate::drivers::test_support::mqtt_stub;
+        use std::sync::mpsc::channel;
+        use std::time::Instant;
+
+        let (url, _) = mqtt_stub();
+        let (route_to_data_sender, route_to_data_receiver) = channel();
+        mqtt_network_functions::notify_states(route_to_data_sender);
+
+        let options = serde_json::from_value(json!({
+            "broker": url,
+            "commandTopic": "remoterelay/drift/set",
+            "stateTopic": "remoterelay/drift/state",
+        }))
+        .unwrap();
+        let relay = MqttRelay::new(
+            "Dehumidifier".to_string(),
+            "basement".to_string(),
+            vec![],
+            options,
+        )
+        .unwrap();
+        let mut relays: HashMap<String, RelayType> = HashMap::from([(
+            "Dehumidifier".to_string(),
+            TrackedRelay::track(Box::new(relay)),
+        )]);
+        let automations = AutomationEngine::new(vec![], &relays);
+
+        // Switched outside the server, nothing polls the relay
+        mqtt_network_functions::broker(Some(&url))
+            .unwrap()
+            .publish("remoterelay/drift/state", "ON")
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !relays["Dehumidifier"].status() && Instant::now() < deadline {
+            let Ok(received) = route_to_data_receiver.recv_timeout(Duration::from_millis(100))
+            else {
+                continue;
+            };
+            assert!(matches!(received, DataThreadCommand::MqttState));
+            handle_command(
+                received,
+                &mut relays,
+                &mut HashMap::new(),
+                &Mutex::new("Custom".to_string()),
+                &automations,
+                &None,
+            )
+            .unwrap();
+        }
+        assert!(relays["Dehumidifier"].status());
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use prost::Message;
+use rocket::log;
+use snow::TransportState;
+
+use crate::drivers::esphome::EspHomeOptions;
+use crate::models::esphome_api_models::*;
+use crate::utils::kasa_plug_network_functions::timeout;
+
+const DEFAULT_PORT: u16 = 6053;
+const NOISE_PROTOCOL: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const NOISE_PROLOGUE: &[u8] = b"NoiseAPIInit\x00\x00";
+/// Setting up an encrypted session and listing entities takes the device a
+/// while, so the handshake doesn't use the device timeout
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// A ping is sent after this long without a message, and the connection is
+/// dropped when the next message doesn't arrive in time either
+const KEEPALIVE: Duration = Duration::from_secs(20);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_FRAME_SIZE: u64 = 1 << 20;
+
+static DEVICES: OnceLock<Mutex<HashMap<String, Arc<EspHomeDevice>>>> = OnceLock::new();
+
+fn read_varint(reader: &mut impl Read) -> Result<u64, Error> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "Varint is too long"))
+}
+
+/// Plaintext frame: a zero byte, the payload size and message type as
+/// varints, then the protobuf payload.
+pub(crate) fn plain_frame(message_type: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8];
+    prost::encoding::encode_varint(payload.len() as u64, &mut frame);
+    prost::encoding::encode_varint(u64::from(message_type), &mut frame);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub(crate) fn read_plain_frame(reader: &mut impl Read) -> Result<(u16, Vec<u8>), Error> {
+    let mut indicator = [0u8];
+    reader.read_exact(&mut indicator)?;
+    match indicator[0] {
+        0 => {}
+        1 => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "The device uses encryption, set its `encryptionKey`",
+            ))
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not an ESPHome API frame",
+            ))
+        }
+    }
+
+    let size = read_varint(reader)?;
+    let message_type = read_varint(reader)?;
+    if size > MAX_FRAME_SIZE || message_type > u64::from(u16::MAX) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid ESPHome API frame",
+        ));
+    }
+    let mut payload = vec![0u8; size as usize];
+    reader.read_exact(&mut payload)?;
+    Ok((message_type as u16, payload))
+}
+
+/// Encrypted frame: a one byte, the size as two big endian bytes, then the
+/// Noise message.
+pub(crate) fn noise_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![1u8];
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub(crate) fn read_noise_frame(reader: &mut impl Read) -> Result<Vec<u8>, Error> {
+    let mut header = [0u8; 3];
+    reader.read_exact(&mut header)?;
+    match header[0] {
+        1 => {}
+        0 => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The device doesn't use encryption, remove its `encryptionKey`",
+            ))
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not an ESPHome API frame",
+            ))
+        }
+    }
+
+    let mut payload = vec![0u8; u16::from_be_bytes([header[1], header[2]]) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Reads the 32 byte pre-shared key from the base64 `encryption.key` of the
+/// device's `api` config.
+pub(crate) fn decode_encryption_key(key: &str) -> Result<Vec<u8>, Error> {
+    match STANDARD.decode(key.trim()) {
+        Ok(key) if key.len() == 32 => Ok(key),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "`encryptionKey` must be the base64 key of the device's api config",
+        )),
+    }
+}
+
+fn noise_error(error: snow::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, error.to_string())
+}
+
+fn decode<T: Message + Default>(payload: &[u8]) -> Result<T, Error> {
+    T::decode(payload).map_err(|error| Error::new(ErrorKind::InvalidData, error))
+}
+
+/// Runs the `Noise_NNpsk0` handshake with the device's pre-shared key.
+fn noise_handshake(mut stream: &TcpStream, key: &[u8]) -> Result<TransportState, Error> {
+    let mut handshake = snow::Builder::new(NOISE_PROTOCOL.parse().map_err(noise_error)?)
+        .prologue(NOISE_PROLOGUE)
+        .psk(0, key)
+        .build_initiator()
+        .map_err(noise_error)?;
+
+    let mut message = vec![0u8; u16::MAX as usize];
+    let size = handshake
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+    let mut payload = vec![0u8];
+    payload.extend_from_slice(&message[..size]);
+    // An empty client hello, then the first handshake message
+    let mut frames = noise_frame(&[]);
+    frames.extend(noise_frame(&payload));
+    stream.write_all(&frames)?;
+
+    let server_hello = read_noise_frame(&mut stream)?;
+    if server_hello.first() != Some(&1) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "The device offered an unsupported encryption protocol",
+        ));
+    }
+
+    match read_noise_frame(&mut stream)?.split_first() {
+        Some((0, response)) => {
+            handshake
+                .read_message(response, &mut message)
+                .map_err(|_| {
+                    Error::new(
+                        ErrorKind::PermissionDenied,
+                        "The device rejected the encryption key",
+                    )
+                })?;
+        }
+        Some((_, reason)) => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "The device rejected the encryption key: {}",
+                    String::from_utf8_lossy(reason)
+                ),
+            ))
+        }
+        None => return Err(Error::new(ErrorKind::InvalidData, "Empty handshake answer")),
+    }
+
+    handshake.into_transport_mode().map_err(noise_error)
+}
+
+/// One API session, in plaintext or Noise encrypted. Writes hold the cipher
+/// lock so frames go out in the order their nonces were used.
+struct ApiConnection {
+    stream: TcpStream,
+    encrypted: bool,
+    noise: Mutex<Option<TransportState>>,
+}
+
+impl ApiConnection {
+    fn send(&self, message_type: u16, message: &impl Message) -> Result<(), Error> {
+        let payload = message.encode_to_vec();
+        let mut noise = self.noise.lock().unwrap();
+        let frame = match noise.as_mut() {
+            Some(noise) => {
+                let mut plain = Vec::with_capacity(payload.len() + 4);
+                plain.extend_from_slice(&message_type.to_be_bytes());
+                plain.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+                plain.extend_from_slice(&payload);
+                let mut encrypted = vec![0u8; plain.len() + 16];
+                let size = noise
+                    .write_message(&plain, &mut encrypted)
+                    .map_err(noise_error)?;
+                noise_frame(&encrypted[..size])
+            }
+            None => plain_frame(message_type, &payload),
+        };
+        (&self.stream).write_all(&frame)
+    }
+
+    fn receive(&self) -> Result<(u16, Vec<u8>), Error> {
+        if !self.encrypted {
+            return read_plain_frame(&mut &self.stream);
+        }
+
+        let frame = read_noise_frame(&mut &self.stream)?;
+        let mut plain = vec![0u8; frame.len()];
+        let size = match self.noise.lock().unwrap().as_mut() {
+            Some(noise) => noise
+                .read_message(&frame, &mut plain)
+                .map_err(noise_error)?,
+            None => return Err(Error::new(ErrorKind::NotConnected, "No encrypted session")),
+        };
+        if size < 4 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Encrypted frame is too short",
+            ));
+        }
+        Ok((
+            u16::from_be_bytes([plain[0], plain[1]]),
+            plain[4..size].to_vec(),
+        ))
+    }
+
+    /// Waits for a message of `message_type`, skipping anything else.
+    fn expect(&self, message_type: u16) -> Result<Vec<u8>, Error> {
+        loop {
+            let (received, payload) = self.receive()?;
+            if received == message_type {
+                return Ok(payload);
+            }
+        }
+    }
+}
+
+/// A switch entity listed by a device.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SwitchEntity {
+    pub(crate) key: u32,
+    pub(crate) object_id: String,
+    pub(crate) name: String,
+}
+
+/// Connection to an ESPHome device, shared by every relay on it. A thread
+/// follows the switch states the device reports and reconnects when the
+/// connection drops.
+pub(crate) struct EspHomeDevice {
+    ip: String,
+    address: SocketAddr,
+    encryption_key: Option<Vec<u8>>,
+    password: Option<String>,
+    connection: Mutex<Option<Arc<ApiConnection>>>,
+    switches: Mutex<Vec<SwitchEntity>>,
+    states: Mutex<HashMap<u32, bool>>,
+    closed: AtomicBool,
+}
+
+impl EspHomeDevice {
+    /// Opens a session, lists the switch entities and subscribes to states.
+    fn connect(&self) -> Result<(), Error> {
+        let stream = TcpStream::connect_timeout(&self.address, timeout())?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let noise = match &self.encryption_key {
+            Some(key) => Some(noise_handshake(&stream, key)?),
+            None => None,
+        };
+        let connection = ApiConnection {
+            stream,
+            encrypted: noise.is_some(),
+            noise: Mutex::new(noise),
+        };
+
+        connection.send(
+            HELLO_REQUEST,
+            &HelloRequest {
+                client_info: "remoterelay".to_string(),
+                api_version_major: 1,
+                api_version_minor: 10,
+            },
+        )?;
+        let hello: HelloResponse = decode(&connection.expect(HELLO_RESPONSE)?)?;
+        if hello.api_version_major != 1 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "ESPHome {} speaks API version {}.{}, only 1.x is supported",
+                    self.ip, hello.api_version_major, hello.api_version_minor
+                ),
+            ));
+        }
+
+        if let Some(password) = &self.password {
+            connection.send(
+                CONNECT_REQUEST,
+                &ConnectRequest {
+                    password: password.clone(),
+                },
+            )?;
+            let response: ConnectResponse = decode(&connection.expect(CONNECT_RESPONSE)?)?;
+            if response.invalid_password {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("ESPHome {} rejected the password", self.ip),
+                ));
+            }
+        }
+
+        connection.send(LIST_ENTITIES_REQUEST, &Empty {})?;
+        let mut switches: Vec<SwitchEntity> = Vec::new();
+        loop {
+            match connection.receive()? {
+                (LIST_ENTITIES_SWITCH_RESPONSE, payload) => {
+                    let entity: ListEntitiesSwitchResponse = decode(&payload)?;
+                    switches.push(SwitchEntity {
+                        key: entity.key,
+                        object_id: entity.object_id,
+                        name: entity.name,
+                    });
+                }
+                (LIST_ENTITIES_DONE_RESPONSE, _) => break,
+                _ => {}
+            }
+        }
+
+        connection.send(SUBSCRIBE_STATES_REQUEST, &Empty {})?;
+        connection.stream.set_read_timeout(Some(KEEPALIVE))?;
+
+        *self.switches.lock().unwrap() = switches;
+        *self.connection.lock().unwrap() = Some(Arc::new(connection));
+        Ok(())
+    }
+
+    fn read_states(&self, connection: &ApiConnection) -> Result<(), Error> {
+        let mut idle = false;
+        loop {
+            match connection.receive() {
+                Ok((message_type, payload)) => {
+                    idle = false;
+                    match message_type {
+                        SWITCH_STATE_RESPONSE => {
+                            let state: SwitchStateResponse = decode(&payload)?;
+                            self.states.lock().unwrap().insert(state.key, state.state);
+                        }
+                        PING_REQUEST => connection.send(PING_RESPONSE, &Empty {})?,
+                        GET_TIME_REQUEST => {
+                            let now = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default();
+                            connection.send(
+                                GET_TIME_RESPONSE,
+                                &GetTimeResponse {
+                                    epoch_seconds: now.as_secs() as u32,
+                                },
+                            )?
+                        }
+                        DISCONNECT_REQUEST => {
+                            let _ = connection.send(DISCONNECT_RESPONSE, &Empty {});
+                            return Err(Error::new(
+                                ErrorKind::ConnectionAborted,
+                                "The device closed the connection",
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+                Err(error)
+                    if !idle
+                        && matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    idle = true;
+                    connection.send(PING_REQUEST, &Empty {})?;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Follows the device until it's closed, reconnecting after a delay.
+    fn follow(&self) {
+        loop {
+            let connection = self.connection.lock().unwrap().clone();
+            if let Some(connection) = connection {
+                if let Err(error) = self.read_states(&connection) {
+                    if !self.closed.load(Ordering::Relaxed) {
+                        log::warn_!("Lost connection to ESPHome {}: {}", self.ip, error);
+                    }
+                }
+                *self.connection.lock().unwrap() = None;
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+
+            thread::sleep(RECONNECT_DELAY);
+            if self.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            let _ = self.connect();
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(connection) = self.connection.lock().unwrap().as_ref() {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn connection(&self) -> Result<Arc<ApiConnection>, Error> {
+        self.connection.lock().unwrap().clone().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                format!("Not connected to ESPHome {}", self.ip),
+            )
+        })
+    }
+
+    /// Switch entities in the order the device lists them.
+    pub(crate) fn switches(&self) -> Vec<SwitchEntity> {
+        self.switches.lock().unwrap().clone()
+    }
+
+    /// Last state the device reported for the switch `key`.
+    pub(crate) fn state(&self, key: u32) -> Result<bool, Error> {
+        self.connection()?;
+        self.states
+            .lock()
+            .unwrap()
+            .get(&key)
+            .copied()
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("ESPHome {} reported no state for switch {}", self.ip, key),
+                )
+            })
+    }
+
+    /// Waits up to the device timeout for the switch `key` to be in `state`,
+    /// or to report any state when `state` is `None`.
+    pub(crate) fn wait_for_state(&self, key: u32, state: Option<bool>) -> Result<bool, Error> {
+        let deadline = Instant::now() + timeout();
+        loop {
+            match self.state(key) {
+                Ok(current) if state.is_none() || state == Some(current) => return Ok(current),
+                Err(error) if error.kind() == ErrorKind::NotConnected => return Err(error),
+                result if Instant::now() >= deadline => {
+                    return match result {
+                        Ok(_) => Err(Error::new(
+                            ErrorKind::TimedOut,
+                            format!("ESPHome {} didn't confirm the switch state", self.ip),
+                        )),
+                        error => error,
+                    }
+                }
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    /// Sends a `SwitchCommandRequest` and waits for the device to confirm it.
+    pub(crate) fn set_state(&self, key: u32, state: bool) -> Result<(), Error> {
+        self.connection()?
+            .send(SWITCH_COMMAND_REQUEST, &SwitchCommandRequest { key, state })?;
+        self.wait_for_state(key, Some(state)).map(|_| ())
+    }
+}
+
+/// Address of the API, `ip` may give a port other than 6053.
+fn api_address(ip: &str) -> Result<SocketAddr, Error> {
+    ip.to_socket_addrs()
+        .or_else(|_| (ip, DEFAULT_PORT).to_socket_addrs())?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid IP address"))
+}
+
+/// Connection to the ESPHome device at `ip`, opened on first use and kept for
+/// the life of the process. A device whose credentials changed is reconnected.
+pub(crate) fn device(ip: &str, options: &EspHomeOptions) -> Result<Arc<EspHomeDevice>, Error> {
+    let encryption_key = match &options.encryption_key {
+        Some(key) => Some(decode_encryption_key(key)?),
+        None => None,
+    };
+
+    let mut devices = DEVICES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    if let Some(device) = devices.get(ip) {
+        if device.encryption_key == encryption_key && device.password == options.password {
+            return Ok(device.clone());
+        }
+        device.close();
+        devices.remove(ip);
+    }
+
+    let device = Arc::new(EspHomeDevice {
+        ip: ip.to_string(),
+        address: api_address(ip)?,
+        encryption_key,
+        password: options.password.clone(),
+        connection: Mutex::new(None),
+        switches: Mutex::new(Vec::new()),
+        states: Mutex::new(HashMap::new()),
+        closed: AtomicBool::new(false),
+    });
+    device.connect()?;
+
+    let follower = device.clone();
+    thread::spawn(move || follower.follow());
+    devices.insert(ip.to_string(), device.clone());
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_plain_frames() {
+        let frame = plain_frame(SWITCH_COMMAND_REQUEST, &[1; 200]);
+        assert_eq!(&frame[..4], &[0, 0xc8, 0x01, 33]);
+
+        let (message_type, payload) = read_plain_frame(&mut frame.as_slice()).unwrap();
+        assert_eq!(message_type, SWITCH_COMMAND_REQUEST);
+        assert_eq!(payload, vec![1; 200]);
+
+        let error = read_plain_frame(&mut noise_frame(&[]).as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_encrypted_session() {
+        let key = [7u8; 32];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut responder = snow::Builder::new(NOISE_PROTOCOL.parse().unwrap())
+                .prologue(NOISE_PROLOGUE)
+                .psk(0, &key)
+                .build_responder()
+                .unwrap();
+            let mut buffer = vec![0u8; u16::MAX as usize];
+
+            assert!(read_noise_frame(&mut stream).unwrap().is_empty());
+            let handshake = read_noise_frame(&mut stream).unwrap();
+            responder
+                .read_message(&handshake[1..], &mut buffer)
+                .unwrap();
+            let size = responder.write_message(&[], &mut buffer).unwrap();
+            let mut answer = vec![0u8];
+            answer.extend_from_slice(&buffer[..size]);
+            stream.write_all(&noise_frame(b"\x01test\x00")).unwrap();
+            stream.write_all(&noise_frame(&answer)).unwrap();
+
+            let connection = ApiConnection {
+                stream,
+                encrypted: true,
+                noise: Mutex::new(Some(responder.into_transport_mode().unwrap())),
+            };
+            let hello: HelloRequest = decode(&connection.expect(HELLO_REQUEST).unwrap()).unwrap();
+            assert_eq!(hello.client_info, "remoterelay");
+            connection
+                .send(
+                    HELLO_RESPONSE,
+                    &HelloResponse {
+                        api_version_major: 1,
+                        api_version_minor: 10,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            connection.expect(LIST_ENTITIES_REQUEST).unwrap();
+            connection
+                .send(
+                    LIST_ENTITIES_SWITCH_RESPONSE,
+                    &ListEntitiesSwitchResponse {
+                        object_id: "relay_1".to_string(),
+                        key: 42,
+                        name: "Relay 1".to_string(),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            connection
+                .send(LIST_ENTITIES_DONE_RESPONSE, &Empty {})
+                .unwrap();
+            connection.expect(SUBSCRIBE_STATES_REQUEST).unwrap();
+            connection
+                .send(
+                    SWITCH_STATE_RESPONSE,
+                    &SwitchStateResponse {
+                        key: 42,
+                        state: true,
+                    },
+                )
+                .unwrap();
+            let command: SwitchCommandRequest =
+                decode(&connection.expect(SWITCH_COMMAND_REQUEST).unwrap()).unwrap();
+            connection
+                .send(
+                    SWITCH_STATE_RESPONSE,
+                    &SwitchStateResponse {
+                        key: command.key,
+                        state: command.state,
+                    },
+                )
+                .unwrap();
+            connection
+        });
+
+        let options = EspHomeOptions {
+            encryption_key: Some(STANDARD.encode(key)),
+            ..Default::default()
+        };
+        let device = device(&address.to_string(), &options).unwrap();
+        assert_eq!(device.switches()[0].object_id, "relay_1");
+        assert!(device.wait_for_state(42, None).unwrap());
+
+        device.set_state(42, false).unwrap();
+        assert!(!device.state(42).unwrap());
+        device.close();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Read};
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::drivers::exec::ExecCommand;
+use crate::drivers::state_parsing::render;
+
+/// Seconds a command gets when it doesn't set a `timeout`.
+const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+/// How a command ended.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ExecOutput {
+    pub(crate) success: bool,
+    pub(crate) code: Option<i32>,
+    pub(crate) stdout: String,
+}
+
+/// User and group id of `user`, a name from `/etc/passwd` or a numeric uid.
+pub(crate) fn user_ids(user: &str) -> Result<(u32, u32), Error> {
+    let passwd = fs::read_to_string("/etc/passwd")?;
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let uid = fields.get(2)?.parse::<u32>().ok()?;
+            let gid = fields.get(3)?.parse::<u32>().ok()?;
+            Some((fields[0], uid, gid))
+        })
+        .find(|(name, uid, _)| *name == user || uid.to_string() == user)
+        .map(|(_, uid, gid)| (uid, gid))
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Unknown user {}", user)))
+}
+
+fn build(command: &ExecCommand, variables: &[(&str, &str)]) -> Result<Command, Error> {
+    let mut process = Command::new(&command.program);
+    process
+        .args(command.args.iter().map(|arg| render(arg, variables)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    if command.clear_env {
+        process.env_clear();
+    }
+    process.envs(&command.env);
+    if let Some(directory) = &command.working_dir {
+        process.current_dir(directory);
+    }
+
+    if let Some(user) = &command.user {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let (uid, gid) = user_ids(user)?;
+            process.uid(uid).gid(gid);
+        }
+        #[cfg(not(unix))]
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Can't run {} as {} on this platform", command.program, user),
+        ));
+    }
+
+    Ok(process)
+}
+
+/// Runs `command` with its arguments filled in from `variables`, killing it
+/// once its timeout is up.
+pub(crate) fn run(command: &ExecCommand, variables: &[(&str, &str)]) -> Result<ExecOutput, Error> {
+    let mut child = build(command, variables)?.spawn().map_err(|error| {
+        Error::new(
+            error.kind(),
+            format!("Unable to run {}: {}", command.program, error),
+        )
+    })?;
+
+    // Read the output as it comes so a chatty command can't fill the pipe
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (closed_sender, closed) = mpsc::channel::<()>();
+    let buffer = output.clone();
+    let stdout = child.stdout.take();
+    thread::spawn(move || {
+        if let Some(mut stdout) = stdout {
+            let mut chunk = [0u8; 4096];
+            while let Ok(read @ 1..) = stdout.read(&mut chunk) {
+                if let Ok(mut buffer) = buffer.lock() {
+                    buffer.extend_from_slice(&chunk[..read]);
+                }
+            }
+        }
+        let _ = closed_sender.send(());
+    });
+
+    let timeout = Duration::from_secs(command.timeout.unwrap_or(DEFAULT_TIMEOUT_SECONDS));
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "{} didn't finish within {} seconds",
+                    command.program,
+                    timeout.as_secs()
+                ),
+            ));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    // A background process started by the command can hold stdout open after
+    // the command itself has exited, so the output is only waited for until
+    // the deadline and whatever was read by then is used.
+    let _ = closed.recv_timeout(timeout.saturating_sub(started.elapsed()));
+    let stdout = output
+        .lock()
+        .map(|output| String::from_utf8_lossy(&output).into_owned())
+        .unwrap_or_default();
+
+    Ok(ExecOutput {
+        success: status.success(),
+        code: status.code(),
+        stdout,
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> ExecCommand {
+        ExecCommand {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            ..ExecCommand::default()
+        }
+    }
+
+    #[test]
+    fn test_running_commands() {
+        let output = run(
+            &shell("echo {{name}} is {{state}}"),
+            &[("name", "Pc"), ("state", "on")],
+        )
+        .unwrap();
+        assert!(output.success);
+        assert_eq!(output.stdout, "Pc is on\n");
+
+        let output = run(&shell("exit 3"), &[]).unwrap();
+        assert_eq!(output.code, Some(3));
+
+        let slow = ExecCommand {
+            timeout: Some(0),
+            ..shell("sleep 5")
+        };
+        assert_eq!(run(&slow, &[]).unwrap_err().kind(), ErrorKind::TimedOut);
+
+        let detached = ExecCommand {
+            timeout: Some(1),
+            ..shell("sleep 5 & echo started")
+        };
+        let started = Instant::now();
+        let output = run(&detached, &[]).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(output.success);
+        assert_eq!(output.stdout, "started\n");
+
+        assert_eq!(user_ids("root").unwrap(), (0, 0));
+        assert!(user_ids("no-such-user").is_err());
+    }
+}
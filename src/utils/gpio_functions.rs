@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Mutex, OnceLock};
+
+/// Chip used by `Gpio` relays that don't name one.
+pub(crate) const DEFAULT_CHIP: &str = "/dev/gpiochip0";
+
+/// An output line of a GPIO chip. Values are logical, an active-low line
+/// reads `true` while it's driven low.
+pub(crate) trait OutputLine: Send {
+    fn set(&mut self, on: bool) -> Result<(), Error>;
+
+    fn get(&self) -> Result<bool, Error>;
+}
+
+struct RequestedLine {
+    active_low: bool,
+    line: Box<dyn OutputLine>,
+}
+
+static LINES: OnceLock<Mutex<HashMap<(String, u32), RequestedLine>>> = OnceLock::new();
+
+/// Path of a chip, `gpiochip0` and `0` are short for `/dev/gpiochip0`.
+pub(crate) fn chip_path(chip: Option<&str>) -> String {
+    match chip {
+        None => DEFAULT_CHIP.to_string(),
+        Some(chip) if chip.starts_with('/') => chip.to_string(),
+        Some(chip) if chip.chars().all(|character| character.is_ascii_digit()) => {
+            format!("/dev/gpiochip{}", chip)
+        }
+        Some(chip) => format!("/dev/{}", chip),
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct CdevLine(gpio_cdev::LineHandle);
+
+#[cfg(target_os = "linux")]
+impl OutputLine for CdevLine {
+    fn set(&mut self, on: bool) -> Result<(), Error> {
+        self.0.set_value(u8::from(on)).map_err(Error::other)
+    }
+
+    fn get(&self) -> Result<bool, Error> {
+        Ok(self.0.get_value().map_err(Error::other)? == 1)
+    }
+}
+
+/// Requests `line` of `chip` as an output through the character device. The
+/// line keeps the value it had, so a relay isn't switched by the server
+/// starting, and the kernel releases it when the server exits.
+#[cfg(target_os = "linux")]
+fn request_line(chip: &str, line: u32, active_low: bool) -> Result<Box<dyn OutputLine>, Error> {
+    use gpio_cdev::{Chip, LineRequestFlags};
+
+    let gpio_error = |error: gpio_cdev::Error| {
+        Error::other(format!(
+            "Unable to request {} line {}: {}",
+            chip, line, error
+        ))
+    };
+    let mut flags = LineRequestFlags::empty();
+    if active_low {
+        flags |= LineRequestFlags::ACTIVE_LOW;
+    }
+
+    let line = Chip::new(chip)
+        .map_err(gpio_error)?
+        .get_line(line)
+        .map_err(gpio_error)?;
+    // Requested without a direction the line is left as it is while it's read
+    let value = line
+        .request(flags, 0, "remoterelay")
+        .and_then(|handle| handle.get_value())
+        .map_err(gpio_error)?;
+    let handle = line
+        .request(flags | LineRequestFlags::OUTPUT, value, "remoterelay")
+        .map_err(gpio_error)?;
+    Ok(Box::new(CdevLine(handle)))
+}
+
+/// Checks that `line` of `chip` exists, without requesting it.
+#[cfg(target_os = "linux")]
+pub(crate) fn check_line(chip: &str, line: u32) -> Result<(), Error> {
+    let gpio_error =
+        |error: gpio_cdev::Error| Error::other(format!("{} line {}: {}", chip, line, error));
+
+    gpio_cdev::Chip::new(chip)
+        .map_err(gpio_error)?
+        .get_line(line)
+        .and_then(|line| line.info())
+        .map_err(gpio_error)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn request_line(_chip: &str, _line: u32, _active_low: bool) -> Result<Box<dyn OutputLine>, Error> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn check_line(_chip: &str, _line: u32) -> Result<(), Error> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "GPIO relays need the Linux GPIO character device",
+    )
+}
+
+/// Runs `action` on `line` of `chip`, requesting it on first use. The line is
+/// kept for the life of the process and requested again when `active_low`
+/// changes.
+fn with_line<T>(
+    chip: &str,
+    line: u32,
+    active_low: bool,
+    action: impl FnOnce(&mut dyn OutputLine) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut lines = LINES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    let key = (chip.to_string(), line);
+
+    if lines
+        .get(&key)
+        .is_some_and(|requested| requested.active_low != active_low)
+    {
+        // The old request has to be released before the line can be taken again
+        lines.remove(&key);
+    }
+    if !lines.contains_key(&key) {
+        let requested = RequestedLine {
+            active_low,
+            line: request_line(chip, line, active_low)?,
+        };
+        lines.insert(key.clone(), requested);
+    }
+
+    match lines.get_mut(&key) {
+        Some(requested) => action(requested.line.as_mut()),
+        None => Err(Error::new(ErrorKind::NotFound, "GPIO line was released")),
+    }
+}
+
+pub(crate) fn set_line(chip: &str, line: u32, active_low: bool, on: bool) -> Result<(), Error> {
+    with_line(chip, line, active_low, |output| output.set(on))
+}
+
+/// Reads back the value the line is driven to.
+pub(crate) fn get_line(chip: &str, line: u32, active_low: bool) -> Result<bool, Error> {
+    with_line(chip, line, active_low, |output| output.get())
+}
+
+/// Gives `line` of `chip` back to the kernel, for a relay removed from the
+/// config.
+pub(crate) fn release_line(chip: &str, line: u32) {
+    if let Some(lines) = LINES.get() {
+        lines.lock().unwrap().remove(&(chip.to_string(), line));
+    }
+}
+
+/// Puts `output` in place of `line` of `chip`, so relays can be tested
+/// without GPIO hardware.
+#[cfg(test)]
+pub(crate) fn insert_line(chip: &str, line: u32, active_low: bool, output: Box<dyn OutputLine>) {
+    LINES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .insert(
+            (chip.to_string(), line),
+            RequestedLine {
+                active_low,
+                line: output,
+            },
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chip_paths() {
+        assert_eq!(chip_path(None), "/dev/gpiochip0");
+        assert_eq!(chip_path(Some("4")), "/dev/gpiochip4");
+        assert_eq!(chip_path(Some("gpiochip1")), "/dev/gpiochip1");
+        assert_eq!(chip_path(Some("/dev/gpiochip2")), "/dev/gpiochip2");
+    }
+}
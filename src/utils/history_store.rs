@@ -0,0 +1,290 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+
+use crate::models::data_thread_models::{ChangeSource, HistoryQuery, RelayStateChange};
+
+const DEFAULT_LIMIT: u32 = 500;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Embedded SQLite log of relay transitions and preset applications.
+pub(crate) struct HistoryStore {
+    connection: Connection,
+    retention_days: u64,
+    last_pruned: Option<Instant>,
+}
+
+fn format_timestamp(timestamp: i64) -> Value {
+    match DateTime::<Utc>::from_timestamp_millis(timestamp) {
+        Some(time) => Value::String(time.to_rfc3339()),
+        None => Value::Null,
+    }
+}
+
+impl HistoryStore {
+    /// Opens (or creates) the history database. A `retention_days` of 0 keeps
+    /// history forever.
+    pub(crate) fn open(path: &str, retention_days: u64) -> rusqlite::Result<Self> {
+        Self::initialize(Connection::open(path)?, retention_days)
+    }
+
+    #[cfg(test)]
+    fn open_in_memory(retention_days: u64) -> rusqlite::Result<Self> {
+        Self::initialize(Connection::open_in_memory()?, retention_days)
+    }
+
+    fn initialize(connection: Connection, retention_days: u64) -> rusqlite::Result<Self> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS relay_history (
+                id INTEGER PRIMARY KEY,
+                relay TEXT NOT NULL,
+                old_state INTEGER,
+                new_state INTEGER NOT NULL,
+                source TEXT NOT NULL,
+                client_ip TEXT,
+                timestamp INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS relay_history_relay_timestamp
+                ON relay_history (relay, timestamp);
+            CREATE TABLE IF NOT EXISTS preset_history (
+                id INTEGER PRIMARY KEY,
+                preset TEXT NOT NULL,
+                applied INTEGER NOT NULL,
+                source TEXT NOT NULL,
+                client_ip TEXT,
+                timestamp INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS preset_history_timestamp
+                ON preset_history (timestamp);",
+        )?;
+
+        Ok(HistoryStore {
+            connection,
+            retention_days,
+            last_pruned: None,
+        })
+    }
+
+    pub(crate) fn record_changes(
+        &self,
+        changes: &[RelayStateChange],
+        source: ChangeSource,
+        client_ip: Option<IpAddr>,
+    ) -> rusqlite::Result<()> {
+        let timestamp = Utc::now().timestamp_millis();
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO relay_history (relay, old_state, new_state, source, client_ip, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+
+        for change in changes {
+            statement.execute(params![
+                change.name,
+                change.previous,
+                change.current,
+                source.to_string(),
+                client_ip.map(|ip| ip.to_string()),
+                timestamp
+            ])?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn record_preset(
+        &self,
+        preset: &str,
+        applied: bool,
+        source: ChangeSource,
+        client_ip: Option<IpAddr>,
+    ) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO preset_history (preset, applied, source, client_ip, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                preset,
+                applied,
+                source.to_string(),
+                client_ip.map(|ip| ip.to_string()),
+                Utc::now().timestamp_millis()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn relay_history(&self, query: &HistoryQuery) -> rusqlite::Result<Value> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT relay, old_state, new_state, source, client_ip, timestamp
+             FROM relay_history
+             WHERE (?1 IS NULL OR relay = ?1)
+               AND (?2 IS NULL OR timestamp >= ?2)
+               AND (?3 IS NULL OR timestamp <= ?3)
+             ORDER BY timestamp DESC, id DESC
+             LIMIT ?4",
+        )?;
+
+        let rows = statement.query_map(
+            params![
+                query.relay,
+                query.since,
+                query.until,
+                query.limit.unwrap_or(DEFAULT_LIMIT)
+            ],
+            |row| {
+                Ok(json!({
+                    "relay": row.get::<_, String>(0)?,
+                    "oldState": row.get::<_, Option<bool>>(1)?,
+                    "newState": row.get::<_, bool>(2)?,
+                    "source": row.get::<_, String>(3)?,
+                    "clientIp": row.get::<_, Option<String>>(4)?,
+                    "timestamp": format_timestamp(row.get(5)?),
+                }))
+            },
+        )?;
+
+        Ok(Value::Array(
+            rows.collect::<rusqlite::Result<Vec<Value>>>()?,
+        ))
+    }
+
+    pub(crate) fn preset_history(&self, query: &HistoryQuery) -> rusqlite::Result<Value> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT preset, applied, source, client_ip, timestamp
+             FROM preset_history
+             WHERE (?1 IS NULL OR timestamp >= ?1)
+               AND (?2 IS NULL OR timestamp <= ?2)
+             ORDER BY timestamp DESC, id DESC
+             LIMIT ?3",
+        )?;
+
+        let rows = statement.query_map(
+            params![
+                query.since,
+                query.until,
+                query.limit.unwrap_or(DEFAULT_LIMIT)
+            ],
+            |row| {
+                Ok(json!({
+                    "preset": row.get::<_, String>(0)?,
+                    "applied": row.get::<_, bool>(1)?,
+                    "source": row.get::<_, String>(2)?,
+                    "clientIp": row.get::<_, Option<String>>(3)?,
+                    "timestamp": format_timestamp(row.get(4)?),
+                }))
+            },
+        )?;
+
+        Ok(Value::Array(
+            rows.collect::<rusqlite::Result<Vec<Value>>>()?,
+        ))
+    }
+
+    /// Deletes entries older than the retention period, at most once an hour.
+    pub(crate) fn prune_if_due(&mut self) -> rusqlite::Result<usize> {
+        if self.retention_days == 0
+            || self
+                .last_pruned
+                .is_some_and(|last_pruned| last_pruned.elapsed() < PRUNE_INTERVAL)
+        {
+            return Ok(0);
+        }
+
+        self.last_pruned = Some(Instant::now());
+        let cutoff = Utc::now().timestamp_millis() - self.retention_days as i64 * MILLIS_PER_DAY;
+        self.prune(cutoff)
+    }
+
+    fn prune(&self, cutoff: i64) -> rusqlite::Result<usize> {
+        let relays = self.connection.execute(
+            "DELETE FROM relay_history WHERE timestamp < ?1",
+            params![cutoff],
+        )?;
+        let presets = self.connection.execute(
+            "DELETE FROM preset_history WHERE timestamp < ?1",
+            params![cutoff],
+        )?;
+        Ok(relays + presets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(name: &str, previous: Option<bool>, current: bool) -> RelayStateChange {
+        RelayStateChange {
+            name: name.to_string(),
+            previous,
+            current,
+        }
+    }
+
+    #[test]
+    fn test_recording_and_querying_relay_history() {
+        let store = HistoryStore::open_in_memory(30).unwrap();
+        let client_ip: IpAddr = "192.168.0.10".parse().unwrap();
+
+        store
+            .record_changes(
+                &[change("DeskLamp", Some(false), true)],
+                ChangeSource::Api,
+                Some(client_ip),
+            )
+            .unwrap();
+        store
+            .record_changes(
+                &[change("Heater", None, false)],
+                ChangeSource::ExternalDrift,
+                None,
+            )
+            .unwrap();
+
+        let all = store.relay_history(&HistoryQuery::default()).unwrap();
+        assert_eq!(all.as_array().unwrap().len(), 2);
+
+        let desk_lamp = store
+            .relay_history(&HistoryQuery {
+                relay: Some("DeskLamp".to_string()),
+                ..HistoryQuery::default()
+            })
+            .unwrap();
+        assert_eq!(desk_lamp.as_array().unwrap().len(), 1);
+        assert_eq!(desk_lamp[0]["oldState"], false);
+        assert_eq!(desk_lamp[0]["newState"], true);
+        assert_eq!(desk_lamp[0]["source"], "api");
+        assert_eq!(desk_lamp[0]["clientIp"], "192.168.0.10");
+
+        let future = store
+            .relay_history(&HistoryQuery {
+                since: Some(Utc::now().timestamp_millis() + 60_000),
+                ..HistoryQuery::default()
+            })
+            .unwrap();
+        assert!(future.as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recording_presets_and_pruning() {
+        let mut store = HistoryStore::open_in_memory(30).unwrap();
+
+        store
+            .record_preset("Bedroom on", true, ChangeSource::Preset, None)
+            .unwrap();
+        let presets = store.preset_history(&HistoryQuery::default()).unwrap();
+        assert_eq!(presets[0]["preset"], "Bedroom on");
+        assert_eq!(presets[0]["applied"], true);
+
+        assert_eq!(store.prune_if_due().unwrap(), 0);
+        assert_eq!(store.prune(Utc::now().timestamp_millis() + 1).unwrap(), 1);
+        assert!(store
+            .preset_history(&HistoryQuery::default())
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::models::presets::{get_preset_names, Preset};
+use crate::models::relays::RelayType;
+
+/// Energy meter values announced as sensors: key in the reading, name, device
+/// class, unit and state class.
+const EMETER_SENSORS: [(&str, &str, &str, &str, &str); 4] = [
+    ("power", "Power", "power", "W", "measurement"),
+    ("voltage", "Voltage", "voltage", "V", "measurement"),
+    ("current", "Current", "current", "A", "measurement"),
+    ("total", "Energy", "energy", "kWh", "total_increasing"),
+];
+
+/// Id Home Assistant knows a relay by, e.g. `remoterelay_desk_lamp`.
+fn object_id(bridge_prefix: &str, name: &str) -> String {
+    format!("{}_{}", bridge_prefix, name)
+        .chars()
+        .map(|character| match character.is_ascii_alphanumeric() {
+            true => character.to_ascii_lowercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+fn hub_device(bridge_prefix: &str) -> Value {
+    json!({
+        "identifiers": [object_id(bridge_prefix, "hub")],
+        "name": "Remote Relay",
+        "manufacturer": "RemoteRelay",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+/// Retained discovery messages announcing a relay as a switch, and its energy
+/// meter as sensors, keyed by config topic.
+pub(crate) fn relay_discovery_messages(
+    discovery_prefix: &str,
+    bridge_prefix: &str,
+    relay: &RelayType,
+) -> HashMap<String, String> {
+    let mut messages: HashMap<String, String> = HashMap::new();
+    let name = relay.name();
+    let id = object_id(bridge_prefix, name);
+    let availability = json!([
+        {"topic": format!("{}/availability", bridge_prefix)},
+        {"topic": format!("{}/{}/availability", bridge_prefix, name)},
+    ]);
+    let device = json!({
+        "identifiers": [&id],
+        "name": name,
+        "model": relay.to_json()["type"],
+        "suggested_area": relay.room(),
+        "via_device": object_id(bridge_prefix, "hub"),
+    });
+
+    let switch = json!({
+        "name": null,
+        "unique_id": &id,
+        "object_id": &id,
+        "command_topic": format!("{}/{}/set", bridge_prefix, name),
+        "state_topic": format!("{}/{}/state", bridge_prefix, name),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "availability": &availability,
+        "availability_mode": "all",
+        "device": &device,
+    });
+    messages.insert(
+        format!("{}/switch/{}/config", discovery_prefix, id),
+        switch.to_string(),
+    );
+
+    if relay.emeter().is_none() {
+        return messages;
+    }
+    for (key, sensor_name, device_class, unit, state_class) in EMETER_SENSORS {
+        let sensor_id = format!("{}_{}", id, key);
+        let sensor = json!({
+            "name": sensor_name,
+            "unique_id": &sensor_id,
+            "object_id": &sensor_id,
+            "state_topic": format!("{}/{}/emeter", bridge_prefix, name),
+            "value_template": format!("{{{{ value_json.{} }}}}", key),
+            "device_class": device_class,
+            "unit_of_measurement": unit,
+            "state_class": state_class,
+            "availability": &availability,
+            "availability_mode": "all",
+            "device": &device,
+        });
+        messages.insert(
+            format!("{}/sensor/{}/config", discovery_prefix, sensor_id),
+            sensor.to_string(),
+        );
+    }
+    messages
+}
+
+/// Retained discovery message announcing the presets as a select, keyed by
+/// config topic. Entities that disappear are removed by clearing their config
+/// topic.
+pub(crate) fn preset_discovery_messages(
+    discovery_prefix: &str,
+    bridge_prefix: &str,
+    presets: &HashMap<String, Preset>,
+) -> HashMap<String, String> {
+    let mut messages: HashMap<String, String> = HashMap::new();
+
+    let preset_names = get_preset_names(presets).unwrap_or_default();
+    if !preset_names.is_empty() {
+        let id = object_id(bridge_prefix, "preset");
+        // States that aren't a preset, like "Custom", show as unknown
+        let select = json!({
+            "name": "Preset",
+            "unique_id": &id,
+            "object_id": &id,
+            "command_topic": format!("{}/preset/set", bridge_prefix),
+            "state_topic": format!("{}/preset/state", bridge_prefix),
+            "value_template": format!(
+                "{{{{ value if value in {} else 'None' }}}}",
+                Value::Array(preset_names.clone())
+            ),
+            "options": preset_names,
+            "availability_topic": format!("{}/availability", bridge_prefix),
+            "device": hub_device(bridge_prefix),
+        });
+        messages.insert(
+            format!("{}/select/{}/config", discovery_prefix, id),
+            select.to_string(),
+        );
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::kasa::KasaPlug;
+    use crate::models::kasa_network_models::EmeterReading;
+
+    #[test]
+    fn test_discovery_messages() {
+        let mut plug = KasaPlug::new(
+            "192.168.0.20".to_string(),
+            "Desk Lamp".to_string(),
+            "office".to_string(),
+            vec![],
+        );
+        let relay = Box::new(KasaPlug::new(
+            "192.168.0.20".to_string(),
+            "Desk Lamp".to_string(),
+            "office".to_string(),
+            vec![],
+        )) as RelayType;
+        let presets = HashMap::from([(
+            "Evening".to_string(),
+            Preset {
+                name: "Evening".to_string(),
+                enabled: true,
+                relays: HashMap::new(),
+                levels: HashMap::new(),
+            },
+        )]);
+
+        let messages = relay_discovery_messages("homeassistant", "remoterelay", &relay);
+        assert_eq!(messages.len(), 1);
+        let switch: Value =
+            serde_json::from_str(&messages["homeassistant/switch/remoterelay_desk_lamp/config"])
+                .unwrap();
+        assert_eq!(switch["command_topic"], "remoterelay/Desk Lamp/set");
+        assert_eq!(switch["device"]["suggested_area"], "office");
+        let messages = preset_discovery_messages("homeassistant", "remoterelay", &presets);
+        let select: Value =
+            serde_json::from_str(&messages["homeassistant/select/remoterelay_preset/config"])
+                .unwrap();
+        assert_eq!(select["options"], json!(["Evening"]));
+
+        plug.emeter = Some(EmeterReading {
+            power: 12.5,
+            voltage: 230.0,
+            current: 0.05,
+            total: 1.2,
+        });
+        let relay = Box::new(plug) as RelayType;
+        let messages = relay_discovery_messages("homeassistant", "remoterelay", &relay);
+        assert_eq!(messages.len(), 5);
+        let energy: Value = serde_json::from_str(
+            &messages["homeassistant/sensor/remoterelay_desk_lamp_total/config"],
+        )
+        .unwrap();
+        assert_eq!(energy["value_template"], "{{ value_json.total }}");
+        assert_eq!(energy["state_class"], "total_increasing");
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use crate::drivers::http::HttpRequest;
+use crate::drivers::state_parsing::render;
+use crate::utils::kasa_plug_network_functions::retries;
+
+/// How long a request waits for its response unless it sets a `timeout`.
+/// Webhooks can be cloud services, so this is well above the device timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn to_io_error(url: &str, error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(code @ (401 | 403), _) => Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} answered with status {}", url, code),
+        ),
+        ureq::Error::Status(code, _) => {
+            Error::other(format!("{} answered with status {}", url, code))
+        }
+        ureq::Error::Transport(transport) => Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Can't connect to {}: {}", url, transport),
+        ),
+    }
+}
+
+fn method(request: &HttpRequest) -> String {
+    match (&request.method, &request.body) {
+        (Some(method), _) => method.to_uppercase(),
+        (None, Some(_)) => "POST".to_string(),
+        (None, None) => "GET".to_string(),
+    }
+}
+
+/// Whether a failed request can be sent again. A request that timed out may
+/// still have reached the device, so only ones that are safe to repeat are.
+fn retryable(request: &HttpRequest) -> bool {
+    request.idempotent || matches!(method(request).as_str(), "GET" | "HEAD")
+}
+
+fn send_once(request: &HttpRequest, variables: &[(&str, &str)]) -> Result<String, Error> {
+    let url = render(&request.url, variables);
+    let body = request.body.as_deref().map(|body| render(body, variables));
+    let method = method(request);
+    let timeout = request
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT);
+
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let mut call = agent.request(&method, &url);
+    for (header, value) in &request.headers {
+        call = call.set(header, &render(value, variables));
+    }
+
+    let response = match body {
+        Some(body) => call.send_string(&body),
+        None => call.call(),
+    };
+    response
+        .map_err(|error| to_io_error(&url, error))?
+        .into_string()
+}
+
+/// Sends `request` with its placeholders filled in from `variables` and
+/// returns the response body. Requests that are safe to repeat are retried
+/// `--device-retries` times.
+pub(crate) fn send(request: &HttpRequest, variables: &[(&str, &str)]) -> Result<String, Error> {
+    let retries = match retryable(request) {
+        true => retries(),
+        false => 0,
+    };
+    let mut attempt = 0;
+    loop {
+        match send_once(request, variables) {
+            Err(error) if attempt < retries && error.kind() != ErrorKind::PermissionDenied => {
+                attempt += 1
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_repeatable_requests_are_retried() {
+        let get = HttpRequest {
+            url: "http://{{ip}}/status".to_string(),
+            ..HttpRequest::default()
+        };
+        let toggle = HttpRequest {
+            body: Some("{\"toggle\": true}".to_string()),
+            ..get.clone()
+        };
+        let put = HttpRequest {
+            method: Some("put".to_string()),
+            idempotent: true,
+            ..toggle.clone()
+        };
+
+        assert!(retryable(&get));
+        assert_eq!(method(&toggle), "POST");
+        assert!(!retryable(&toggle));
+        assert!(retryable(&put));
+    }
+}
@@ -1,14 +1,33 @@
 #![allow(dead_code, non_snake_case)]
 use serde_json::json;
 use serde_json::Value;
-use std::convert::TryFrom;
 use std::io::{Error, ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::time::Duration;
 use std::vec;
 
-const TIMEOUT: Duration = Duration::from_millis(300);
+pub const DEFAULT_TIMEOUT_MS: u64 = 300;
+pub const DEFAULT_RETRIES: u32 = 0;
+
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_MS);
+static RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_RETRIES);
+
+/// Sets the timeout of every device request and how many times a failed
+/// request is retried.
+pub fn configure(timeout: Duration, retries: u32) {
+    TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    RETRIES.store(retries, Ordering::Relaxed);
+}
+
+pub(crate) fn timeout() -> Duration {
+    Duration::from_millis(TIMEOUT_MS.load(Ordering::Relaxed))
+}
+
+pub(crate) fn retries() -> u32 {
+    RETRIES.load(Ordering::Relaxed)
+}
 
 pub fn decrypt(string: Vec<u8>) -> String {
     let key: u8 = 171;
@@ -22,7 +41,7 @@ pub fn decrypt(string: Vec<u8>) -> String {
     result
 }
 
-pub fn encrypt(string: &String) -> Vec<u8> {
+pub fn encrypt(string: &str) -> Vec<u8> {
     let mut key: u8 = 171;
     let mut result: Vec<u8> = vec![];
     result.extend_from_slice(&(string.len() as u32).to_be_bytes());
@@ -35,30 +54,52 @@ pub fn encrypt(string: &String) -> Vec<u8> {
     result
 }
 
-pub fn send<T: serde::de::DeserializeOwned>(ip: &String, cmd: &String) -> Result<T, Error> {
+pub fn send<T: serde::de::DeserializeOwned>(ip: &str, cmd: &str) -> Result<T, Error> {
+    let retries = retries();
+    let mut attempt = 0;
+    loop {
+        match send_once::<T>(ip, cmd) {
+            Err(_) if attempt < retries => attempt += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Largest response accepted from a plug, far above what any of them send.
+const MAX_RESPONSE_LENGTH: usize = 1 << 20;
+
+/// Reads one length-prefixed response and decrypts it.
+fn read_response(stream: &mut impl Read) -> Result<String, Error> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_be_bytes(header) as usize;
+    if length > MAX_RESPONSE_LENGTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Response claims to be {} bytes long", length),
+        ));
+    }
+
+    let mut data = vec![0; length];
+    stream.read_exact(&mut data)?;
+    Ok(decrypt(data))
+}
+
+fn send_once<T: serde::de::DeserializeOwned>(ip: &str, cmd: &str) -> Result<T, Error> {
     const PORT: u16 = 9999;
-    let addr = (ip.as_str(), PORT)
+    let addr = (ip, PORT)
         .to_socket_addrs()?
         .next()
         .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid IP address"))?;
 
-    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout())?;
+    stream.set_read_timeout(Some(timeout()))?;
+    stream.set_write_timeout(Some(timeout()))?;
 
     let encrypted = encrypt(cmd);
     stream.write_all(&encrypted)?;
-    let mut data = vec![0; 4096];
-    stream.read(&mut data)?;
-
-    let a_ref: &[u8] = &data[..4];
-    let b = match <[u8; 4]>::try_from(a_ref) {
-        Ok(result) => i32::from_be_bytes(result),
-        Err(error) => return Err(Error::new(ErrorKind::InvalidData, error)),
-    };
-
-    let end_pos: i32 = b + 4i32;
-
-    let decrypted = decrypt(data[4..end_pos as usize].to_vec());
-    let json_data: T = serde_json::from_str::<T>(&decrypted.as_str())?;
+    let decrypted = read_response(&mut stream)?;
+    let json_data: T = serde_json::from_str::<T>(decrypted.as_str())?;
     Ok(json_data)
 }
 
@@ -77,3 +118,26 @@ pub fn wlan_scan(ip: String) -> Result<Value, Error> {
     let cmd = json!({"netif": {"get_scaninfo": {"refresh": 0}}});
     send::<Value>(&ip, &cmd.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_reading_framed_responses() {
+        let response = encrypt(r#"{"system": {}}"#);
+        assert_eq!(
+            read_response(&mut Cursor::new(response.clone())).unwrap(),
+            r#"{"system": {}}"#
+        );
+
+        let truncated = &response[..response.len() - 2];
+        let error = read_response(&mut Cursor::new(truncated)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        let oversized = u32::MAX.to_be_bytes();
+        let error = read_response(&mut Cursor::new(oversized)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,15 +1,61 @@
-use crate::models::config_models::Config;
-use crate::utils::local_config_utils::load_local_config;
-use crate::utils::mongodb_utils::load_mongo_config;
-use std::io::{Error, ErrorKind};
+use crate::utils::local_config_utils::{load_config_from_file, LoadedConfig};
+use crate::utils::mongodb_utils::{mongo_connection, MongoSettings};
+use crate::utils::sqlite_config_utils::load_sqlite_config;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
-use tokio::runtime::Runtime;
 
-#[derive(Debug, Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
 pub(crate) enum ConfigLocation {
+    #[value(alias = "mongo")]
     MONGODB,
     LOCAL,
+    SQLITE,
+}
+
+/// Where to load the config from, plus the backend specific settings.
+#[derive(Debug, Clone)]
+pub(crate) struct ConfigSettings {
+    pub(crate) location: ConfigLocation,
+    pub(crate) config_file: PathBuf,
+    pub(crate) sqlite_path: String,
+    pub(crate) mongo: MongoSettings,
+    /// Set while a watcher is sending config changes to the data thread, in
+    /// which case the periodic refresh doesn't re-read the config.
+    pub(crate) watched: Arc<AtomicBool>,
+}
+
+impl ConfigSettings {
+    pub(crate) fn watched(&self) -> bool {
+        self.watched.load(Ordering::Relaxed)
+    }
+}
+
+/// Marks the config as watched for as long as it's held, so the periodic
+/// refresh goes back to re-reading the config once a watcher thread exits.
+pub(crate) struct WatchGuard(Arc<AtomicBool>);
+
+impl WatchGuard {
+    pub(crate) fn new(watched: &Arc<AtomicBool>) -> Self {
+        watched.store(true, Ordering::Relaxed);
+        WatchGuard(watched.clone())
+    }
+
+    /// Marks whether the watcher is currently receiving changes, for watchers
+    /// that lose and regain their source while running.
+    pub(crate) fn set(&self, watching: bool) {
+        self.0.store(watching, Ordering::Relaxed);
+    }
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
 impl std::fmt::Display for ConfigLocation {
@@ -17,26 +63,23 @@ impl std::fmt::Display for ConfigLocation {
         let location = match *self {
             ConfigLocation::MONGODB => "MongoDB",
             ConfigLocation::LOCAL => "Local",
+            ConfigLocation::SQLITE => "SQLite",
         };
         write!(f, "{}", location)
     }
 }
 
-pub fn load_config(config_location: ConfigLocation) -> JoinHandle<Result<Config, Error>> {
-    thread::spawn(move || {
-        let rt = Runtime::new().expect("Could not create runtime");
-
-        match config_location {
-            ConfigLocation::MONGODB => {
-                let mongodb_config = rt.block_on(load_mongo_config());
+/// Reads the config from its backend without connecting to any relays, that is
+/// left to the caller so unchanged relays can be kept on a refresh.
+pub(crate) fn load_config(settings: &ConfigSettings) -> JoinHandle<Result<LoadedConfig, Error>> {
+    let settings = settings.clone();
+    thread::spawn(move || match settings.location {
+        ConfigLocation::MONGODB => mongo_connection(&settings.mongo)
+            .and_then(|connection| connection.read_config(&settings.mongo))
+            .map_err(Error::other),
 
-                match mongodb_config {
-                    Ok(config) => Ok(config),
-                    Err(error) => Err(Error::new(ErrorKind::Other, error)),
-                }
-            }
+        ConfigLocation::LOCAL => load_config_from_file(&settings.config_file),
 
-            ConfigLocation::LOCAL => load_local_config(),
-        }
+        ConfigLocation::SQLITE => load_sqlite_config(&settings.sqlite_path),
     })
 }
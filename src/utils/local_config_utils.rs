@@ -1,60 +1,205 @@
 use std::collections::HashMap;
 
-use crate::models::config_models::{Config, ConfigRelay, ConfigRelayType};
+use crate::drivers::option_schemas;
+use crate::models::automations::Automation;
+use crate::models::config_models::{restore_policies, Config, ConfigRelay, ServerSettings};
 use crate::models::presets::Preset;
-use crate::models::relays::{KasaMultiPlug, KasaPlug};
-use crate::models::relays::{RelayActions, RelayType};
+use crate::models::relays::{RelayType, TrackedRelay};
+use futures::executor::block_on;
+use futures::future::join_all;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{RootSchema, Schema};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::from_str;
+use std::fmt::Display;
 use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Relay config as written in a config file, before any relay is connected.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[schemars(title = "RemoteRelay config")]
 pub struct LoadedConfig {
-    relays: Vec<ConfigRelay>,
-    presets: Vec<Preset>,
+    pub(crate) relays: Vec<ConfigRelay>,
+    pub(crate) presets: Vec<Preset>,
+    #[serde(default)]
+    pub(crate) automations: Vec<Automation>,
+    /// Server settings, read once at startup
+    #[serde(default, skip_serializing_if = "ServerSettings::is_empty")]
+    pub(crate) server: ServerSettings,
 }
 
-pub fn load_config_from_file() -> Result<LoadedConfig, std::io::Error> {
-    match fs::read_to_string("config.json") {
-        Err(_) => panic!("Couldn't find 'config.json'"),
-        Ok(data) => Ok(from_str(data.as_str())?),
+#[derive(Debug, Deserialize)]
+struct ServerSection {
+    #[serde(default)]
+    server: ServerSettings,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ConfigFormat {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub(crate) fn from_path(path: &Path) -> Result<ConfigFormat, Error> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match extension.as_str() {
+            "json" => Ok(ConfigFormat::Json),
+            "json5" => Ok(ConfigFormat::Json5),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported config format '{}', expected .json, .json5, .yaml, .yml or .toml",
+                    path.display()
+                ),
+            )),
+        }
     }
 }
 
-fn load_relays(from_config: Vec<ConfigRelay>) -> HashMap<String, RelayType> {
-    let mut relays: HashMap<String, RelayType> = HashMap::new();
+fn parse_error(path: &Path, location: Option<(usize, usize)>, message: impl Display) -> Error {
+    let message = match location {
+        Some((line, column)) => format!(
+            "Invalid config {} at line {} column {}: {}",
+            path.display(),
+            line,
+            column,
+            message
+        ),
+        None => format!("Invalid config {}: {}", path.display(), message),
+    };
+    Error::new(ErrorKind::InvalidData, message)
+}
 
-    for relay in from_config {
-        match relay.relay_type {
-            ConfigRelayType::KasaMultiPlug => {
-                let plugs = KasaMultiPlug::new(relay.ip, relay.names, relay.room, relay.tags);
-
-                if plugs.is_ok() {
-                    for mut plug in plugs.unwrap() {
-                        if plug.connected().is_ok() {
-                            relays.insert(plug.name.clone(), RelayType::KasaMultiPlug(plug));
-                        }
-                    }
-                }
+/// One-based line and column of a byte offset into `data`.
+fn line_column(data: &str, offset: usize) -> (usize, usize) {
+    let before = &data[..offset.min(data.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|index| index + 1).unwrap_or(0) + 1;
+    (line, column)
+}
+
+/// JSON Schema of the config file format, generated from the config types and
+/// the options each relay driver reads.
+pub(crate) fn config_schema() -> RootSchema {
+    let mut gen = SchemaGenerator::default();
+    let mut schema = gen.root_schema_for::<LoadedConfig>();
+    let options = option_schemas(&mut gen);
+    if let Some(Schema::Object(relay)) = schema.definitions.get_mut("ConfigRelay") {
+        relay
+            .subschemas()
+            .all_of
+            .get_or_insert_with(Vec::new)
+            .extend(options);
+    }
+    // Types only the options use, such as `HttpRequest`
+    for (name, definition) in gen.take_definitions() {
+        schema.definitions.entry(name).or_insert(definition);
+    }
+    schema
+}
+
+/// Parses config file contents in the format given by the file's extension.
+pub(crate) fn parse_config<T: DeserializeOwned>(path: &Path, data: &str) -> Result<T, Error> {
+    match ConfigFormat::from_path(path)? {
+        ConfigFormat::Json => serde_json::from_str(data)
+            .map_err(|error| parse_error(path, Some((error.line(), error.column())), &error)),
+        ConfigFormat::Json5 => json5::from_str(data).map_err(|error| match &error {
+            json5::Error::Message {
+                msg,
+                location: Some(location),
+            } => parse_error(path, Some((location.line, location.column)), msg),
+            json5::Error::Message {
+                msg,
+                location: None,
+            } => parse_error(path, None, msg),
+        }),
+        ConfigFormat::Yaml => serde_yaml::from_str(data).map_err(|error| {
+            let location = error
+                .location()
+                .map(|location| (location.line(), location.column()));
+            parse_error(path, location, &error)
+        }),
+        ConfigFormat::Toml => toml::from_str(data).map_err(|error| {
+            let location = error.span().map(|span| line_column(data, span.start));
+            parse_error(path, location, error.message())
+        }),
+    }
+}
+
+pub fn load_config_from_file(path: &Path) -> Result<LoadedConfig, Error> {
+    let data = fs::read_to_string(path).map_err(|error| {
+        Error::new(
+            error.kind(),
+            format!("Couldn't read config file '{}': {}", path.display(), error),
+        )
+    })?;
+
+    parse_config(path, &data)
+}
+
+/// Writes a config file in the format given by its extension. JSON5 files are
+/// written as plain JSON, which JSON5 readers accept.
+pub(crate) fn write_config_file(path: &Path, config: &LoadedConfig) -> Result<(), Error> {
+    let data = match ConfigFormat::from_path(path)? {
+        ConfigFormat::Json | ConfigFormat::Json5 => serde_json::to_string_pretty(config)?,
+        ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(Error::other)?,
+        ConfigFormat::Toml => toml::to_string_pretty(config).map_err(Error::other)?,
+    };
+
+    fs::write(path, data)
+}
+
+/// Reads only the `server` section of a config file.
+pub(crate) fn load_server_settings(path: &Path) -> Result<ServerSettings, Error> {
+    let data = fs::read_to_string(path)?;
+    Ok(parse_config::<ServerSection>(path, &data)?.server)
+}
+
+/// Connects every entry at once, so an unreachable device doesn't hold up the
+/// others for its timeout.
+pub(crate) fn load_relays(from_config: Vec<ConfigRelay>) -> HashMap<String, RelayType> {
+    let connecting = from_config.into_iter().filter_map(|relay| {
+        let driver = match relay.driver() {
+            Ok(driver) => driver,
+            Err(error) => {
+                rocket::log::private::warn!("Skipping relay entry: {}", error);
+                return None;
             }
-            ConfigRelayType::KasaPlug => {
-                let mut plug = KasaPlug::new(relay.ip, relay.name, relay.room, relay.tags);
-                match plug.connected() {
-                    Ok(_) => {
-                        relays.insert(plug.name.clone(), RelayType::KasaPlug(plug));
-                    }
-                    Err(error) => {
-                        rocket::log::private::error!("Unable to connnect {} {}", plug.name, error)
-                    }
+        };
+        let names = relay.relay_names().join(", ");
+        Some(async move { (names, driver.connect(relay).await) })
+    });
+
+    let mut relays: HashMap<String, RelayType> = HashMap::new();
+    for (names, connected) in block_on(join_all(connecting)) {
+        match connected {
+            Ok(connected) => {
+                for relay in connected {
+                    relays.insert(relay.name().clone(), TrackedRelay::track(relay));
                 }
             }
+            Err(error) => {
+                rocket::log::private::error!("Unable to connnect {} {}", names, error)
+            }
         }
     }
 
     relays
 }
 
-fn load_presets(from_config: Vec<Preset>) -> HashMap<String, Preset> {
+pub(crate) fn load_presets(from_config: Vec<Preset>) -> HashMap<String, Preset> {
     let mut presets: HashMap<String, Preset> = HashMap::new();
     for preset in from_config {
         presets.insert(preset.name.clone(), preset);
@@ -66,6 +211,7 @@ fn load_presets(from_config: Vec<Preset>) -> HashMap<String, Preset> {
             name: "Custom".to_string(),
             enabled: false,
             relays: HashMap::new(),
+            levels: HashMap::new(),
         });
 
     presets
@@ -74,39 +220,188 @@ fn load_presets(from_config: Vec<Preset>) -> HashMap<String, Preset> {
             name: "FullOff".to_string(),
             enabled: false,
             relays: HashMap::new(),
+            levels: HashMap::new(),
         });
 
     presets
 }
 
-pub fn load_local_config() -> Result<Config, std::io::Error> {
-    let loaded_config = load_config_from_file()?;
-
+/// Connects to the relays of an unprocessed config and fills in the default presets.
+pub(crate) fn build_config(loaded_config: LoadedConfig) -> Config {
+    let restore_policies = restore_policies(&loaded_config.relays);
     let relays: HashMap<String, RelayType> = load_relays(loaded_config.relays);
     let presets: HashMap<String, Preset> = load_presets(loaded_config.presets);
-    Ok(Config { relays, presets })
+    Config {
+        relays,
+        presets,
+        automations: loaded_config.automations,
+        restore_policies,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::config_models::LogLevel;
 
     #[test]
     fn test_loading_from_file_success() {
-        let loaded_config = load_config_from_file();
+        let loaded_config = load_config_from_file(Path::new("config.json"));
         assert!(loaded_config.is_ok())
     }
 
     #[test]
     fn test_loading_relays_formatted_success() {
-        let loaded_config = load_config_from_file().expect("Config File Not Found");
+        let loaded_config =
+            load_config_from_file(Path::new("config.json")).expect("Config File Not Found");
         let relays = load_relays(loaded_config.relays);
         assert!(!relays.is_empty())
     }
 
+    #[test]
+    fn test_detecting_config_format() {
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("/etc/remoterelay/config.YML")).unwrap(),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("config.json5")).unwrap(),
+            ConfigFormat::Json5
+        );
+        assert!(ConfigFormat::from_path(Path::new("config.ini")).is_err());
+    }
+
+    #[test]
+    fn test_parsing_each_format() {
+        let json5 = r#"{
+            // Comments are allowed in JSON5
+            relays: [{type: "KasaPlug", name: "DeskLamp", ip: "192.168.0.10", room: "office"}],
+            presets: [],
+        }"#;
+        let yaml = "relays:\n  - type: KasaPlug\n    name: DeskLamp\n    ip: 192.168.0.10\n    room: office\npresets: []\n";
+        let toml = "presets = []\n\n[[relays]]\ntype = \"KasaPlug\"\nname = \"DeskLamp\"\nip = \"192.168.0.10\"\nroom = \"office\"\n";
+
+        for (path, data) in [
+            ("config.json5", json5),
+            ("config.yaml", yaml),
+            ("config.toml", toml),
+        ] {
+            let loaded_config: LoadedConfig = parse_config(Path::new(path), data).unwrap();
+            assert_eq!(loaded_config.relays[0].name, "DeskLamp", "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors_report_line_and_column() {
+        let error = parse_config::<LoadedConfig>(
+            Path::new("config.json"),
+            "{\n  \"relays\": [\n    {\"type\": 3}\n  ]\n}",
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 3"), "{}", error);
+
+        let error = parse_config::<LoadedConfig>(
+            Path::new("config.toml"),
+            "presets = []\nrelays = [\n  { type = \"KasaPlug\" ip = \"x\" }\n]\n",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("line 3"), "{}", error);
+    }
+
+    #[cfg(all(
+        feature = "esphome",
+        feature = "gpio",
+        feature = "shelly",
+        feature = "tasmota",
+        feature = "mqtt",
+        feature = "http",
+        feature = "exec"
+    ))]
+    #[test]
+    fn test_config_schema_follows_types() {
+        let schema = serde_json::to_value(config_schema()).unwrap();
+
+        assert_eq!(schema["required"], serde_json::json!(["presets", "relays"]));
+        assert_eq!(
+            schema["definitions"]["ConfigRelayType"]["enum"],
+            serde_json::json!([
+                "KasaPlug",
+                "KasaMultiPlug",
+                "KasaDimmer",
+                "KasaBulb",
+                "Shelly",
+                "Tasmota",
+                "Mqtt",
+                "EspHome",
+                "Gpio",
+                "Http",
+                "Exec",
+                "Group",
+                "Virtual"
+            ])
+        );
+        assert!(schema["definitions"]["ConfigRelay"]["properties"]["type"].is_object());
+
+        let mqtt = schema["definitions"]["ConfigRelay"]["allOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|rule| rule["if"]["properties"]["type"]["const"] == "Mqtt")
+            .unwrap();
+        let allowed = mqtt["then"]["allOf"][1]["propertyNames"]["enum"]
+            .as_array()
+            .unwrap();
+        assert!(allowed.contains(&serde_json::json!("commandTopic")));
+        assert!(!allowed.contains(&serde_json::json!("encryptionKey")));
+        assert_eq!(
+            mqtt["then"]["allOf"][0]["properties"]["commandTopic"]["type"],
+            serde_json::json!(["string", "null"])
+        );
+        assert!(schema["definitions"]["HttpRequest"]["properties"]["url"].is_object());
+    }
+
+    #[test]
+    fn test_server_section_fills_unset_flags() {
+        let toml = "[server]\nport = 8080\ndeviceTimeout = 500\nlogLevel = \"debug\"\n";
+        let file: ServerSection = parse_config(Path::new("config.toml"), toml).unwrap();
+        let flags = ServerSettings {
+            port: Some(9000),
+            ..ServerSettings::default()
+        };
+
+        let server = flags.or(file.server);
+        assert_eq!(server.port, Some(9000));
+        assert_eq!(server.device_timeout, Some(500));
+        assert_eq!(server.log_level, Some(LogLevel::Debug));
+        assert_eq!(server.refresh_interval, None);
+    }
+
+    #[test]
+    fn test_unknown_relay_type_is_skipped() {
+        let loaded_config: LoadedConfig = parse_config(
+            Path::new("config.json"),
+            r#"{"relays": [
+                {"type": "Toaster", "name": "Toaster", "room": "kitchen"},
+                {"type": "Virtual", "name": "Scene", "room": "office"}
+            ], "presets": []}"#,
+        )
+        .unwrap();
+
+        let relays = load_relays(loaded_config.relays);
+        assert_eq!(relays.keys().collect::<Vec<_>>(), vec!["Scene"]);
+    }
+
+    #[test]
+    fn test_missing_config_file_is_an_error() {
+        let error = load_config_from_file(Path::new("does/not/exist.json")).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
     #[test]
     fn test_loading_presets_formatted_success() {
-        let loaded_config = load_config_from_file().expect("Config File Not Found");
+        let loaded_config =
+            load_config_from_file(Path::new("config.json")).expect("Config File Not Found");
         let presets = load_presets(loaded_config.presets);
         assert!(!presets.is_empty())
     }
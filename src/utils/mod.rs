@@ -1,5 +1,29 @@
+pub(crate) mod automation_handling;
+pub(crate) mod config_diff;
+pub(crate) mod config_transfer;
+pub(crate) mod config_validation;
+pub(crate) mod config_watcher;
 pub mod data_thread_handling;
+#[cfg(feature = "esphome")]
+pub(crate) mod esphome_network_functions;
+#[cfg(feature = "exec")]
+pub(crate) mod exec_functions;
+#[cfg(feature = "gpio")]
+pub(crate) mod gpio_functions;
+pub(crate) mod history_store;
+pub(crate) mod home_assistant_discovery;
+#[cfg(feature = "http")]
+pub(crate) mod http_relay_functions;
 pub mod kasa_plug_network_functions;
 pub(crate) mod load_config;
 pub mod local_config_utils;
 pub mod mongodb_utils;
+pub(crate) mod mongodb_watcher;
+pub(crate) mod mqtt_bridge;
+pub(crate) mod mqtt_network_functions;
+pub(crate) mod relay_state_file;
+#[cfg(feature = "shelly")]
+pub(crate) mod shelly_network_functions;
+pub(crate) mod sqlite_config_utils;
+#[cfg(feature = "tasmota")]
+pub(crate) mod tasmota_network_functions;
@@ -1,77 +1,241 @@
-use crate::models::config_models::{Config, ConfigRelay, ConfigRelayType};
+use crate::models::automations::Automation;
+use crate::models::config_models::{ConfigRelay, ServerSettings};
 
 use crate::models::presets::Preset;
-use crate::models::relays::{KasaMultiPlug, KasaPlug, RelayActions, RelayType};
+use crate::utils::local_config_utils::LoadedConfig;
 
 use dotenv::dotenv;
-use mongodb::{bson::doc, options::ClientOptions, Client};
+use mongodb::bson::Document;
+use mongodb::options::{Tls, TlsOptions};
+use mongodb::{bson::doc, options::ClientOptions, Client, ClientSession};
 use mongodb::{Collection, Database};
 use rocket::futures::TryStreamExt;
+use rocket::log;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::env;
 use std::env::VarError;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+// Defaults shared by the flags and `MongoSettings::default`
+const DEFAULT_DATABASE: &str = "HomeConfig";
+const DEFAULT_RELAYS_COLLECTION: &str = "Relays";
+const DEFAULT_PRESETS_COLLECTION: &str = "Presets";
+const DEFAULT_AUTOMATIONS_COLLECTION: &str = "Automations";
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_SERVER_SELECTION_TIMEOUT: u64 = 30;
+
+/// Connection settings and names used with `--config mongodb`. Each can be
+/// given as a flag or an environment variable, including from `.env`.
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct MongoSettings {
+    /// Connection string, can also be set with MONGODB_URL in `.env`
+    #[arg(long = "mongo-url", env = "MONGODB_URL", hide_env_values = true)]
+    pub(crate) url: Option<String>,
+
+    /// Database holding the config, use a different one per home to share a cluster
+    #[arg(
+        long = "mongo-db",
+        env = "MONGODB_DATABASE",
+        default_value = DEFAULT_DATABASE
+    )]
+    pub(crate) database: String,
+
+    #[arg(
+        long = "mongo-relays-collection",
+        env = "MONGODB_RELAYS_COLLECTION",
+        default_value = DEFAULT_RELAYS_COLLECTION
+    )]
+    pub(crate) relays_collection: String,
+
+    #[arg(
+        long = "mongo-presets-collection",
+        env = "MONGODB_PRESETS_COLLECTION",
+        default_value = DEFAULT_PRESETS_COLLECTION
+    )]
+    pub(crate) presets_collection: String,
+
+    #[arg(
+        long = "mongo-automations-collection",
+        env = "MONGODB_AUTOMATIONS_COLLECTION",
+        default_value = DEFAULT_AUTOMATIONS_COLLECTION
+    )]
+    pub(crate) automations_collection: String,
+
+    /// Force TLS on or off, otherwise the connection string decides
+    #[arg(long = "mongo-tls", env = "MONGODB_TLS")]
+    pub(crate) tls: Option<bool>,
+
+    /// CA certificate file used to verify the server
+    #[arg(long = "mongo-tls-ca-file", env = "MONGODB_TLS_CA_FILE")]
+    pub(crate) tls_ca_file: Option<PathBuf>,
+
+    /// Client certificate and key file for x.509 authentication
+    #[arg(long = "mongo-tls-cert-key-file", env = "MONGODB_TLS_CERT_KEY_FILE")]
+    pub(crate) tls_cert_key_file: Option<PathBuf>,
+
+    #[arg(
+        long = "mongo-tls-allow-invalid-certificates",
+        env = "MONGODB_TLS_ALLOW_INVALID_CERTIFICATES"
+    )]
+    pub(crate) tls_allow_invalid_certificates: bool,
+
+    /// Seconds to wait when opening a connection
+    #[arg(
+        long = "mongo-connect-timeout",
+        env = "MONGODB_CONNECT_TIMEOUT",
+        default_value_t = DEFAULT_CONNECT_TIMEOUT
+    )]
+    pub(crate) connect_timeout: u64,
+
+    /// Seconds to wait for a usable server before a query fails
+    #[arg(
+        long = "mongo-server-selection-timeout",
+        env = "MONGODB_SERVER_SELECTION_TIMEOUT",
+        default_value_t = DEFAULT_SERVER_SELECTION_TIMEOUT
+    )]
+    pub(crate) server_selection_timeout: u64,
+}
+
+impl Default for MongoSettings {
+    fn default() -> Self {
+        MongoSettings {
+            url: None,
+            database: DEFAULT_DATABASE.to_string(),
+            relays_collection: DEFAULT_RELAYS_COLLECTION.to_string(),
+            presets_collection: DEFAULT_PRESETS_COLLECTION.to_string(),
+            automations_collection: DEFAULT_AUTOMATIONS_COLLECTION.to_string(),
+            tls: None,
+            tls_ca_file: None,
+            tls_cert_key_file: None,
+            tls_allow_invalid_certificates: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            server_selection_timeout: DEFAULT_SERVER_SELECTION_TIMEOUT,
+        }
+    }
+}
+
+impl MongoSettings {
+    pub(crate) fn collections(&self) -> [&str; 3] {
+        [
+            &self.relays_collection,
+            &self.presets_collection,
+            &self.automations_collection,
+        ]
+    }
+}
 
 fn load_mongo_url() -> Result<String, VarError> {
     dotenv().ok();
     env::var("MONGODB_URL")
 }
 
-async fn load_mongo_client() -> mongodb::error::Result<Client> {
-    let mongodb_url = load_mongo_url().expect("Unable to read MONGODB_URL from .env");
-    let client_options = ClientOptions::parse(&mongodb_url).await?;
-    Client::with_options(client_options)
+async fn mongo_client_options(settings: &MongoSettings) -> mongodb::error::Result<ClientOptions> {
+    let mongodb_url = match settings.url.clone().or_else(|| load_mongo_url().ok()) {
+        Some(mongodb_url) => mongodb_url,
+        None => {
+            return Err(mongodb::error::Error::custom(
+                "No Mongo connection string, set --mongo-url or MONGODB_URL",
+            ))
+        }
+    };
+
+    let mut client_options = ClientOptions::parse(&mongodb_url).await?;
+    client_options.connect_timeout = Some(Duration::from_secs(settings.connect_timeout));
+    client_options.server_selection_timeout =
+        Some(Duration::from_secs(settings.server_selection_timeout));
+
+    let tls_options = settings.tls_ca_file.is_some()
+        || settings.tls_cert_key_file.is_some()
+        || settings.tls_allow_invalid_certificates;
+    client_options.tls = match (settings.tls, tls_options) {
+        (Some(false), _) => Some(Tls::Disabled),
+        (Some(true), _) | (None, true) => Some(Tls::Enabled(
+            TlsOptions::builder()
+                .ca_file_path(settings.tls_ca_file.clone())
+                .cert_key_file_path(settings.tls_cert_key_file.clone())
+                .allow_invalid_certificates(settings.tls_allow_invalid_certificates)
+                .build(),
+        )),
+        (None, false) => client_options.tls,
+    };
+
+    Ok(client_options)
+}
+
+pub(crate) async fn load_mongo_client(settings: &MongoSettings) -> mongodb::error::Result<Client> {
+    Client::with_options(mongo_client_options(settings).await?)
+}
+
+/// Runtime and client shared by every Mongo config read, the watcher and
+/// imports, so a refresh reuses one connection pool. The client's background
+/// tasks run on the runtime, so both live for the rest of the process.
+pub(crate) struct MongoConnection {
+    runtime: Runtime,
+    client: Client,
+}
+
+static MONGO_CONNECTION: Mutex<Option<Arc<MongoConnection>>> = Mutex::new(None);
+
+/// The process wide Mongo connection, opened on first use. Must not be called
+/// from async code, since it blocks on its own runtime.
+pub(crate) fn mongo_connection(
+    settings: &MongoSettings,
+) -> mongodb::error::Result<Arc<MongoConnection>> {
+    let mut shared = MONGO_CONNECTION.lock().unwrap();
+    if let Some(connection) = shared.as_ref() {
+        return Ok(connection.clone());
+    }
+
+    let runtime = Runtime::new()?;
+    let client = runtime.block_on(load_mongo_client(settings))?;
+    let connection = Arc::new(MongoConnection { runtime, client });
+    *shared = Some(connection.clone());
+    Ok(connection)
+}
+
+impl MongoConnection {
+    pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    pub(crate) fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub(crate) fn database(&self, settings: &MongoSettings) -> Database {
+        self.client.database(&settings.database)
+    }
+
+    pub(crate) fn read_config(
+        &self,
+        settings: &MongoSettings,
+    ) -> Result<LoadedConfig, mongodb::error::Error> {
+        self.block_on(read_mongo_config(&self.database(settings), settings))
+    }
 }
 
-async fn find_mongo_relays(
+async fn find_mongo_config_relays(
     database: &Database,
-) -> Result<HashMap<String, RelayType>, mongodb::error::Error> {
-    let relays_collection: Collection<ConfigRelay> = database.collection("Relays");
+    settings: &MongoSettings,
+) -> Result<Vec<ConfigRelay>, mongodb::error::Error> {
+    let relays_collection: Collection<ConfigRelay> =
+        database.collection(&settings.relays_collection);
     let filter = doc! {};
     let query_result = relays_collection.find(filter).await;
-    let relay_query = query_result?.try_collect::<Vec<_>>().await?;
-
-    let mut relays: HashMap<String, RelayType> = HashMap::new();
-
-    for relay in relay_query {
-        match &relay.relay_type {
-            ConfigRelayType::KasaPlug => {
-                let mut plug = KasaPlug::new(
-                    relay.ip.clone(),
-                    relay.name.clone(),
-                    relay.room.clone(),
-                    relay.tags.clone(),
-                );
-
-                if plug.connected().is_ok() {
-                    relays.insert(plug.name.clone(), RelayType::KasaPlug(plug));
-                }
-            }
-            ConfigRelayType::KasaMultiPlug => {
-                let plugs = KasaMultiPlug::new(
-                    relay.ip.clone(),
-                    relay.names.clone(),
-                    relay.room.clone(),
-                    relay.tags.clone(),
-                );
-
-                if plugs.is_ok() {
-                    for mut plug in plugs? {
-                        if plug.connected().is_ok() {
-                            relays.insert(plug.name.clone(), RelayType::KasaMultiPlug(plug));
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    Ok(relays)
+    query_result?.try_collect::<Vec<_>>().await
 }
 
 async fn find_mongo_presets(
     database: &Database,
+    settings: &MongoSettings,
 ) -> Result<HashMap<String, Preset>, mongodb::error::Error> {
-    let presets_collection: Collection<Preset> = database.collection("Presets");
+    let presets_collection: Collection<Preset> = database.collection(&settings.presets_collection);
     let filter = doc! {};
     let query_result = presets_collection.find(filter).await;
 
@@ -89,6 +253,7 @@ async fn find_mongo_presets(
             name: "Custom".to_string(),
             enabled: false,
             relays: HashMap::new(),
+            levels: HashMap::new(),
         });
 
     presets
@@ -97,29 +262,356 @@ async fn find_mongo_presets(
             name: "FullOff".to_string(),
             enabled: false,
             relays: HashMap::new(),
+            levels: HashMap::new(),
         });
 
     Ok(presets)
 }
 
-pub async fn load_mongo_config() -> Result<Config, mongodb::error::Error> {
-    let client = load_mongo_client()
+async fn find_mongo_automations(
+    database: &Database,
+    settings: &MongoSettings,
+) -> Result<Vec<Automation>, mongodb::error::Error> {
+    let automations_collection: Collection<Automation> =
+        database.collection(&settings.automations_collection);
+    let filter = doc! {};
+    let query_result = automations_collection.find(filter).await;
+
+    query_result?.try_collect::<Vec<_>>().await
+}
+
+/// Reads the relays, presets and automations from an open database. Presets
+/// are sorted by name so two reads of the same config compare equal.
+pub(crate) async fn read_mongo_config(
+    database: &Database,
+    settings: &MongoSettings,
+) -> Result<LoadedConfig, mongodb::error::Error> {
+    let mut presets: Vec<Preset> = find_mongo_presets(database, settings)
+        .await?
+        .into_values()
+        .collect();
+    presets.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(LoadedConfig {
+        relays: find_mongo_config_relays(database, settings).await?,
+        presets,
+        automations: find_mongo_automations(database, settings).await?,
+        server: ServerSettings::default(),
+    })
+}
+
+/// Server error code for operations the deployment doesn't support, which a
+/// standalone server answers transactions with.
+const ILLEGAL_OPERATION: i32 = 20;
+
+fn transactions_unsupported(error: &mongodb::error::Error) -> bool {
+    matches!(
+        *error.kind,
+        mongodb::error::ErrorKind::Command(mongodb::error::CommandError { code, .. })
+            if code == ILLEGAL_OPERATION
+    )
+}
+
+/// Replaces the contents of the config collections in one transaction, so a
+/// failed write leaves the old config in place.
+async fn replace_in_transaction(
+    client: &Client,
+    settings: &MongoSettings,
+    config: &LoadedConfig,
+) -> Result<(), mongodb::error::Error> {
+    let database = client.database(&settings.database);
+    let mut session = client.start_session().await?;
+    session.start_transaction().await?;
+
+    // Dropping the session without committing aborts the transaction
+    replace_collection(
+        database.collection(&settings.relays_collection),
+        &config.relays,
+        &mut session,
+    )
+    .await?;
+    replace_collection(
+        database.collection(&settings.presets_collection),
+        &config.presets,
+        &mut session,
+    )
+    .await?;
+    replace_collection(
+        database.collection(&settings.automations_collection),
+        &config.automations,
+        &mut session,
+    )
+    .await?;
+
+    session.commit_transaction().await
+}
+
+async fn replace_collection<T: Serialize + Send + Sync>(
+    collection: Collection<T>,
+    documents: &[T],
+    session: &mut ClientSession,
+) -> Result<(), mongodb::error::Error> {
+    collection
+        .delete_many(doc! {})
+        .session(&mut *session)
+        .await?;
+    if !documents.is_empty() {
+        collection
+            .insert_many(documents)
+            .session(&mut *session)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Name of the collection a config collection is written to before it is
+/// renamed over the live one.
+fn staging_name(collection: &str) -> String {
+    format!("{}_import", collection)
+}
+
+async fn fill_staging<T: Serialize + Send + Sync>(
+    database: &Database,
+    collection: &str,
+    documents: &[T],
+) -> Result<(), mongodb::error::Error> {
+    let staging: Collection<T> = database.collection(&staging_name(collection));
+    staging.drop().await?;
+    database.create_collection(staging_name(collection)).await?;
+    if !documents.is_empty() {
+        staging.insert_many(documents).await?;
+    }
+    Ok(())
+}
+
+/// Name a live config collection is moved to while its staging copy takes
+/// its place, so it can be put back if a later rename fails.
+fn previous_name(collection: &str) -> String {
+    format!("{}_previous", collection)
+}
+
+async fn rename_collection(
+    client: &Client,
+    database: &str,
+    from: &str,
+    to: &str,
+) -> Result<(), mongodb::error::Error> {
+    client
+        .database("admin")
+        .run_command(doc! {
+            "renameCollection": format!("{}.{}", database, from),
+            "to": format!("{}.{}", database, to),
+            "dropTarget": true,
+        })
+        .await
+        .map(|_| ())
+}
+
+/// Checks that a staging collection holds every document written to it.
+async fn check_staging(
+    database: &Database,
+    collection: &str,
+    expected: usize,
+) -> Result<(), Error> {
+    let count = database
+        .collection::<Document>(&staging_name(collection))
+        .count_documents(doc! {})
+        .await
+        .map_err(Error::other)?;
+    if count != expected as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Staging collection {} holds {} documents instead of {}, nothing was replaced",
+                staging_name(collection),
+                count,
+                expected
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves the live collection aside, when there is one, and renames the
+/// staging copy into its place.
+async fn swap_in_staging(
+    client: &Client,
+    database: &str,
+    collection: &str,
+    exists: bool,
+) -> Result<(), mongodb::error::Error> {
+    if exists {
+        rename_collection(client, database, collection, &previous_name(collection)).await?;
+    }
+    rename_collection(client, database, &staging_name(collection), collection).await
+}
+
+/// Puts the collections moved aside by `swap_in_staging` back, and drops the
+/// ones that didn't exist before.
+async fn roll_back_staging(
+    client: &Client,
+    settings: &MongoSettings,
+    touched: &[&str],
+    existing: &[String],
+) -> Result<(), mongodb::error::Error> {
+    let database = client.database(&settings.database);
+    let names = database.list_collection_names().await?;
+    for collection in touched.iter().rev() {
+        if names.contains(&previous_name(collection)) {
+            rename_collection(
+                client,
+                &settings.database,
+                &previous_name(collection),
+                collection,
+            )
+            .await?;
+        } else if !existing.iter().any(|name| name == collection) {
+            database.collection::<Document>(collection).drop().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the config collections on servers without transactions. Every
+/// collection is written to a staging copy and checked before any live
+/// collection is touched. The copies are then renamed over the live
+/// collections one at a time, so this isn't atomic: a reader can see some
+/// collections replaced and others not. If a rename fails the replaced
+/// collections are renamed back, and if that fails too the error names the
+/// collections that hold the new config.
+async fn replace_through_staging(
+    client: &Client,
+    settings: &MongoSettings,
+    config: &LoadedConfig,
+) -> Result<(), Error> {
+    let database = client.database(&settings.database);
+    fill_staging(&database, &settings.relays_collection, &config.relays)
         .await
-        .expect("Unable to connect to client");
+        .map_err(Error::other)?;
+    fill_staging(&database, &settings.presets_collection, &config.presets)
+        .await
+        .map_err(Error::other)?;
+    fill_staging(
+        &database,
+        &settings.automations_collection,
+        &config.automations,
+    )
+    .await
+    .map_err(Error::other)?;
+
+    let expected = [
+        config.relays.len(),
+        config.presets.len(),
+        config.automations.len(),
+    ];
+    for (collection, expected) in settings.collections().into_iter().zip(expected) {
+        check_staging(&database, collection, expected).await?;
+    }
 
-    let home_config = client.database("HomeConfig");
+    // Leftovers of an earlier failed import would be restored by a roll back
+    for collection in settings.collections() {
+        database
+            .collection::<Document>(&previous_name(collection))
+            .drop()
+            .await
+            .map_err(Error::other)?;
+    }
 
-    let relays = find_mongo_relays(&home_config).await?;
-    let presets = find_mongo_presets(&home_config).await?;
+    let existing = database
+        .list_collection_names()
+        .await
+        .map_err(Error::other)?;
+    let mut touched: Vec<&str> = Vec::new();
+    for collection in settings.collections() {
+        touched.push(collection);
+        let exists = existing.iter().any(|name| name == collection);
+        let Err(error) = swap_in_staging(client, &settings.database, collection, exists).await
+        else {
+            continue;
+        };
+
+        let swapped = touched[..touched.len() - 1].join(", ");
+        return Err(
+            match roll_back_staging(client, settings, &touched, &existing).await {
+                Ok(()) => Error::other(format!(
+                    "Unable to replace collection {}, the previous config was put back: {}",
+                    collection, error
+                )),
+                Err(roll_back_error) => Error::other(format!(
+                    "Unable to replace collection {} ({}) or put the previous config back ({}), \
+                     already replaced: [{}], previous copies are kept as *_previous",
+                    collection, error, roll_back_error, swapped
+                )),
+            },
+        );
+    }
+
+    for collection in settings.collections() {
+        if let Err(error) = database
+            .collection::<Document>(&previous_name(collection))
+            .drop()
+            .await
+        {
+            log::warn_!("Unable to drop {}: {}", previous_name(collection), error);
+        }
+    }
+    Ok(())
+}
 
-    Ok(Config { relays, presets })
+/// Replaces the contents of the config collections with `config`, in a
+/// transaction when the deployment supports them and through staging
+/// collections otherwise.
+pub(crate) async fn write_mongo_config(
+    client: &Client,
+    settings: &MongoSettings,
+    config: &LoadedConfig,
+) -> Result<(), Error> {
+    match replace_in_transaction(client, settings, config).await {
+        Err(error) if transactions_unsupported(&error) => {
+            replace_through_staging(client, settings, config).await
+        }
+        result => result.map_err(Error::other),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::local_config_utils::load_relays;
     use dotenv::dotenv;
-    use mongodb::bson::Document;
+
+    #[test]
+    fn test_relay_document_ids_are_not_options() {
+        let document = doc! {
+            "_id": mongodb::bson::oid::ObjectId::new(),
+            "type": "Virtual",
+            "name": "Scene",
+            "room": "office",
+        };
+        let relay: ConfigRelay = mongodb::bson::from_document(document).unwrap();
+        assert!(relay.options.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_options_from_settings() {
+        let settings = MongoSettings {
+            url: Some("mongodb://localhost:27017".to_string()),
+            tls_ca_file: Some(PathBuf::from("ca.pem")),
+            connect_timeout: 3,
+            ..MongoSettings::default()
+        };
+
+        let client_options = mongo_client_options(&settings).await.unwrap();
+        assert_eq!(client_options.connect_timeout, Some(Duration::from_secs(3)));
+        assert!(matches!(client_options.tls, Some(Tls::Enabled(_))));
+
+        let settings = MongoSettings {
+            tls: Some(false),
+            ..settings
+        };
+        let client_options = mongo_client_options(&settings).await.unwrap();
+        assert!(matches!(client_options.tls, Some(Tls::Disabled)));
+    }
 
     #[test]
     fn test_loading_mongo_url_from_dotenv() {
@@ -131,14 +623,14 @@ mod tests {
     #[tokio::test]
     async fn test_connecting_to_mongo_db() {
         dotenv().ok();
-        let client = load_mongo_client().await;
+        let client = load_mongo_client(&MongoSettings::default()).await;
         assert!(client.is_ok())
     }
 
     #[tokio::test]
     async fn test_getting_config_from_mongodb() {
         dotenv().ok();
-        let client_result = load_mongo_client().await;
+        let client_result = load_mongo_client(&MongoSettings::default()).await;
         assert!(client_result.is_ok());
 
         let client = client_result.unwrap();
@@ -156,16 +648,17 @@ mod tests {
     #[tokio::test]
     async fn test_getting_config_from_mongodb_relays() {
         dotenv().ok();
-        let client_result = load_mongo_client().await;
+        let client_result = load_mongo_client(&MongoSettings::default()).await;
         assert!(client_result.is_ok());
 
         let client = client_result.unwrap();
 
         let home_config = client.database("HomeConfig");
 
-        let relays = find_mongo_relays(&home_config)
+        let config_relays = find_mongo_config_relays(&home_config, &MongoSettings::default())
             .await
             .expect("Could not get Mongo relays");
+        let relays = load_relays(config_relays);
 
         assert!(!relays.is_empty())
     }
@@ -173,14 +666,14 @@ mod tests {
     #[tokio::test]
     async fn test_getting_config_from_mongodb_presets() {
         dotenv().ok();
-        let client_result = load_mongo_client().await;
+        let client_result = load_mongo_client(&MongoSettings::default()).await;
         assert!(client_result.is_ok());
 
         let client = client_result.unwrap();
 
         let home_config = client.database("HomeConfig");
 
-        let presets = find_mongo_presets(&home_config)
+        let presets = find_mongo_presets(&home_config, &MongoSettings::default())
             .await
             .expect("Could not get Mongo presets");
         assert!(!presets.is_empty())
@@ -0,0 +1,165 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use mongodb::bson::Document;
+use mongodb::change_stream::event::ChangeStreamEvent;
+use mongodb::error::{CommandError, ErrorKind};
+use mongodb::Database;
+use rocket::futures::TryStreamExt;
+use rocket::log;
+
+use crate::models::data_thread_models::DataThreadCommand;
+use crate::utils::load_config::WatchGuard;
+use crate::utils::local_config_utils::LoadedConfig;
+use crate::utils::mongodb_utils::{mongo_connection, read_mongo_config, MongoSettings};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Server error codes meaning change streams aren't available, either because
+/// the deployment isn't a replica set or the server is too old.
+const CHANGE_STREAMS_UNSUPPORTED: [i32; 2] = [40573, 40324];
+
+fn change_streams_unsupported(error: &mongodb::error::Error) -> bool {
+    matches!(
+        *error.kind,
+        ErrorKind::Command(CommandError { code, .. }) if CHANGE_STREAMS_UNSUPPORTED.contains(&code)
+    )
+}
+
+fn is_config_change(event: &ChangeStreamEvent<Document>, settings: &MongoSettings) -> bool {
+    event
+        .ns
+        .as_ref()
+        .and_then(|namespace| namespace.coll.as_deref())
+        .is_some_and(|collection| settings.collections().contains(&collection))
+}
+
+/// Reads the config and hands it to the data thread. Returns false once the
+/// data thread has gone away.
+async fn send_mongo_config(
+    database: &Database,
+    settings: &MongoSettings,
+    route_to_data_sender: &Sender<DataThreadCommand>,
+) -> bool {
+    match read_mongo_config(database, settings).await {
+        Ok(loaded_config) => route_to_data_sender
+            .send(DataThreadCommand::ConfigReload(loaded_config))
+            .is_ok(),
+        Err(error) => {
+            log::warn_!("Unable to read Mongo config: {}", error);
+            true
+        }
+    }
+}
+
+/// Reads the config every `POLL_INTERVAL` and hands it to the data thread
+/// when it differs from the last one read, so an unchanged config doesn't
+/// cause a reload.
+async fn poll_mongo_config(
+    database: &Database,
+    settings: &MongoSettings,
+    route_to_data_sender: &Sender<DataThreadCommand>,
+) {
+    let mut last_config: Option<LoadedConfig> = None;
+    loop {
+        match read_mongo_config(database, settings).await {
+            Ok(loaded_config) if last_config.as_ref() != Some(&loaded_config) => {
+                last_config = Some(loaded_config.clone());
+                if route_to_data_sender
+                    .send(DataThreadCommand::ConfigReload(loaded_config))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(error) => log::warn_!("Unable to read Mongo config: {}", error),
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn watch_mongo_config(
+    database: Database,
+    settings: MongoSettings,
+    route_to_data_sender: Sender<DataThreadCommand>,
+    guard: &WatchGuard,
+) {
+    loop {
+        let mut change_stream = match database.watch().await {
+            Ok(change_stream) => change_stream,
+            Err(error) if change_streams_unsupported(&error) => {
+                log::warn_!(
+                    "Mongo change streams unavailable, polling config every {} seconds",
+                    POLL_INTERVAL.as_secs()
+                );
+                return poll_mongo_config(&database, &settings, &route_to_data_sender).await;
+            }
+            Err(error) => {
+                // Let the periodic refresh read the config until the stream is back
+                guard.set(false);
+                log::warn_!("Unable to watch Mongo config: {}", error);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        guard.set(true);
+
+        // Pick up anything that changed while the stream wasn't open
+        if !send_mongo_config(&database, &settings, &route_to_data_sender).await {
+            return;
+        }
+
+        loop {
+            match change_stream.try_next().await {
+                Ok(Some(event)) if is_config_change(&event, &settings) => {
+                    if !send_mongo_config(&database, &settings, &route_to_data_sender).await {
+                        return;
+                    }
+                }
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(error) => {
+                    log::warn_!("Mongo change stream closed: {}", error);
+                    break;
+                }
+            }
+        }
+        guard.set(false);
+
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Sends config changes to the data thread as they happen over the shared
+/// Mongo connection, using change streams when the deployment supports them
+/// and polling otherwise. `watched` is set while changes are coming through.
+pub(crate) fn setup_mongodb_watcher(
+    settings: MongoSettings,
+    route_to_data_sender: Sender<DataThreadCommand>,
+    watched: &Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let guard = WatchGuard::new(watched);
+    thread::spawn(move || {
+        let connection = match mongo_connection(&settings) {
+            Ok(connection) => connection,
+            Err(error) => {
+                log::warn_!("Unable to connect to Mongo for config updates: {}", error);
+                return;
+            }
+        };
+
+        let database = connection.database(&settings);
+        connection.block_on(watch_mongo_config(
+            database,
+            settings,
+            route_to_data_sender,
+            &guard,
+        ))
+    })
+}
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rocket::log;
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+
+use crate::models::data_thread_models::{DataThreadCommand, MqttCommand};
+use crate::models::presets::Preset;
+use crate::models::relays::RelayType;
+use crate::utils::data_thread_handling::handle_command_input;
+use crate::utils::home_assistant_discovery::{preset_discovery_messages, relay_discovery_messages};
+use crate::utils::mqtt_network_functions::{parse_broker_url, MqttSettings};
+
+/// Mirrors the relay registry onto a broker. Every relay's state is published
+/// retained to `<prefix>/<relay>/state`, and `set` topics are turned into data
+/// thread commands.
+pub(crate) struct MqttBridge {
+    client: Client,
+    prefix: String,
+    /// Home Assistant discovery prefix, when discovery is turned on
+    discovery_prefix: Option<String>,
+    /// Retained messages last published by topic, republished after a reconnect
+    retained: Arc<Mutex<HashMap<String, String>>>,
+    /// Retained messages of each relay, kept until the config removes it so a
+    /// relay that can't be reconnected only shows as unavailable
+    relay_messages: Mutex<HashMap<String, HashMap<String, String>>>,
+}
+
+fn state_payload(state: bool) -> &'static str {
+    match state {
+        true => "ON",
+        false => "OFF",
+    }
+}
+
+/// Reads a command from a message on one of the bridge's `set` topics.
+/// `<prefix>/preset/set` takes a preset name, `<prefix>/tag/<tag>/set` and
+/// `<prefix>/<relay>/set` take `ON`, `OFF` or `SWITCH`.
+pub(crate) fn parse_set_topic(prefix: &str, topic: &str, payload: &[u8]) -> Option<MqttCommand> {
+    let payload = String::from_utf8_lossy(payload).trim().to_string();
+    let path = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    let path = path.strip_suffix("/set")?;
+
+    if path == "preset" {
+        return Some(MqttCommand::Preset(payload));
+    }
+
+    let command = handle_command_input(&payload)?;
+    match path.strip_prefix("tag/") {
+        Some(tag) => Some(MqttCommand::Tag(tag.to_string(), command)),
+        None if !path.contains('/') => Some(MqttCommand::Relay(path.to_string(), command)),
+        None => None,
+    }
+}
+
+/// Payload replacing a retained message that is no longer wanted. Availability
+/// goes `offline`, anything else is cleared.
+fn removal_payload(topic: &str) -> &'static str {
+    match topic.ends_with("/availability") {
+        true => "offline",
+        false => "",
+    }
+}
+
+impl MqttBridge {
+    fn publish(client: &Client, topic: String, payload: &str) {
+        if let Err(error) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+            log::warn_!("Unable to publish to MQTT: {}", error);
+        }
+    }
+
+    fn availability_topic(&self, name: &str) -> String {
+        format!("{}/{}/availability", self.prefix, name)
+    }
+
+    /// Retained messages of one relay. Its availability is `offline` while
+    /// the device doesn't answer.
+    fn messages_of(&self, name: &str, relay: &RelayType) -> HashMap<String, String> {
+        let mut messages: HashMap<String, String> = HashMap::new();
+        let topic = format!("{}/{}", self.prefix, name);
+        let availability = match relay.reachable() {
+            true => "online",
+            false => "offline",
+        };
+
+        messages.insert(self.availability_topic(name), availability.to_string());
+        messages.insert(
+            format!("{}/state", topic),
+            state_payload(relay.status()).to_string(),
+        );
+        if let Some(emeter) = relay.emeter() {
+            messages.insert(format!("{}/emeter", topic), json!(emeter).to_string());
+        }
+        if let Some(discovery_prefix) = &self.discovery_prefix {
+            messages.extend(relay_discovery_messages(
+                discovery_prefix,
+                &self.prefix,
+                relay,
+            ));
+        }
+        messages
+    }
+
+    /// Every retained message the bridge keeps on the broker, keyed by topic.
+    /// Relays that are configured but not connected keep their last messages,
+    /// marked `offline`.
+    fn retained_messages(
+        &self,
+        relays: &HashMap<String, RelayType>,
+        presets: &HashMap<String, Preset>,
+        current_preset: &str,
+    ) -> HashMap<String, String> {
+        let mut relay_messages = self.relay_messages.lock().unwrap();
+        for (name, relay) in relays {
+            relay_messages.insert(name.clone(), self.messages_of(name, relay));
+        }
+
+        let mut messages: HashMap<String, String> = HashMap::new();
+        for (name, relay_topics) in relay_messages.iter_mut() {
+            if !relays.contains_key(name) {
+                relay_topics.insert(self.availability_topic(name), "offline".to_string());
+            }
+            messages.extend(relay_topics.clone());
+        }
+        messages.insert(
+            format!("{}/preset/state", self.prefix),
+            current_preset.to_string(),
+        );
+
+        if let Some(discovery_prefix) = &self.discovery_prefix {
+            messages.extend(preset_discovery_messages(
+                discovery_prefix,
+                &self.prefix,
+                presets,
+            ));
+        }
+
+        messages
+    }
+
+    /// Drops the relays a config reload removed, their retained messages and
+    /// discovery configs are cleared by the next `publish_changes`.
+    pub(crate) fn forget_relays(&self, names: &[String]) {
+        let mut relay_messages = self.relay_messages.lock().unwrap();
+        for name in names {
+            relay_messages.remove(name);
+        }
+    }
+
+    /// Publishes the retained messages that changed since the last call, and
+    /// replaces the ones of forgotten relays and entities that are gone.
+    pub(crate) fn publish_changes(
+        &self,
+        relays: &HashMap<String, RelayType>,
+        presets: &HashMap<String, Preset>,
+        current_preset: &str,
+    ) {
+        let messages = self.retained_messages(relays, presets, current_preset);
+        let mut retained = self.retained.lock().unwrap();
+
+        for (topic, payload) in &messages {
+            if retained.get(topic) != Some(payload) {
+                Self::publish(&self.client, topic.clone(), payload);
+            }
+        }
+        for topic in retained.keys() {
+            if !messages.contains_key(topic) {
+                Self::publish(&self.client, topic.clone(), removal_payload(topic));
+            }
+        }
+
+        *retained = messages;
+    }
+}
+
+/// Connects the bridge to `broker`. `<prefix>/availability` is `online` while
+/// connected, the broker sets it to `offline` through the last will otherwise.
+pub(crate) fn setup_mqtt_bridge(
+    broker: &str,
+    settings: &MqttSettings,
+    route_to_data_sender: Sender<DataThreadCommand>,
+) -> Result<MqttBridge, Error> {
+    let address = parse_broker_url(broker)?;
+    let prefix = settings.bridge_prefix.clone();
+    let availability = format!("{}/availability", prefix);
+
+    let mut options = MqttOptions::new(
+        format!("remoterelay-bridge-{}", std::process::id()),
+        address.host,
+        address.port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        &availability,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some((username, password)) = address.credentials {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut connection) = Client::new(options, 256);
+    let bridge = MqttBridge {
+        client: client.clone(),
+        prefix: prefix.clone(),
+        discovery_prefix: match settings.discovery {
+            true => Some(settings.discovery_prefix.clone()),
+            false => None,
+        },
+        retained: Arc::new(Mutex::new(HashMap::new())),
+        relay_messages: Mutex::new(HashMap::new()),
+    };
+    let retained = bridge.retained.clone();
+    let broker = broker.to_string();
+
+    thread::spawn(move || {
+        let mut connected = false;
+        for event in connection.iter() {
+            match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    connected = true;
+                    MqttBridge::publish(&client, availability.clone(), "online");
+                    for topic in [format!("{}/+/set", prefix), format!("{}/tag/+/set", prefix)] {
+                        let _ = client.try_subscribe(topic, QoS::AtLeastOnce);
+                    }
+                    for (topic, payload) in retained.lock().unwrap().iter() {
+                        MqttBridge::publish(&client, topic.clone(), payload);
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match parse_set_topic(&prefix, &publish.topic, &publish.payload) {
+                        Some(command) => {
+                            if route_to_data_sender
+                                .send(DataThreadCommand::Mqtt(command))
+                                .is_err()
+                            {
+                                return;
+                            }
+                        }
+                        None => log::warn_!("Ignoring MQTT message on {}", publish.topic),
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    if connected {
+                        log::warn_!("Lost connection to MQTT broker {}: {}", broker, error);
+                        connected = false;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    });
+
+    Ok(bridge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::kasa::KasaPlug;
+    use crate::models::data_thread_models::RelayCommands;
+    use crate::models::relays::TrackedRelay;
+
+    #[test]
+    fn test_parsing_set_topics() {
+        assert_eq!(
+            parse_set_topic("remoterelay", "remoterelay/DeskLamp/set", b"ON"),
+            Some(MqttCommand::Relay(
+                "DeskLamp".to_string(),
+                RelayCommands::TRUE
+            ))
+        );
+        assert_eq!(
+            parse_set_topic("remoterelay", "remoterelay/tag/kitchen/set", b"switch"),
+            Some(MqttCommand::Tag(
+                "kitchen".to_string(),
+                RelayCommands::SWITCH
+            ))
+        );
+        assert_eq!(
+            parse_set_topic("remoterelay", "remoterelay/preset/set", b"Bedroom on"),
+            Some(MqttCommand::Preset("Bedroom on".to_string()))
+        );
+        assert_eq!(
+            parse_set_topic("remoterelay", "remoterelay/DeskLamp/set", b"dim"),
+            None
+        );
+        assert_eq!(
+            parse_set_topic("remoterelay", "other/DeskLamp/set", b"ON"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unreachable_relays_keep_their_topics_until_removed() {
+        let (client, _connection) = Client::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let bridge = MqttBridge {
+            client,
+            prefix: "remoterelay".to_string(),
+            discovery_prefix: Some("homeassistant".to_string()),
+            retained: Arc::new(Mutex::new(HashMap::new())),
+            relay_messages: Mutex::new(HashMap::new()),
+        };
+        let config_topic = "homeassistant/switch/remoterelay_desklamp/config";
+        let availability = "remoterelay/DeskLamp/availability";
+
+        // Nothing listens on the Kasa port of localhost
+        let mut plug = TrackedRelay::track(Box::new(KasaPlug::new(
+            "127.0.0.1".to_string(),
+            "DeskLamp".to_string(),
+            "office".to_string(),
+            vec![],
+        )));
+        let mut relays: HashMap<String, RelayType> = HashMap::new();
+        let messages = bridge.retained_messages(&relays, &HashMap::new(), "");
+        assert!(!messages.contains_key(availability));
+
+        assert!(plug.get_status().is_err());
+        relays.insert("DeskLamp".to_string(), plug);
+        let messages = bridge.retained_messages(&relays, &HashMap::new(), "");
+        assert_eq!(messages[availability], "offline");
+        assert!(messages.contains_key(config_topic));
+
+        // Failing to reconnect on a config reload leaves it out of the registry
+        relays.clear();
+        let messages = bridge.retained_messages(&relays, &HashMap::new(), "");
+        assert_eq!(messages[availability], "offline");
+        assert!(messages.contains_key(config_topic));
+
+        bridge.forget_relays(&["DeskLamp".to_string()]);
+        let messages = bridge.retained_messages(&relays, &HashMap::new(), "");
+        assert!(!messages.contains_key(availability));
+        assert!(!messages.contains_key(config_topic));
+    }
+}
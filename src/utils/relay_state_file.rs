@@ -0,0 +1,287 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Error;
+use std::path::PathBuf;
+
+use rocket::log;
+use serde::{Deserialize, Serialize};
+
+use crate::models::config_models::RestorePolicy;
+use crate::models::data_thread_models::RelayStateChange;
+use crate::models::relays::{is_group, RelayType};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct SavedRelayState {
+    #[serde(rename = "currentPreset", default)]
+    current_preset: Option<String>,
+    #[serde(default)]
+    relays: HashMap<String, bool>,
+}
+
+/// Local file holding the last desired state of every relay and the active
+/// preset, so they can be put back after a restart or power outage.
+#[derive(Debug)]
+pub(crate) struct RelayStateFile {
+    path: PathBuf,
+    default_policy: RestorePolicy,
+    saved: SavedRelayState,
+    /// Relays whose restore policy has been applied. The others are restored
+    /// the first time they are connected and reachable, since devices often
+    /// come back after the server does.
+    restored: HashSet<String>,
+}
+
+impl RelayStateFile {
+    pub(crate) fn load(path: &str, default_policy: RestorePolicy) -> Self {
+        let saved = match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|error| {
+                log::warn_!("Ignoring unreadable relay state file {}: {}", path, error);
+                SavedRelayState::default()
+            }),
+            Err(_) => SavedRelayState::default(),
+        };
+
+        RelayStateFile {
+            path: PathBuf::from(path),
+            default_policy,
+            saved,
+            restored: HashSet::new(),
+        }
+    }
+
+    /// Applies each relay's restore policy and returns the preset to start in:
+    /// the saved preset if every relay ended up in its saved state, otherwise
+    /// "Custom".
+    pub(crate) fn restore(
+        &mut self,
+        relays: &mut HashMap<String, RelayType>,
+        policies: &HashMap<String, RestorePolicy>,
+    ) -> String {
+        let saved = self.saved.relays.clone();
+        self.restore_relays(relays, policies);
+
+        let matches_saved = !saved.is_empty()
+            && relays
+                .iter()
+                .filter(|(_, relay)| !is_group(relay))
+                .all(|(name, relay)| saved.get(name) == Some(&relay.status()));
+        let current_preset = match (&self.saved.current_preset, matches_saved) {
+            (Some(preset), true) => preset.clone(),
+            _ => "Custom".to_string(),
+        };
+        self.saved.current_preset = Some(current_preset.clone());
+
+        if let Err(error) = self.save() {
+            log::warn_!("Unable to write relay state file: {}", error);
+        }
+
+        current_preset
+    }
+
+    /// Applies the restore policy of relays that connected or became
+    /// reachable since startup and haven't been restored yet.
+    pub(crate) fn restore_pending(
+        &mut self,
+        relays: &mut HashMap<String, RelayType>,
+        policies: &HashMap<String, RestorePolicy>,
+    ) {
+        if self.restore_relays(relays, policies) {
+            if let Err(error) = self.save() {
+                log::warn_!("Unable to write relay state file: {}", error);
+            }
+        }
+    }
+
+    /// Restores every reachable relay that isn't restored yet, returning
+    /// whether the saved states gained a relay. A relay that fails to switch
+    /// stays pending.
+    fn restore_relays(
+        &mut self,
+        relays: &mut HashMap<String, RelayType>,
+        policies: &HashMap<String, RestorePolicy>,
+    ) -> bool {
+        let mut saved_changed = false;
+
+        for (name, relay) in relays.iter_mut() {
+            // Groups follow their members, which are restored on their own
+            if is_group(relay) || !relay.reachable() || self.restored.contains(name) {
+                continue;
+            }
+            let policy = policies.get(name).copied().unwrap_or(self.default_policy);
+            let target = match policy {
+                RestorePolicy::Restore => self.saved.relays.get(name).copied(),
+                RestorePolicy::Leave => None,
+                RestorePolicy::ForceOff => Some(false),
+                RestorePolicy::ForceOn => Some(true),
+            };
+
+            let result = match target {
+                Some(true) if !relay.status() => relay.turn_on().map(|_| ()),
+                Some(false) if relay.status() => relay.turn_off().map(|_| ()),
+                _ => Ok(()),
+            };
+            match result {
+                Ok(()) => {
+                    self.restored.insert(name.clone());
+                }
+                Err(error) => log::warn_!("Unable to restore relay {}: {}", name, error),
+            }
+
+            if !self.saved.relays.contains_key(name) {
+                self.saved.relays.insert(name.clone(), relay.status());
+                saved_changed = true;
+            }
+        }
+
+        saved_changed
+    }
+
+    /// Records relay changes the server made itself and the active preset,
+    /// writing the file when anything differs from what is saved.
+    pub(crate) fn update(&mut self, changes: &[RelayStateChange], current_preset: &str) {
+        let mut saved = self.saved.clone();
+        for change in changes {
+            saved.relays.insert(change.name.clone(), change.current);
+        }
+        saved.current_preset = Some(current_preset.to_string());
+
+        if saved == self.saved {
+            return;
+        }
+
+        self.saved = saved;
+        if let Err(error) = self.save() {
+            log::warn_!("Unable to write relay state file: {}", error);
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let temporary_path = self.path.with_extension("tmp");
+        fs::write(&temporary_path, serde_json::to_string_pretty(&self.saved)?)?;
+        fs::rename(temporary_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::kasa::KasaPlug;
+    use crate::drivers::virtual_relay::VirtualRelay;
+    use crate::models::relays::TrackedRelay;
+    use std::env;
+
+    fn temporary_state_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("remoterelay_{}_{}.json", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn relays(statuses: &[(&str, bool)]) -> HashMap<String, RelayType> {
+        statuses
+            .iter()
+            .map(|(name, status)| {
+                let mut plug = KasaPlug::new(
+                    "127.0.0.1".to_string(),
+                    name.to_string(),
+                    "bedroom".to_string(),
+                    vec![],
+                );
+                plug.status = *status;
+                (name.to_string(), Box::new(plug) as RelayType)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_update_persists_and_reloads() {
+        let path = temporary_state_path("update");
+        let mut state_file = RelayStateFile::load(&path, RestorePolicy::Restore);
+
+        state_file.update(
+            &[RelayStateChange {
+                name: "DeskLamp".to_string(),
+                previous: Some(false),
+                current: true,
+            }],
+            "Evening",
+        );
+
+        let reloaded = RelayStateFile::load(&path, RestorePolicy::Restore);
+        assert_eq!(reloaded.saved.relays.get("DeskLamp"), Some(&true));
+        assert_eq!(reloaded.saved.current_preset, Some("Evening".to_string()));
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_restore_keeps_preset_only_when_states_match() {
+        let path = temporary_state_path("restore");
+        let mut state_file = RelayStateFile::load(&path, RestorePolicy::Leave);
+        state_file.saved = SavedRelayState {
+            current_preset: Some("Evening".to_string()),
+            relays: HashMap::from([("DeskLamp".to_string(), true)]),
+        };
+
+        let mut matching = relays(&[("DeskLamp", true)]);
+        assert_eq!(
+            state_file.restore(&mut matching, &HashMap::new()),
+            "Evening"
+        );
+
+        let mut drifted = relays(&[("DeskLamp", false)]);
+        assert_eq!(state_file.restore(&mut drifted, &HashMap::new()), "Custom");
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_relays_that_come_up_later_are_restored() {
+        let path = temporary_state_path("pending");
+        let mut state_file = RelayStateFile::load(&path, RestorePolicy::Restore);
+        state_file.saved.relays = HashMap::from([
+            ("DeskLamp".to_string(), true),
+            ("Fan".to_string(), true),
+            ("Heater".to_string(), true),
+        ]);
+        let virtual_relay = |name: &str| {
+            TrackedRelay::track(Box::new(VirtualRelay::new(
+                name.to_string(),
+                "bedroom".to_string(),
+                vec![],
+            )))
+        };
+
+        let mut relays = HashMap::from([("DeskLamp".to_string(), virtual_relay("DeskLamp"))]);
+        assert_eq!(state_file.restore(&mut relays, &HashMap::new()), "Custom");
+        assert!(relays["DeskLamp"].status());
+
+        // Nothing listens on the Kasa port of localhost, so the heater stays pending
+        let mut heater = TrackedRelay::track(Box::new(KasaPlug::new(
+            "127.0.0.1".to_string(),
+            "Heater".to_string(),
+            "bedroom".to_string(),
+            vec![],
+        )));
+        assert!(heater.get_status().is_err());
+        relays.insert("Fan".to_string(), virtual_relay("Fan"));
+        relays.insert("Heater".to_string(), heater);
+        let policies = HashMap::from([("Fan".to_string(), RestorePolicy::Restore)]);
+        state_file.restore_pending(&mut relays, &policies);
+        assert!(relays["Fan"].status());
+        assert!(!state_file.restored.contains("Heater"));
+
+        // A relay is only restored once
+        relays.get_mut("Fan").unwrap().turn_off().unwrap();
+        state_file.restore_pending(&mut relays, &policies);
+        assert!(!relays["Fan"].status());
+
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_restore_policy_deserializes_snake_case() {
+        let policy: RestorePolicy = serde_json::from_str("\"force_off\"").unwrap();
+        assert_eq!(policy, RestorePolicy::ForceOff);
+    }
+}
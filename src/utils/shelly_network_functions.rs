@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::utils::kasa_plug_network_functions::{retries, timeout};
+
+/// Username and password of a Shelly with authentication turned on.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ShellyCredentials {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+fn to_io_error(ip: &str, error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(401, _) => Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Shelly {} rejected the username or password", ip),
+        ),
+        ureq::Error::Status(code, _) => {
+            Error::other(format!("Shelly {} answered with status {}", ip, code))
+        }
+        ureq::Error::Transport(transport) => Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Can't connect to Shelly {}: {}", ip, transport),
+        ),
+    }
+}
+
+fn sha256_hex(data: String) -> String {
+    format!("{:x}", Sha256::digest(data.as_bytes()))
+}
+
+/// Parameters of a `WWW-Authenticate` challenge, keyed by lowercase name.
+/// Quoted values can contain commas and backslash escapes, like
+/// `qop="auth,auth-int"`.
+fn challenge_params(params: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = params.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.trim().is_empty() {
+            break;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            // Skip anything between the closing quote and the next comma
+            while chars.next_if(|c| *c != ',').is_some() {}
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+        parsed.insert(key.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    parsed
+}
+
+/// Answers a Digest challenge. Shelly Gen2 devices only offer SHA-256.
+fn digest_authorization(
+    params: &str,
+    path: &str,
+    credentials: &ShellyCredentials,
+    cnonce: &str,
+) -> Result<String, Error> {
+    let params = challenge_params(params);
+    let algorithm = params
+        .get("algorithm")
+        .map(String::as_str)
+        .unwrap_or("SHA-256");
+    if !algorithm.eq_ignore_ascii_case("SHA-256") {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Unsupported digest algorithm {}", algorithm),
+        ));
+    }
+
+    let realm = params.get("realm").cloned().unwrap_or_default();
+    let nonce = params.get("nonce").cloned().unwrap_or_default();
+    let ha1 = sha256_hex(format!(
+        "{}:{}:{}",
+        credentials.username, realm, credentials.password
+    ));
+    let ha2 = sha256_hex(format!("GET:{}", path));
+
+    let header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm=SHA-256",
+        credentials.username, realm, nonce, path
+    );
+    match params.get("qop") {
+        Some(qop) if qop.split(',').any(|qop| qop.trim() == "auth") => {
+            let response = sha256_hex(format!(
+                "{}:{}:00000001:{}:auth:{}",
+                ha1, nonce, cnonce, ha2
+            ));
+            Ok(format!(
+                "{}, qop=auth, nc=00000001, cnonce=\"{}\", response=\"{}\"",
+                header, cnonce, response
+            ))
+        }
+        _ => {
+            let response = sha256_hex(format!("{}:{}:{}", ha1, nonce, ha2));
+            Ok(format!("{}, response=\"{}\"", header, response))
+        }
+    }
+}
+
+/// `Authorization` header answering `challenge`. Gen1 devices ask for Basic
+/// authentication, Gen2 devices for Digest.
+fn authorization(
+    challenge: &str,
+    path: &str,
+    credentials: &ShellyCredentials,
+) -> Result<String, Error> {
+    let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+    match scheme.to_lowercase().as_str() {
+        "basic" => Ok(format!(
+            "Basic {}",
+            STANDARD.encode(format!("{}:{}", credentials.username, credentials.password))
+        )),
+        "digest" => {
+            let cnonce = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            digest_authorization(params, path, credentials, &format!("{:x}", cnonce))
+        }
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Unsupported authentication scheme {}", scheme),
+        )),
+    }
+}
+
+fn get_once<T: DeserializeOwned>(
+    ip: &str,
+    path: &str,
+    credentials: Option<&ShellyCredentials>,
+) -> Result<T, Error> {
+    let url = format!("http://{}{}", ip, path);
+    let agent = ureq::AgentBuilder::new().timeout(timeout()).build();
+
+    let response = match agent.get(&url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(401, response)) => {
+            let credentials = credentials.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Shelly {} needs a username and password", ip),
+                )
+            })?;
+            let challenge = response.header("WWW-Authenticate").unwrap_or_default();
+            agent
+                .get(&url)
+                .set(
+                    "Authorization",
+                    &authorization(challenge, path, credentials)?,
+                )
+                .call()
+                .map_err(|error| to_io_error(ip, error))?
+        }
+        Err(error) => return Err(to_io_error(ip, error)),
+    };
+
+    let body = response.into_string()?;
+    serde_json::from_str(&body).map_err(|error| Error::new(ErrorKind::InvalidData, error))
+}
+
+/// Sends a GET request to the Shelly at `ip` and parses its JSON answer,
+/// authenticating with `credentials` when the device asks for it.
+pub(crate) fn get<T: DeserializeOwned>(
+    ip: &str,
+    path: &str,
+    credentials: Option<&ShellyCredentials>,
+) -> Result<T, Error> {
+    let retries = retries();
+    let mut attempt = 0;
+    loop {
+        match get_once::<T>(ip, path, credentials) {
+            Err(error) if attempt < retries && error.kind() != ErrorKind::PermissionDenied => {
+                attempt += 1
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_answers_challenge() {
+        let credentials = ShellyCredentials {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        };
+        let header = digest_authorization(
+            "qop=\"auth\", realm=\"shellyplus1-abc\", nonce=\"1700000000\", algorithm=SHA-256",
+            "/rpc/Switch.GetStatus?id=0",
+            &credentials,
+            "cafe",
+        )
+        .unwrap();
+
+        let ha1 = sha256_hex("admin:shellyplus1-abc:secret".to_string());
+        let ha2 = sha256_hex("GET:/rpc/Switch.GetStatus?id=0".to_string());
+        let expected = sha256_hex(format!("{}:1700000000:00000001:cafe:auth:{}", ha1, ha2));
+
+        assert!(header.starts_with("Digest username=\"admin\""));
+        assert!(header.contains("realm=\"shellyplus1-abc\""));
+        assert!(header.ends_with(&format!("response=\"{}\"", expected)));
+    }
+
+    #[test]
+    fn test_challenge_params_keep_quoted_commas() {
+        let params = challenge_params(
+            "realm=\"shelly, \\\"plus\\\"\", qop=\"auth,auth-int\",nonce=1700000000, algorithm=SHA-256",
+        );
+
+        assert_eq!(params["realm"], "shelly, \"plus\"");
+        assert_eq!(params["qop"], "auth,auth-int");
+        assert_eq!(params["nonce"], "1700000000");
+        assert_eq!(params["algorithm"], "SHA-256");
+        assert_eq!(params.len(), 4);
+    }
+
+    #[test]
+    fn test_basic_and_unknown_schemes() {
+        let credentials = ShellyCredentials {
+            username: "admin".to_string(),
+            password: "secret".to_string(),
+        };
+        assert_eq!(
+            authorization("Basic realm=\"shelly1\"", "/relay/0", &credentials).unwrap(),
+            "Basic YWRtaW46c2VjcmV0"
+        );
+        assert!(authorization("Bearer", "/relay/0", &credentials).is_err());
+    }
+}
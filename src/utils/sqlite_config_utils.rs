@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::io::Error;
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::models::automations::Automation;
+use crate::models::config_models::{ConfigRelay, ConfigRelayType, RelayOptions, ServerSettings};
+use crate::models::presets::Preset;
+use crate::utils::local_config_utils::LoadedConfig;
+
+/// Schema migrations, applied in order. The database's `user_version` records
+/// how many have already run.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE relays (
+        id INTEGER PRIMARY KEY,
+        type TEXT NOT NULL,
+        ip TEXT NOT NULL,
+        room TEXT NOT NULL DEFAULT '',
+        restore TEXT
+    );
+    CREATE TABLE relay_names (
+        relay_id INTEGER NOT NULL REFERENCES relays (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL UNIQUE,
+        PRIMARY KEY (relay_id, position)
+    );
+    CREATE TABLE relay_tags (
+        relay_id INTEGER NOT NULL REFERENCES relays (id) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (relay_id, tag)
+    );
+    CREATE TABLE presets (
+        name TEXT PRIMARY KEY,
+        enabled INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE TABLE preset_relays (
+        preset TEXT NOT NULL REFERENCES presets (name) ON DELETE CASCADE ON UPDATE CASCADE,
+        relay TEXT NOT NULL,
+        state INTEGER NOT NULL,
+        PRIMARY KEY (preset, relay)
+    );
+    CREATE TABLE automations (
+        name TEXT PRIMARY KEY,
+        enabled INTEGER NOT NULL DEFAULT 1,
+        trigger TEXT NOT NULL,
+        actions TEXT NOT NULL
+    );",
+    "ALTER TABLE relays ADD COLUMN options TEXT;",
+    "ALTER TABLE preset_relays ADD COLUMN levels TEXT;",
+];
+
+fn to_io_error(error: rusqlite::Error) -> Error {
+    Error::other(error)
+}
+
+/// Opens the config database, creating it and running any pending migrations.
+pub(crate) fn open_sqlite_config(path: &str) -> rusqlite::Result<Connection> {
+    let mut connection = Connection::open(path)?;
+    migrate(&mut connection)?;
+    Ok(connection)
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    connection.pragma_update(None, "foreign_keys", true)?;
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn column_error(column: usize) -> impl Fn(serde_json::Error) -> rusqlite::Error {
+    move |error| {
+        rusqlite::Error::FromSqlConversionFailure(
+            column,
+            rusqlite::types::Type::Text,
+            Box::new(error),
+        )
+    }
+}
+
+fn find_sqlite_relays(connection: &Connection) -> rusqlite::Result<Vec<ConfigRelay>> {
+    let mut names_statement =
+        connection.prepare("SELECT name FROM relay_names WHERE relay_id = ?1 ORDER BY position")?;
+    let mut tags_statement =
+        connection.prepare("SELECT tag FROM relay_tags WHERE relay_id = ?1 ORDER BY tag")?;
+    let mut relays_statement = connection
+        .prepare("SELECT id, type, ip, room, restore, options FROM relays ORDER BY id")?;
+
+    let rows = relays_statement.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            serde_json::from_value::<ConfigRelayType>(Value::String(row.get(1)?))
+                .map_err(column_error(1))?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            match row.get::<_, Option<String>>(4)? {
+                Some(restore) => {
+                    Some(serde_json::from_value(Value::String(restore)).map_err(column_error(4))?)
+                }
+                None => None,
+            },
+            match row.get::<_, Option<String>>(5)? {
+                Some(options) => serde_json::from_str(&options).map_err(column_error(5))?,
+                None => RelayOptions::default(),
+            },
+        ))
+    })?;
+
+    let mut relays: Vec<ConfigRelay> = Vec::new();
+    for row in rows {
+        let (id, relay_type, ip, room, restore, options) = row?;
+        let names = names_statement
+            .query_map(params![id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        let tags = tags_statement
+            .query_map(params![id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        let lists_names = relay_type
+            .driver()
+            .is_some_and(|driver| driver.lists_names())
+            || names.len() > 1;
+
+        relays.push(ConfigRelay {
+            name: match lists_names {
+                true => String::new(),
+                false => names.first().cloned().unwrap_or_default(),
+            },
+            names: match lists_names {
+                true => names,
+                false => Vec::new(),
+            },
+            relay_type,
+            ip,
+            room,
+            tags,
+            restore,
+            options,
+        });
+    }
+
+    Ok(relays)
+}
+
+fn find_sqlite_presets(connection: &Connection) -> rusqlite::Result<Vec<Preset>> {
+    let mut relays_statement =
+        connection.prepare("SELECT relay, state, levels FROM preset_relays WHERE preset = ?1")?;
+    let mut presets_statement =
+        connection.prepare("SELECT name, enabled FROM presets ORDER BY name")?;
+
+    let rows = presets_statement.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+    })?;
+
+    let mut presets: Vec<Preset> = Vec::new();
+    for row in rows {
+        let (name, enabled) = row?;
+        let mut relays = HashMap::new();
+        let mut levels = HashMap::new();
+        let rows = relays_statement.query_map(params![name], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, bool>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (relay, state, relay_levels) = row?;
+            if let Some(relay_levels) = relay_levels {
+                levels.insert(
+                    relay.clone(),
+                    serde_json::from_str(&relay_levels).map_err(column_error(2))?,
+                );
+            }
+            relays.insert(relay, state);
+        }
+
+        presets.push(Preset {
+            name,
+            enabled,
+            relays,
+            levels,
+        });
+    }
+
+    Ok(presets)
+}
+
+fn find_sqlite_automations(connection: &Connection) -> rusqlite::Result<Vec<Automation>> {
+    let mut statement = connection
+        .prepare("SELECT name, enabled, trigger, actions FROM automations ORDER BY name")?;
+
+    let rows = statement.query_map([], |row| {
+        let trigger: String = row.get(2)?;
+        let actions: String = row.get(3)?;
+        Ok(Automation {
+            name: row.get(0)?,
+            enabled: row.get(1)?,
+            when: serde_json::from_str(&trigger).map_err(column_error(2))?,
+            then: serde_json::from_str(&actions).map_err(column_error(3))?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+pub(crate) fn read_sqlite_config(connection: &Connection) -> rusqlite::Result<LoadedConfig> {
+    Ok(LoadedConfig {
+        relays: find_sqlite_relays(connection)?,
+        presets: find_sqlite_presets(connection)?,
+        automations: find_sqlite_automations(connection)?,
+        server: ServerSettings::default(),
+    })
+}
+
+/// Text stored for a unit enum variant, matching its serde name.
+fn variant_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn to_text_error(error: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(error))
+}
+
+/// Replaces everything in the config tables with `config`.
+pub(crate) fn write_sqlite_config(
+    connection: &mut Connection,
+    config: &LoadedConfig,
+) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(
+        "DELETE FROM relays;
+        DELETE FROM presets;
+        DELETE FROM automations;",
+    )?;
+
+    for relay in &config.relays {
+        transaction.execute(
+            "INSERT INTO relays (type, ip, room, restore, options) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                variant_name(&relay.relay_type),
+                relay.ip,
+                relay.room,
+                relay.restore.as_ref().map(variant_name),
+                match relay.options.is_empty() {
+                    true => None,
+                    false => Some(serde_json::to_string(&relay.options).map_err(to_text_error)?),
+                }
+            ],
+        )?;
+        let relay_id = transaction.last_insert_rowid();
+
+        for (position, name) in relay.relay_names().iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO relay_names (relay_id, position, name) VALUES (?1, ?2, ?3)",
+                params![relay_id, position, name],
+            )?;
+        }
+        for tag in &relay.tags {
+            transaction.execute(
+                "INSERT OR IGNORE INTO relay_tags (relay_id, tag) VALUES (?1, ?2)",
+                params![relay_id, tag],
+            )?;
+        }
+    }
+
+    for preset in &config.presets {
+        transaction.execute(
+            "INSERT OR REPLACE INTO presets (name, enabled) VALUES (?1, ?2)",
+            params![preset.name, preset.enabled],
+        )?;
+        for (relay, state) in &preset.relays {
+            let levels = match preset.levels.get(relay) {
+                Some(levels) => Some(serde_json::to_string(levels).map_err(to_text_error)?),
+                None => None,
+            };
+            transaction.execute(
+                "INSERT INTO preset_relays (preset, relay, state, levels) VALUES (?1, ?2, ?3, ?4)",
+                params![preset.name, relay, state, levels],
+            )?;
+        }
+    }
+
+    for automation in &config.automations {
+        transaction.execute(
+            "INSERT OR REPLACE INTO automations (name, enabled, trigger, actions)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                automation.name,
+                automation.enabled,
+                serde_json::to_string(&automation.when).map_err(to_text_error)?,
+                serde_json::to_string(&automation.then).map_err(to_text_error)?
+            ],
+        )?;
+    }
+
+    transaction.commit()
+}
+
+pub fn load_sqlite_config(path: &str) -> Result<LoadedConfig, Error> {
+    let connection = open_sqlite_config(path).map_err(to_io_error)?;
+    read_sqlite_config(&connection).map_err(to_io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::automations::AutomationAction;
+    use crate::models::config_models::RestorePolicy;
+
+    fn open_in_memory() -> Connection {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        connection
+    }
+
+    #[test]
+    fn test_migrations_are_idempotent() {
+        let mut connection = open_in_memory();
+        migrate(&mut connection).unwrap();
+
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_loading_config_from_tables() {
+        let connection = open_in_memory();
+        connection
+            .execute_batch(
+                "INSERT INTO relays (id, type, ip, room, restore) VALUES
+                    (1, 'KasaPlug', '192.168.0.10', 'bedroom', 'force_off'),
+                    (2, 'KasaMultiPlug', '192.168.0.11', 'kitchen', NULL);
+                INSERT INTO relay_names (relay_id, position, name) VALUES
+                    (1, 0, 'DeskLamp'),
+                    (2, 0, 'Kettle'),
+                    (2, 1, 'Toaster');
+                INSERT INTO relay_tags (relay_id, tag) VALUES (2, 'kitchen');
+                INSERT INTO presets (name, enabled) VALUES ('Breakfast', 1);
+                INSERT INTO preset_relays (preset, relay, state) VALUES ('Breakfast', 'Kettle', 1);
+                INSERT INTO automations (name, enabled, trigger, actions) VALUES
+                    ('Kettle off', 1, '{\"relay\": \"Kettle\", \"state\": true, \"for\": 600}',
+                     '[{\"relay\": \"Kettle\", \"set\": false}]');",
+            )
+            .unwrap();
+
+        let loaded_config = read_sqlite_config(&connection).unwrap();
+
+        assert_eq!(loaded_config.relays.len(), 2);
+        assert_eq!(loaded_config.relays[0].name, "DeskLamp");
+        assert_eq!(
+            loaded_config.relays[0].restore,
+            Some(RestorePolicy::ForceOff)
+        );
+        assert_eq!(loaded_config.relays[1].names, vec!["Kettle", "Toaster"]);
+        assert_eq!(loaded_config.relays[1].tags, vec!["kitchen"]);
+
+        assert_eq!(loaded_config.presets[0].relays.get("Kettle"), Some(&true));
+
+        assert_eq!(
+            loaded_config.automations[0].then,
+            vec![AutomationAction::Relay {
+                relay: "Kettle".to_string(),
+                set: false
+            }]
+        );
+    }
+
+    #[cfg(all(
+        feature = "shelly",
+        feature = "mqtt",
+        feature = "esphome",
+        feature = "http"
+    ))]
+    #[test]
+    fn test_writing_config_round_trips() {
+        let mut connection = open_in_memory();
+        let config: LoadedConfig = serde_json::from_value(serde_json::json!({
+            "relays": [
+                {"type": "KasaPlug", "name": "DeskLamp", "ip": "192.168.0.10", "room": "office", "restore": "force_on"},
+                {"type": "KasaMultiPlug", "names": ["Kettle", "Toaster"], "ip": "192.168.0.11", "room": "kitchen", "tags": ["kitchen"]},
+                {"type": "Shelly", "name": "Porch", "ip": "192.168.0.12", "room": "outside", "channel": 1, "generation": 2, "password": "secret"},
+                {"type": "Shelly", "names": ["Fan", "Vent"], "ip": "192.168.0.13", "room": "bathroom"},
+                {"type": "Mqtt", "name": "Dehumidifier", "room": "basement", "commandTopic": "zigbee2mqtt/dehumidifier/set", "stateTopic": "zigbee2mqtt/dehumidifier", "statePath": "state"},
+                {"type": "EspHome", "names": ["Pump", "Valve"], "ip": "192.168.0.14", "room": "garden", "encryptionKey": "px7tsbK3C7bpXHr2OevEV2ZMg/FrNBw2+O2pNPbedtA="},
+                {"type": "Http", "name": "Heater", "ip": "192.168.0.15", "room": "garage", "onRequest": {"method": "PUT", "url": "http://{{ip}}/api/power", "headers": {"Content-Type": "application/json"}, "body": "{\"power\": \"{{state}}\"}"}, "offRequest": {"url": "http://{{ip}}/off"}, "statusRequest": {"url": "http://{{ip}}/api/power"}, "statePointer": "/power"}
+            ],
+            "presets": [{"name": "Breakfast", "enabled": true, "relays": {"Kettle": true, "DeskLamp": true}, "levels": {"DeskLamp": {"brightness": 40}}}],
+            "automations": [{
+                "name": "Kettle off",
+                "when": {"relay": "Kettle", "state": true, "for": 600},
+                "then": [{"relay": "Kettle", "set": false}]
+            }]
+        }))
+        .unwrap();
+
+        write_sqlite_config(&mut connection, &config).unwrap();
+        write_sqlite_config(&mut connection, &config).unwrap();
+
+        let read_back = read_sqlite_config(&connection).unwrap();
+        assert_eq!(read_back.relays, config.relays);
+        assert_eq!(read_back.presets, config.presets);
+        assert_eq!(read_back.automations, config.automations);
+    }
+
+    #[test]
+    fn test_unknown_relay_type_is_read() {
+        let connection = open_in_memory();
+        connection
+            .execute(
+                "INSERT INTO relays (type, ip) VALUES ('Toaster', '192.168.0.12')",
+                [],
+            )
+            .unwrap();
+
+        let config = read_sqlite_config(&connection).unwrap();
+        assert_eq!(config.relays[0].relay_type.name(), "Toaster");
+        assert!(config.relays[0].relay_type.driver().is_none());
+    }
+}
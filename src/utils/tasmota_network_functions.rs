@@ -0,0 +1,68 @@
+use std::io::{Error, ErrorKind};
+
+use crate::models::tasmota_network_models::TasmotaResponse;
+use crate::utils::kasa_plug_network_functions::{retries, timeout};
+
+fn to_io_error(ip: &str, error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(401, _) => Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Tasmota {} rejected the username or password", ip),
+        ),
+        ureq::Error::Status(code, _) => {
+            Error::other(format!("Tasmota {} answered with status {}", ip, code))
+        }
+        ureq::Error::Transport(transport) => Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Can't connect to Tasmota {}: {}", ip, transport),
+        ),
+    }
+}
+
+fn send_once(
+    ip: &str,
+    command: &str,
+    credentials: Option<(&str, &str)>,
+) -> Result<TasmotaResponse, Error> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout()).build();
+    let mut request = agent
+        .get(&format!("http://{}/cm", ip))
+        .query("cmnd", command);
+    if let Some((username, password)) = credentials {
+        request = request.query("user", username).query("password", password);
+    }
+
+    let body = request
+        .call()
+        .map_err(|error| to_io_error(ip, error))?
+        .into_string()?;
+    let response: TasmotaResponse =
+        serde_json::from_str(&body).map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+    match response.warning() {
+        Some(warning) => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Tasmota {} refused {}: {}", ip, command, warning),
+        )),
+        None => Ok(response),
+    }
+}
+
+/// Sends a console command such as `Power1 On` to the Tasmota at `ip`.
+/// `credentials` are the username and password set in the device's web admin.
+pub(crate) fn send(
+    ip: &str,
+    command: &str,
+    credentials: Option<(&str, &str)>,
+) -> Result<TasmotaResponse, Error> {
+    let retries = retries();
+    let mut attempt = 0;
+    loop {
+        match send_once(ip, command, credentials) {
+            Err(error) if attempt < retries && error.kind() != ErrorKind::PermissionDenied => {
+                attempt += 1
+            }
+            result => return result,
+        }
+    }
+}